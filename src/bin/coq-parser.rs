@@ -6,6 +6,8 @@ use std::{
     process,
 };
 
+use daemon::{client::commands::escape::escape, range::ColumnUnit};
+
 /// Coq's bullet styles.
 ///
 /// `{` and `}` are not in there because they do not stack (meaning `{{` is considered two different bullets).
@@ -68,7 +70,7 @@ enum MachineState {
 struct Cursor(
     /// The line number.
     u64,
-    /// The column number.
+    /// The column number, counted in bytes like Kakoune does.
     u64,
 );
 
@@ -82,10 +84,13 @@ impl Cursor {
             self.0 += 1;
             self.1 = 1;
         } else {
-            self.1 += 1;
+            self.1 += ColumnUnit::Byte.width(c);
         }
     }
 
+    /// Go back by one column.
+    ///
+    /// This is only ever used to go back over the ASCII character ending a statement.
     fn back(&mut self) {
         self.1 -= 1;
     }
@@ -153,14 +158,6 @@ impl GlobalState {
     }
 }
 
-/// Escapes a given string slice to make it safe to use with the plugin.
-///
-/// - `\n` is substituted for `\\n`
-/// - `\"` is replaced with `\\\"`
-fn escape(code: &String) -> String {
-    code.replace("\n", "\\n").replace("\"", "\\\"")
-}
-
 fn reached_target(st: &GlobalState) -> bool {
     match &st.command {
         Command::Next => true,
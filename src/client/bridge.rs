@@ -1,48 +1,69 @@
 use std::{
-    io,
+    future, io,
     sync::{Arc, RwLock},
 };
 
 use tokio::{
-    net::{UnixListener, UnixStream},
-    sync::{broadcast, watch},
+    io::AsyncWriteExt,
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    sync::{broadcast, mpsc, Mutex},
+    task::JoinHandle,
 };
-use tokio_util::codec::FramedRead;
 
 use crate::{
     client::commands::decode::command_decoder,
-    coqtop::xml_protocol::types::{ProtocolCall, ProtocolValue},
+    config::Config,
+    coqtop::{
+        backend::Call,
+        xml_protocol::types::{ProtocolRichPP, ProtocolRichPPPart},
+    },
+    error::{ClientError, DaemonError},
+    error_state::ErrorEvent,
     kakoune::command_line::kak,
-    range::Range,
+    range::{Edit, Position, Range},
     session::{edited_file, input_fifo, session_id, Session},
-    state::{ErrorState, Operation, State},
+    state::{Operation, State},
 };
 
 use super::commands::{
-    decode::CommandDecoder,
-    types::{ClientCommand, DisplayCommand},
+    protocol::{ErrorKind, Origin, Response},
+    types::{ClientCommand, DisplayCommand, GoalCategory},
 };
 
+/// The writing end of a connection to a client, where responses are sent.
+type Connection = Arc<Mutex<OwnedWriteHalf>>;
+
+/// A decoded command (or the reason why it could not be decoded), along with where it comes from.
+type Request = (Origin, Result<ClientCommand, ClientError>);
+
 pub struct ClientBridge {
     /// All information required to communicate with the session.
     _session: Arc<Session>,
     /// The global application state.
     state: Arc<RwLock<State>>,
+    /// The configuration of the daemon.
+    config: Arc<Config>,
     /// The backdoor for sending [`ClientCommand`]s from inside the daemon.
     pub command_tx: broadcast::Sender<ClientCommand>,
     /// The receiver of the backdoor.
     command_rx: broadcast::Receiver<ClientCommand>,
-    /// A reader to decode the incoming stream of commands.
-    reader: FramedRead<UnixStream, CommandDecoder>,
-    /// Manual ending of the daemon.
-    stop_tx: watch::Sender<()>,
+    /// All commands decoded from the connected clients, along with the connection they come from.
+    requests_rx: mpsc::UnboundedReceiver<(Connection, Request)>,
+    /// The connection and the id of the JSON request currently being processed, which is answered
+    /// once the command has been processed.
+    current_request: Option<(Connection, Option<u64>)>,
+    /// The task serving the connection opened by Kakoune, which ends when Kakoune disconnects.
+    kakoune: Option<JoinHandle<()>>,
+    /// Commands to process once the response to the current command has been processed (see
+    /// [`ClientBridge::send_deferred`]).
+    deferred: Vec<ClientCommand>,
 }
 
 impl ClientBridge {
     pub async fn new<const SIZE: usize>(
         session: Arc<Session>,
         state: Arc<RwLock<State>>,
-        stop_tx: watch::Sender<()>,
+        config: Arc<Config>,
     ) -> io::Result<Self> {
         let session_id = session_id(session.clone());
         let unix_listener = UnixListener::bind(input_fifo(session.clone()))?;
@@ -60,94 +81,167 @@ impl ClientBridge {
         log::debug!("Connected to FIFO at path {}", input_fifo(session.clone()));
 
         let (command_tx, command_rx) = broadcast::channel(SIZE);
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+
+        let kakoune = serve(pipe, requests_tx.clone());
+        // Other clients (e.g. scripts) may connect to the socket directly.
+        tokio::spawn(async move {
+            loop {
+                match unix_listener.accept().await {
+                    Ok((stream, _)) => {
+                        log::debug!("New client connected");
+                        serve(stream, requests_tx.clone());
+                    }
+                    Err(err) => {
+                        log::error!("Cannot accept new clients anymore: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
 
         Ok(Self {
             _session: session,
             state,
+            config,
             command_tx,
             command_rx,
-            reader: command_decoder(pipe),
-            stop_tx,
+            requests_rx,
+            current_request: None,
+            kakoune: Some(kakoune),
+            deferred: vec![],
         })
     }
 
-    /// Wait until a command is received, either through the unix socket or the backdoor channel.
-    pub async fn recv(&mut self, _stop: watch::Receiver<()>) -> io::Result<ClientCommand> {
-        loop {
-            tokio::select! {
-                biased; // We want to always favor receiving from the internal channel.
-
-                Ok(cmd) = self.command_rx.recv() => break Ok(cmd),
-                Ok(cmd) = ClientCommand::decode_stream(&mut self.reader) => {
-                    match cmd {
-                        None => log::warn!("Junk found in stream"),
-                        Some(cmd) => break Ok(cmd),
+    /// Wait until a command is received, either from a client or through the backdoor channel.
+    ///
+    /// Commands which cannot be decoded are answered right away (if they were sent as JSON requests),
+    /// and returned as errors.
+    /// If Kakoune disconnects from the socket (e.g. because it has crashed), an error of kind
+    /// [`io::ErrorKind::ConnectionAborted`] is returned.
+    pub async fn recv(&mut self) -> io::Result<ClientCommand> {
+        self.current_request = None;
+
+        tokio::select! {
+            biased; // We want to always favor receiving from the internal channel.
+
+            Ok(cmd) = self.command_rx.recv() => Ok(cmd),
+            Some((connection, (origin, cmd))) = self.requests_rx.recv() => match (origin, cmd) {
+                (Origin::Legacy, Ok(cmd)) => Ok(cmd),
+                (Origin::Json(id), Ok(cmd)) => {
+                    self.current_request = Some((connection, id));
+                    Ok(cmd)
+                }
+                (origin, Err(err)) => {
+                    let err = DaemonError::Client(err);
+                    if let Origin::Json(id) = origin {
+                        respond(&connection, Response::error(id, ErrorKind::of(&err), err.to_string())).await;
                     }
+                    Err(err.into())
                 }
-            }
+            },
+            () = disconnected(&mut self.kakoune) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Kakoune has disconnected")),
+            else => Err(io::Error::new(io::ErrorKind::BrokenPipe, "No more commands to receive")),
         }
     }
 
+    /// Processes the last received command, and answers the request it came in (if any).
     pub async fn process(
         &mut self,
         command: ClientCommand,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
-        let error_state = self.state.read().unwrap().error_state;
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        let request = self.current_request.take();
+
+        let accepted = self.state.read().unwrap().error_state.accepts(&command);
+        if let Err(reason) = accepted {
+            log::warn!("Ignoring command {:?}: {}", command, reason);
+
+            if let Some((connection, id)) = request {
+                respond(
+                    &connection,
+                    Response::error(id, ErrorKind::Rejected, reason.to_string()),
+                )
+                .await;
+            }
 
-        match command {
+            // Statements were already marked as to be processed, but they will not be.
+            let display = match &command {
+                ClientCommand::Next(_, range, _) => {
+                    vec![DisplayCommand::RemoveToBeProcessed(*range)]
+                }
+                ClientCommand::MoveTo(ranges) => ranges
+                    .iter()
+                    .map(|(range, _)| DisplayCommand::RemoveToBeProcessed(*range))
+                    .collect(),
+                _ => vec![],
+            };
+            return Ok((None, command, display));
+        }
+
+        let result = match command {
             ClientCommand::StopInterrupt => self.process_stop_interrupt(),
             ClientCommand::Init => self.process_init(),
             ClientCommand::Quit => self.process_quit(),
-            ClientCommand::Previous if error_state != ErrorState::Interrupted => {
-                self.process_previous()
-            }
-            ClientCommand::RewindTo(line, column) if error_state != ErrorState::Interrupted => {
-                self.process_rewind_to(line, column)
+            ClientCommand::Previous => self.process_previous(),
+            ClientCommand::RewindTo(line, column) => self.process_rewind_to(line, column),
+            ClientCommand::Query(_) => {
+                Err(DaemonError::Client(ClientError::Unsupported("Queries")).into())
             }
-            ClientCommand::Query(_) => todo!(),
-            ClientCommand::MoveTo(ranges) if error_state == ErrorState::Ok => {
-                self.process_move_to(ranges)
-            }
-            ClientCommand::Next(append, range, code) if error_state == ErrorState::Ok => {
-                self.process_next(append, range, code)
-            }
-            ClientCommand::IgnoreError if error_state == ErrorState::Error => {
-                self.process_ignore_error()
-            }
-            ClientCommand::Hints => todo!(),
-            ClientCommand::ShowGoals(range) if error_state != ErrorState::Interrupted => {
-                self.process_show_goals(range)
+            ClientCommand::MoveTo(ranges) => self.process_move_to(ranges),
+            ClientCommand::Next(append, range, code) => self.process_next(append, range, code),
+            ClientCommand::IgnoreError => self.process_ignore_error(),
+            ClientCommand::Hints => {
+                Err(DaemonError::Client(ClientError::Unsupported("Hints")).into())
             }
+            ClientCommand::ShowGoals(range) => self.process_show_goals(range),
             ClientCommand::BackTo(op) => self.process_back_to(op),
-            ClientCommand::Next(append, range, code) => Ok((
-                None,
-                ClientCommand::Next(append, range, code),
-                vec![DisplayCommand::RemoveToBeProcessed(range)],
-            )),
             ClientCommand::Status => self.process_status(),
-            c => Ok((None, c, vec![])),
+            ClientCommand::ToggleGoals(category) => self.process_toggle_goals(category),
+            ClientCommand::MessagesAt(line, column) => self.process_messages_at(line, column),
+            ClientCommand::Edited(timestamp, edits) => self.process_edited(timestamp, edits),
+            ClientCommand::ShowConfig => self.process_show_config(),
+            ClientCommand::Restart(replay) => self.process_restart(replay),
+        };
+
+        if let Some((connection, id)) = request {
+            let response = match &result {
+                Ok(_) => Response::ack(id),
+                Err(err) => match err
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<DaemonError>())
+                {
+                    Some(err) => Response::error(id, ErrorKind::of(err), err.to_string()),
+                    None => Response::error(id, ErrorKind::Internal, err.to_string()),
+                },
+            };
+            respond(&connection, response).await;
+        }
+
+        result
+    }
+
+    /// Sends the commands which had to wait for the response to the last processed command, so that they are
+    /// received next.
+    pub fn send_deferred(&mut self) -> io::Result<()> {
+        for cmd in self.deferred.drain(..) {
+            self.command_tx
+                .send(cmd)
+                .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
         }
+        Ok(())
     }
 
     // -----------------
 
-    fn process_init(
-        &self,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
-        Ok((
-            Some(ProtocolCall::Init(ProtocolValue::Optional(None))),
-            ClientCommand::Init,
-            vec![],
-        ))
+    fn process_init(&self) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        Ok((Some(Call::Init), ClientCommand::Init, vec![]))
     }
 
-    fn process_quit(
-        &mut self,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
+    fn process_quit(&mut self) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
         log::info!("Beginning quitting");
 
-        self.stop_tx.send(()).unwrap();
-        Ok((Some(ProtocolCall::Quit), ClientCommand::Quit, vec![]))
+        // NOTE: quitting is up to the main loop, which owns the `coqidetop` process
+        Ok((None, ClientCommand::Quit, vec![]))
     }
 
     fn process_next(
@@ -155,9 +249,32 @@ impl ClientBridge {
         append: bool,
         range: Range,
         code: String,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
-        let call = match self.state.read().unwrap().operations.front() {
-            Some(Operation { state_id, .. }) => Some(ProtocolCall::Add(code.clone(), *state_id)),
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        let (tip, translated) = {
+            let mut state = self.state.write().unwrap();
+            state.buffer_timestamp = state.buffer_timestamp.max(range.timestamp);
+
+            (state.operations.tip().cloned(), state.translate(range))
+        };
+
+        if translated.is_none() {
+            // The statement was sent before an edit happened before it, so it may not even exist anymore,
+            // and we have already gone back before it anyway.
+            log::info!(
+                "Dropping statement at {} (timestamp {}) as the buffer has been edited since",
+                range.begin,
+                range.timestamp
+            );
+
+            return Ok((
+                None,
+                ClientCommand::Next(append, range, code),
+                vec![DisplayCommand::RemoveToBeProcessed(range)],
+            ));
+        }
+
+        let call = match tip {
+            Some(Operation { state_id, .. }) => Some(Call::Add(code.clone(), state_id)),
             None => None,
         };
         Ok((call, ClientCommand::Next(append, range, code), vec![]))
@@ -166,19 +283,15 @@ impl ClientBridge {
     fn process_show_goals(
         &mut self,
         range: Range,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
-        Ok((
-            Some(ProtocolCall::Goal),
-            ClientCommand::ShowGoals(range),
-            vec![],
-        ))
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        Ok((Some(Call::Goals), ClientCommand::ShowGoals(range), vec![]))
     }
 
     fn process_previous(
         &mut self,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
-        let call = match self.state.read().unwrap().operations.get(1) {
-            Some(Operation { state_id, .. }) => Some(ProtocolCall::EditAt(*state_id)),
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        let call = match self.state.read().unwrap().operations.before_tip() {
+            Some(Operation { state_id, .. }) => Some(Call::Cancel(*state_id)),
             None => {
                 log::warn!("No earlier operation to go back to (this might be a case where you are trying to rollback on buffer start)");
                 None
@@ -191,18 +304,22 @@ impl ClientBridge {
         &mut self,
         line: u64,
         column: u64,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        let (call, op) = self.rewind_to(line, column);
+
+        Ok((call, ClientCommand::BackTo(op), vec![]))
+    }
+
+    /// Finds the last operation ending strictly before the given line and column numbers,
+    /// and the call needed to go back to it (if it is not already the tip).
+    fn rewind_to(&self, line: u64, column: u64) -> (Option<Call>, Operation) {
         let (op, tip) = {
             let state = self.state.read().unwrap();
             let op = state
                 .operations
-                .iter()
-                .find(|op| {
-                    let range = op.range;
-                    range.end.0 < line || (range.end.0 == line && range.end.1 < column)
-                })
+                .last_before(Position::new(line, column), |range| state.translate(range))
                 .cloned();
-            (op, state.operations.front().cloned())
+            (op, state.operations.tip().cloned())
         };
         let (new_state_id, tip_id, op) = {
             let op = op.unwrap_or_else(Operation::default);
@@ -211,23 +328,74 @@ impl ClientBridge {
             (new_id, tip.map(|op| op.state_id).unwrap_or(1), op)
         };
 
-        Ok((
+        (
             if new_state_id < tip_id {
-                Some(ProtocolCall::EditAt(new_state_id))
+                Some(Call::Cancel(new_state_id))
             } else {
                 None
             },
-            ClientCommand::BackTo(op),
-            vec![],
-        ))
+            op,
+        )
+    }
+
+    fn process_edited(
+        &mut self,
+        timestamp: u64,
+        edits: Vec<Edit>,
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        let begin = edits.iter().map(|edit| edit.begin).min();
+        // Everything after the first edit has to be processed again.
+        let rewound = begin.map(Range::rest_from);
+
+        let mut display = vec![];
+        {
+            let mut state = self.state.write().unwrap();
+            state.buffer_timestamp = state.buffer_timestamp.max(timestamp);
+            for edit in &edits {
+                state.record_edit(timestamp, *edit);
+            }
+
+            match (rewound, state.last_error_range) {
+                (Some(rewound), Some(error_range)) if error_range.intersects(&rewound) => {
+                    state.last_error_range = None;
+                    state.error_state.transition(ErrorEvent::Cleared);
+
+                    display.push(DisplayCommand::RefreshErrorRange(None, true));
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(Position { line, column }) = begin {
+            let (call, op) = self.rewind_to(line, column);
+            if call.is_some() {
+                log::info!(
+                    "Buffer edited at {}:{} (timestamp {}), going back to state ID {}",
+                    line,
+                    column,
+                    timestamp,
+                    op.state_id
+                );
+
+                // Goals and status must be refreshed once we have gone back.
+                self.deferred = vec![
+                    ClientCommand::ShowGoals(Range::default()),
+                    ClientCommand::Status,
+                ];
+
+                return Ok((call, ClientCommand::BackTo(op), display));
+            }
+        }
+
+        Ok((None, ClientCommand::Edited(timestamp, edits), display))
     }
 
     fn process_back_to(
         &mut self,
         op: Operation,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
         Ok((
-            Some(ProtocolCall::EditAt(op.state_id)),
+            Some(Call::Cancel(op.state_id)),
             ClientCommand::BackTo(op),
             vec![],
         ))
@@ -235,11 +403,11 @@ impl ClientBridge {
 
     fn process_ignore_error(
         &mut self,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
         {
             let mut state = self.state.write().unwrap();
             state.last_error_range = None;
-            state.error_state = ErrorState::Ok;
+            state.error_state.transition(ErrorEvent::Cleared);
         }
 
         Ok((
@@ -249,20 +417,35 @@ impl ClientBridge {
         ))
     }
 
-    fn process_status(
+    fn process_status(&mut self) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        Ok((Some(Call::Status), ClientCommand::Status, vec![]))
+    }
+
+    fn process_show_config(
         &mut self,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
         Ok((
-            Some(ProtocolCall::Status(ProtocolValue::Boolean(false))),
-            ClientCommand::Status,
-            vec![],
+            None,
+            ClientCommand::ShowConfig,
+            vec![DisplayCommand::ColorResult(
+                ProtocolRichPP::RichPP(vec![ProtocolRichPPPart::Raw(self.config.to_string())]),
+                false,
+            )],
         ))
     }
 
+    fn process_restart(
+        &mut self,
+        replay: bool,
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        // NOTE: restarting is up to the main loop, which owns the `coqidetop` process
+        Ok((None, ClientCommand::Restart(replay), vec![]))
+    }
+
     fn process_move_to(
         &mut self,
         ranges: Vec<(Range, String)>,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
         let mut should_append = false;
         for (range, code) in ranges.iter() {
             self.command_tx
@@ -278,11 +461,117 @@ impl ClientBridge {
         Ok((None, ClientCommand::MoveTo(ranges), vec![]))
     }
 
+    fn process_toggle_goals(
+        &mut self,
+        category: GoalCategory,
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        Ok((
+            None,
+            ClientCommand::ToggleGoals(category),
+            vec![DisplayCommand::ToggleGoals(category)],
+        ))
+    }
+
+    fn process_messages_at(
+        &mut self,
+        line: u64,
+        column: u64,
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        let messages = {
+            let state = self.state.read().unwrap();
+            state
+                .operations
+                .at(Position::new(line, column), |range| state.translate(range))
+                .map(|op| {
+                    state
+                        .messages
+                        .get(&op.state_id)
+                        .cloned()
+                        .unwrap_or_default()
+                })
+        };
+
+        let display = match messages {
+            Some(messages) => {
+                let mut display = vec![DisplayCommand::ColorResult(
+                    ProtocolRichPP::RichPP(vec![]),
+                    false,
+                )];
+                for message in messages {
+                    display.push(DisplayCommand::ColorResult(message, true));
+                }
+                display
+            }
+            None => {
+                log::warn!("No processed statement at {}:{}", line, column);
+                vec![]
+            }
+        };
+
+        Ok((None, ClientCommand::MessagesAt(line, column), display))
+    }
+
     fn process_stop_interrupt(
         &mut self,
-    ) -> io::Result<(Option<ProtocolCall>, ClientCommand, Vec<DisplayCommand>)> {
-        self.state.write().unwrap().error_state = ErrorState::Ok;
+    ) -> io::Result<(Option<Call>, ClientCommand, Vec<DisplayCommand>)> {
+        self.state
+            .write()
+            .unwrap()
+            .error_state
+            .transition(ErrorEvent::Resumed);
 
         Ok((None, ClientCommand::StopInterrupt, vec![]))
     }
 }
+
+/// Decodes all commands sent by a client in the background, until it disconnects.
+fn serve(
+    stream: UnixStream,
+    requests_tx: mpsc::UnboundedSender<(Connection, Request)>,
+) -> JoinHandle<()> {
+    let (reader, writer) = stream.into_split();
+    let connection = Arc::new(Mutex::new(writer));
+    let mut reader = command_decoder(reader);
+
+    tokio::spawn(async move {
+        loop {
+            match ClientCommand::decode_stream(&mut reader).await {
+                Ok(request) => {
+                    if requests_tx.send((connection.clone(), request)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                    log::debug!("Client disconnected");
+                    break;
+                }
+                Err(err) => {
+                    log::error!("Cannot read from client anymore: {}", err);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Waits until the task serving the connection opened by Kakoune ends, or forever if it has already ended.
+async fn disconnected(kakoune: &mut Option<JoinHandle<()>>) {
+    match kakoune.as_mut() {
+        Some(task) => {
+            let _ = task.await;
+            // NOTE: a finished task cannot be waited for again
+            *kakoune = None;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Sends a response back to a client, which may have disconnected in the meantime.
+async fn respond(connection: &Connection, response: Response) {
+    let line = response.encode();
+    log::debug!("Sending response {:?}", line);
+
+    if let Err(err) = connection.lock().await.write_all(line.as_bytes()).await {
+        log::warn!("Cannot send response to client: {}", err);
+    }
+}
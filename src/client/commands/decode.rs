@@ -5,7 +5,7 @@ use bytes::Buf;
 use nom::{
     branch::alt,
     bytes::streaming::{is_a, tag, take, take_while, take_while1},
-    combinator::{cut, map, value, verify},
+    combinator::{cut, map, map_opt, map_res, recognize, value},
     multi::{many0, separated_list1},
     sequence::{delimited, pair, preceded, tuple},
     IResult,
@@ -14,9 +14,16 @@ use tokio::io::AsyncRead;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Decoder, FramedRead};
 
-use crate::range::Range;
+use crate::{
+    error::ClientError,
+    range::{Edit, Position, Range},
+};
 
-use super::types::ClientCommand;
+use super::{
+    escape,
+    protocol::{self, Origin},
+    types::{ClientCommand, GoalCategory},
+};
 
 #[derive(Default)]
 pub struct CommandDecoder {}
@@ -24,39 +31,80 @@ pub struct CommandDecoder {}
 unsafe impl Send for CommandDecoder {}
 
 impl Decoder for CommandDecoder {
-    type Item = Option<ClientCommand>;
+    /// A command, or the reason why it could not be decoded, along with where it comes from.
+    ///
+    /// Malformed commands are not reported as [`Decoder::Error`]s, which would end the stream.
+    type Item = (Origin, Result<ClientCommand, ClientError>);
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let start = src
+            .iter()
+            .position(|c| !c.is_ascii_whitespace())
+            .unwrap_or(src.len());
+        // Skip blank lines, so that JSON requests can be separated by empty lines.
+        src.advance(start);
+
+        if src.first() == Some(&b'{') {
+            return Ok(src.iter().position(|&c| c == b'\n').map(|end| {
+                let line = src.split_to(end + 1);
+                log::debug!(
+                    "Accepted {} bytes from stream: {:?}",
+                    line.len(),
+                    String::from_utf8_lossy(&line)
+                );
+
+                protocol::decode_request(&line[..end])
+            }));
+        }
+
         let result = parse_command(&src[..]);
         match result {
             Ok((remaining, parsed)) => {
                 let count = src.len() - remaining.len();
-                log::debug!(
-                    "Accepted {} bytes from stream: {:?}",
-                    count,
-                    std::str::from_utf8(&src[..count]).unwrap()
-                );
+                let line = String::from_utf8_lossy(&src[..count]).to_string();
+                log::debug!("Accepted {} bytes from stream: {:?}", count, line);
 
                 src.advance(count);
 
-                Ok(Some(parsed))
+                Ok(Some((
+                    Origin::Legacy,
+                    parsed.ok_or(ClientError::InvalidCommand(line)),
+                )))
             }
             Err(nom::Err::Incomplete(_)) => {
                 log::warn!("More data needed to parse input");
 
                 Ok(None)
             }
-            Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+            Err(err) => match src.iter().position(|&c| c == b'\n') {
+                // Skip the whole line, so that the next commands can still be decoded.
+                Some(end) => {
+                    let line = String::from_utf8_lossy(&src[..end]).to_string();
+                    log::debug!("Could not parse command {:?}: {}", line, err);
+
+                    src.advance(end + 1);
+
+                    Ok(Some((
+                        Origin::Legacy,
+                        Err(ClientError::InvalidCommand(line)),
+                    )))
+                }
+                None => {
+                    log::warn!("More data needed to skip malformed input");
+
+                    Ok(None)
+                }
+            },
         }
     }
 }
 
 impl ClientCommand {
-    /// Decodes a stream chunks by chunks until a complete XML node can be decoded.
+    /// Decodes a stream chunks by chunks until a complete command can be decoded.
     pub async fn decode_stream<R>(
         reader: &mut FramedRead<R, CommandDecoder>,
-    ) -> io::Result<Option<Self>>
+    ) -> io::Result<(Origin, Result<Self, ClientError>)>
     where
         R: AsyncRead + Unpin,
     {
@@ -91,6 +139,11 @@ fn parse_command<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
         parse_move_to,
         parse_show_goals,
         parse_status,
+        parse_toggle_goals,
+        parse_messages_at,
+        parse_edited,
+        parse_show_config,
+        parse_restart,
         //map(take(1usize), |_| None),
     ))(input)
 }
@@ -145,12 +198,16 @@ fn parse_next<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
         cut(map(
             tuple((
                 parse_boolean,
-                space0,
+                space1,
+                u64,
+                space1,
                 parse_coq_statement,
                 space0,
                 tag("\n"),
             )),
-            |(append, _, (range, code), _, _)| Some(ClientCommand::Next(append, range, code)),
+            |(append, _, timestamp, _, (range, code), _, _)| {
+                Some(ClientCommand::Next(append, range.at(timestamp), code))
+            },
         )),
     )(input)
 }
@@ -170,11 +227,20 @@ fn parse_move_to<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
         pair(tag("move-to"), space1),
         cut(map(
             tuple((
+                u64,
+                space1,
                 separated_list1(space1, parse_coq_statement),
                 space0,
                 tag("\n"),
             )),
-            |(ranges, _, _)| Some(ClientCommand::MoveTo(ranges)),
+            |(timestamp, _, ranges, _, _)| {
+                Some(ClientCommand::MoveTo(
+                    ranges
+                        .into_iter()
+                        .map(|(range, code)| (range.at(timestamp), code))
+                        .collect(),
+                ))
+            },
         )),
     )(input)
 }
@@ -196,6 +262,23 @@ fn parse_status<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
     )(input)
 }
 
+fn parse_show_config<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
+    preceded(
+        pair(tag("show-config"), space0),
+        cut(value(Some(ClientCommand::ShowConfig), tag("\n"))),
+    )(input)
+}
+
+fn parse_restart<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
+    preceded(
+        pair(tag("restart"), space1),
+        cut(map(
+            tuple((parse_boolean, space0, tag("\n"))),
+            |(replay, _, _)| Some(ClientCommand::Restart(replay)),
+        )),
+    )(input)
+}
+
 fn parse_stop_interrupt<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
     preceded(
         pair(tag("stop-interrupt"), space0),
@@ -203,8 +286,52 @@ fn parse_stop_interrupt<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
     )(input)
 }
 
+fn parse_toggle_goals<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
+    preceded(
+        pair(tag("toggle-goals"), space1),
+        cut(map(
+            tuple((parse_goal_category, space0, tag("\n"))),
+            |(category, _, _)| Some(ClientCommand::ToggleGoals(category)),
+        )),
+    )(input)
+}
+
+fn parse_messages_at<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
+    preceded(
+        pair(tag("messages-at"), space1),
+        cut(map(
+            tuple((u64, space1, u64, space0, tag("\n"))),
+            |(line, _, column, _, _)| Some(ClientCommand::MessagesAt(line, column)),
+        )),
+    )(input)
+}
+
+fn parse_edited<'a>(input: Input<'a>) -> IResult<Input<'a>, Output> {
+    preceded(
+        pair(tag("edited"), space1),
+        cut(map(
+            tuple((
+                u64,
+                space1,
+                separated_list1(space1, parse_edit),
+                space0,
+                tag("\n"),
+            )),
+            |(timestamp, _, edits, _, _)| Some(ClientCommand::Edited(timestamp, edits)),
+        )),
+    )(input)
+}
+
 // ---------------------------
 
+fn parse_goal_category<'a>(input: Input<'a>) -> IResult<Input<'a>, GoalCategory> {
+    alt((
+        value(GoalCategory::Background, tag("background")),
+        value(GoalCategory::Shelved, tag("shelved")),
+        value(GoalCategory::GivenUp, tag("given-up")),
+    ))(input)
+}
+
 fn parse_range<'a>(input: Input<'a>) -> IResult<Input<'a>, Range> {
     map(
         tuple((u64, tag("."), u64, tag(","), u64, tag("."), u64)),
@@ -214,6 +341,39 @@ fn parse_range<'a>(input: Input<'a>) -> IResult<Input<'a>, Range> {
     )(input)
 }
 
+fn parse_position<'a>(input: Input<'a>) -> IResult<Input<'a>, Position> {
+    map(tuple((u64, tag("."), u64)), |(line, _, column)| {
+        Position::new(line, column)
+    })(input)
+}
+
+/// Parses an edit, given either as `begin,removed_end,inserted_end`, or as the position where an edit whose
+/// extent is unknown begins (see [`Edit::unknown_from`]).
+///
+/// A selection around such an edit is also accepted, as sent by older versions of the plugin.
+fn parse_edit<'a>(input: Input<'a>) -> IResult<Input<'a>, Edit> {
+    alt((
+        map(
+            tuple((
+                parse_position,
+                tag(","),
+                parse_position,
+                tag(","),
+                parse_position,
+            )),
+            |(begin, _, removed_end, _, inserted_end)| Edit {
+                begin,
+                removed_end,
+                inserted_end,
+            },
+        ),
+        map(parse_range, |range| {
+            Edit::unknown_from(range.normalized().begin)
+        }),
+        map(parse_position, Edit::unknown_from),
+    ))(input)
+}
+
 fn parse_coq_statement<'a>(input: Input<'a>) -> IResult<Input<'a>, (Range, String)> {
     map(
         tuple((parse_range, space0, parse_string)),
@@ -221,14 +381,16 @@ fn parse_coq_statement<'a>(input: Input<'a>) -> IResult<Input<'a>, (Range, Strin
     )(input)
 }
 
+/// Parses a string between double quotes, whose content is escaped as described in [`ESCAPES`].
+///
+/// [`ESCAPES`]: super::escape::ESCAPES
 fn parse_string<'a>(input: Input<'a>) -> IResult<Input<'a>, String> {
-    let escaped = |input| map(tag("\\\""), |_| b'"')(input);
+    let escaped = |input| preceded(tag("\\"), take(1usize))(input);
+    let raw = |input| take_while1(escape::is_raw_byte)(input);
 
     delimited(
         tag("\""),
-        map(many0(alt((escaped, any_single))), |res| {
-            std::str::from_utf8(&res[..]).unwrap().to_string()
-        }),
+        map_opt(recognize(many0(alt((escaped, raw)))), escape::unescape),
         tag("\""),
     )(input)
 }
@@ -237,13 +399,6 @@ fn parse_boolean<'a>(input: Input<'a>) -> IResult<Input<'a>, bool> {
     map(alt((tag("true"), tag("false"))), |t| t == b"true")(input)
 }
 
-fn any_single<'a>(input: Input<'a>) -> IResult<Input<'a>, u8> {
-    map(
-        verify(take(1usize), |s: &[u8]| s[0] != b'"'),
-        |s: &[u8]| s[0],
-    )(input)
-}
-
 fn space1<'a>(input: Input<'a>) -> IResult<Input<'a>, ()> {
     let is_space = |c: u8| c == b' ' || c == b'\t';
 
@@ -257,7 +412,8 @@ fn space0<'a>(input: Input<'a>) -> IResult<Input<'a>, ()> {
 }
 
 fn u64<'a>(input: Input<'a>) -> IResult<Input<'a>, u64> {
-    map(is_a("0123456789"), |slice| {
-        std::str::from_utf8(slice).unwrap().parse::<u64>().unwrap()
+    map_res(is_a("0123456789"), |slice| {
+        // NOTE: only ASCII digits are accepted, so this is valid UTF-8
+        String::from_utf8_lossy(slice).parse::<u64>()
     })(input)
 }
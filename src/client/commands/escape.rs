@@ -0,0 +1,70 @@
+/// All the characters which are escaped inside strings (e.g. Coq statements), along with the character
+/// following the backslash.
+///
+/// Strings are sent inside double quotes on a single line, so that commands can be framed by newlines.
+/// Inside these quotes, a backslash is always followed by one of these characters:
+///
+/// | Sequence | Stands for        |
+/// |----------|-------------------|
+/// | `\\`     | a backslash       |
+/// | `\"`     | a double quote    |
+/// | `\n`     | a line feed       |
+/// | `\r`     | a carriage return |
+/// | `\t`     | a tabulation      |
+///
+/// Any other byte stands for itself, and the unescaped bytes must form valid UTF-8.
+/// Raw double quotes and line feeds are never allowed.
+pub const ESCAPES: [(char, char); 5] = [
+    ('\\', '\\'),
+    ('"', '"'),
+    ('\n', 'n'),
+    ('\r', 'r'),
+    ('\t', 't'),
+];
+
+/// Escapes a string so that it can be put inside double quotes in a command.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match ESCAPES.iter().find(|(raw, _)| *raw == c) {
+            Some((_, sequence)) => {
+                escaped.push('\\');
+                escaped.push(*sequence);
+            }
+            None => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Retrieves the byte standing for a backslash followed by the given byte, if this is a valid escape sequence.
+pub fn unescape_byte(sequence: u8) -> Option<u8> {
+    ESCAPES
+        .iter()
+        .find(|(_, escaped)| *escaped == sequence as char)
+        .map(|(raw, _)| *raw as u8)
+}
+
+/// Whether a byte can appear unescaped between the double quotes.
+pub fn is_raw_byte(byte: u8) -> bool {
+    byte != b'\\' && byte != b'"' && byte != b'\n'
+}
+
+/// Unescapes the content of a string (without its double quotes).
+///
+/// Returns `None` if it contains an invalid escape sequence, a forbidden raw byte, or if it is not valid UTF-8
+/// once unescaped.
+pub fn unescape(escaped: &[u8]) -> Option<String> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut iter = escaped.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == b'\\' {
+            bytes.push(unescape_byte(*iter.next()?)?);
+        } else if is_raw_byte(byte) {
+            bytes.push(byte);
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -1,4 +1,8 @@
 /// A decoder (parser) for user commands received from Kakoune.
 pub mod decode;
+/// The escaping scheme of strings in commands, shared by `coq-parser` and the command decoder.
+pub mod escape;
+/// The versioned JSON protocol, along with the responses sent back to clients.
+pub mod protocol;
 
 pub mod types;
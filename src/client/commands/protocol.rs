@@ -0,0 +1,343 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::{ClientError, DaemonError},
+    range::{Edit, Position, Range},
+};
+
+use super::types::{ClientCommand, GoalCategory};
+
+/// The version of the JSON protocol, which must be given in every request.
+///
+/// It is bumped whenever a change to requests or responses breaks existing clients.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// Where a command comes from, which tells whether (and how) it must be answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// A line in the old format (e.g. `next true 1.1,2.3 "..."`), which is never answered.
+    Legacy,
+    /// A JSON request, along with its id if it could be retrieved.
+    ///
+    /// Every JSON request is answered with exactly one [`Response`] carrying the same id.
+    Json(Option<u64>),
+}
+
+/// A position in the buffer, with columns counted in bytes.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonPosition {
+    line: u64,
+    column: u64,
+}
+
+/// A range in the buffer, with both ends included.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRange {
+    begin: JsonPosition,
+    end: JsonPosition,
+}
+
+/// A modification of the buffer, which replaced the text from `begin` up to `removed-end` (excluded) with some
+/// text which now ends at `inserted-end` (excluded).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct JsonEdit {
+    begin: JsonPosition,
+    removed_end: JsonPosition,
+    inserted_end: JsonPosition,
+}
+
+/// A Coq statement along with its range in the buffer.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonStatement {
+    range: JsonRange,
+    code: String,
+}
+
+/// All the commands which can be sent in a JSON request, mirroring [`ClientCommand`].
+///
+/// Timestamps are the ones of the buffer at the time the ranges were computed.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case", deny_unknown_fields)]
+enum JsonCommand {
+    Init,
+    Quit,
+    Previous,
+    RewindTo {
+        line: u64,
+        column: u64,
+    },
+    Query {
+        query: String,
+    },
+    MoveTo {
+        timestamp: u64,
+        statements: Vec<JsonStatement>,
+    },
+    Next {
+        #[serde(default)]
+        append: bool,
+        timestamp: u64,
+        range: JsonRange,
+        code: String,
+    },
+    IgnoreError,
+    Hints,
+    ShowGoals {
+        range: JsonRange,
+    },
+    Status,
+    StopInterrupt,
+    ToggleGoals {
+        category: JsonGoalCategory,
+    },
+    MessagesAt {
+        line: u64,
+        column: u64,
+    },
+    Edited {
+        timestamp: u64,
+        #[serde(default)]
+        ranges: Vec<JsonRange>,
+        #[serde(default)]
+        edits: Vec<JsonEdit>,
+    },
+    ShowConfig,
+    Restart {
+        #[serde(default)]
+        replay: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum JsonGoalCategory {
+    Background,
+    Shelved,
+    GivenUp,
+}
+
+/// The answer to a JSON request.
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub version: u64,
+    /// The id of the request, which is `null` if it could not be retrieved.
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+/// Whether a request was accepted.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum Outcome {
+    /// The command has been accepted, and its effects will be visible in Kakoune.
+    Ack,
+    /// The command has not been (and will not be) processed.
+    Error { kind: ErrorKind, message: String },
+}
+
+/// The reasons why a request may not be processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The request is not valid JSON, or does not describe a known command.
+    MalformedRequest,
+    /// The request was written for another version of the protocol (see [`PROTOCOL_VERSION`]).
+    UnsupportedVersion,
+    /// The command is not supported (yet).
+    Unsupported,
+    /// The command cannot be processed in the current state (e.g. going further after an error).
+    Rejected,
+    /// Something went wrong inside the daemon.
+    Internal,
+}
+
+impl Response {
+    /// Acknowledges the request with the given id.
+    pub fn ack(id: Option<u64>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            outcome: Outcome::Ack,
+        }
+    }
+
+    /// Answers the request with the given id with an error.
+    pub fn error(id: Option<u64>, kind: ErrorKind, message: String) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            outcome: Outcome::Error { kind, message },
+        }
+    }
+
+    /// Encodes the response on a single line, including the trailing newline.
+    pub fn encode(&self) -> String {
+        // NOTE: serializing a response never fails, as it only contains strings and numbers
+        let mut line = serde_json::to_string(self).unwrap_or_else(|err| {
+            log::error!("Could not encode response {:?}: {}", self, err);
+            format!(
+                r#"{{"version":{},"id":null,"status":"error","kind":"internal","message":""}}"#,
+                PROTOCOL_VERSION
+            )
+        });
+        line.push('\n');
+        line
+    }
+}
+
+impl ErrorKind {
+    /// The kind of error reported to the client for a [`DaemonError`].
+    pub fn of(err: &DaemonError) -> Self {
+        match err {
+            DaemonError::Client(ClientError::InvalidCommand(_))
+            | DaemonError::Client(ClientError::MalformedRequest(_)) => ErrorKind::MalformedRequest,
+            DaemonError::Client(ClientError::UnsupportedVersion(_)) => {
+                ErrorKind::UnsupportedVersion
+            }
+            DaemonError::Client(ClientError::Unsupported(_)) => ErrorKind::Unsupported,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Decodes a JSON request from a single line (without the trailing newline).
+///
+/// The id of the request is retrieved even if the command is malformed, so that the error can be
+/// reported to the right request.
+pub fn decode_request(line: &[u8]) -> (Origin, Result<ClientCommand, ClientError>) {
+    let value = match serde_json::from_slice::<Value>(line) {
+        Ok(value) => value,
+        Err(err) => {
+            return (
+                Origin::Json(None),
+                Err(ClientError::MalformedRequest(err.to_string())),
+            )
+        }
+    };
+
+    let id = value.get("id").and_then(Value::as_u64);
+    let origin = Origin::Json(id);
+
+    let mut fields = match value {
+        Value::Object(fields) => fields,
+        _ => {
+            return (
+                origin,
+                Err(ClientError::MalformedRequest(
+                    "a request must be an object".to_string(),
+                )),
+            )
+        }
+    };
+
+    match fields.remove("version").map(|version| version.as_u64()) {
+        Some(Some(PROTOCOL_VERSION)) => {}
+        Some(Some(version)) => return (origin, Err(ClientError::UnsupportedVersion(version))),
+        _ => {
+            return (
+                origin,
+                Err(ClientError::MalformedRequest(
+                    "missing or invalid version".to_string(),
+                )),
+            )
+        }
+    }
+    if fields.remove("id").and_then(|id| id.as_u64()).is_none() {
+        return (
+            origin,
+            Err(ClientError::MalformedRequest(
+                "missing or invalid id".to_string(),
+            )),
+        );
+    }
+
+    let command = serde_json::from_value::<JsonCommand>(Value::Object(fields))
+        .map(JsonCommand::into_command)
+        .map_err(|err| ClientError::MalformedRequest(err.to_string()));
+    (origin, command)
+}
+
+impl JsonCommand {
+    fn into_command(self) -> ClientCommand {
+        match self {
+            JsonCommand::Init => ClientCommand::Init,
+            JsonCommand::Quit => ClientCommand::Quit,
+            JsonCommand::Previous => ClientCommand::Previous,
+            JsonCommand::RewindTo { line, column } => ClientCommand::RewindTo(line, column),
+            JsonCommand::Query { query } => ClientCommand::Query(query),
+            JsonCommand::MoveTo {
+                timestamp,
+                statements,
+            } => ClientCommand::MoveTo(
+                statements
+                    .into_iter()
+                    .map(|JsonStatement { range, code }| (range.into_range().at(timestamp), code))
+                    .collect(),
+            ),
+            JsonCommand::Next {
+                append,
+                timestamp,
+                range,
+                code,
+            } => ClientCommand::Next(append, range.into_range().at(timestamp), code),
+            JsonCommand::IgnoreError => ClientCommand::IgnoreError,
+            JsonCommand::Hints => ClientCommand::Hints,
+            JsonCommand::ShowGoals { range } => ClientCommand::ShowGoals(range.into_range()),
+            JsonCommand::Status => ClientCommand::Status,
+            JsonCommand::StopInterrupt => ClientCommand::StopInterrupt,
+            JsonCommand::ToggleGoals { category } => ClientCommand::ToggleGoals(match category {
+                JsonGoalCategory::Background => GoalCategory::Background,
+                JsonGoalCategory::Shelved => GoalCategory::Shelved,
+                JsonGoalCategory::GivenUp => GoalCategory::GivenUp,
+            }),
+            JsonCommand::MessagesAt { line, column } => ClientCommand::MessagesAt(line, column),
+            JsonCommand::Edited {
+                timestamp,
+                ranges,
+                edits,
+            } => ClientCommand::Edited(
+                timestamp,
+                ranges
+                    .into_iter()
+                    .map(|range| Edit::unknown_from(range.into_range().normalized().begin))
+                    .chain(edits.into_iter().map(JsonEdit::into_edit))
+                    .collect(),
+            ),
+            JsonCommand::ShowConfig => ClientCommand::ShowConfig,
+            JsonCommand::Restart { replay } => ClientCommand::Restart(replay),
+        }
+    }
+}
+
+impl JsonPosition {
+    fn into_position(self) -> Position {
+        Position::new(self.line, self.column)
+    }
+}
+
+impl JsonEdit {
+    fn into_edit(self) -> Edit {
+        Edit {
+            begin: self.begin.into_position(),
+            removed_end: self.removed_end.into_position(),
+            inserted_end: self.inserted_end.into_position(),
+        }
+    }
+}
+
+impl JsonRange {
+    fn into_range(self) -> Range {
+        Range::new(
+            self.begin.line,
+            self.begin.column,
+            self.end.line,
+            self.end.column,
+        )
+    }
+}
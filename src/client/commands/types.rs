@@ -1,6 +1,6 @@
 use crate::{
-    coqtop::xml_protocol::types::{ProtocolRichPP, ProtocolValue},
-    range::Range,
+    coqtop::{backend::Goals, xml_protocol::types::ProtocolRichPP},
+    range::{Edit, Range},
     state::Operation,
 };
 
@@ -34,9 +34,37 @@ pub enum ClientCommand {
     Status,
     /// Stop interrupting statement processing.
     StopInterrupt,
+    /// Expand (or collapse) a category of goals in the goal buffer.
+    ToggleGoals(GoalCategory),
+    /// Show again all the messages received for the statement at the given line and column numbers.
+    MessagesAt(u64, u64),
+    /// The buffer has been modified by the given edits (in the order they were made), resulting in the given
+    /// timestamp.
+    Edited(u64, Vec<Edit>),
+    /// Show the configuration of the daemon, once merged with the command-line.
+    ShowConfig,
+    /// Kill [`COQTOP`] and start a new one, processing again all the statements which were processed
+    /// if asked to.
+    ///
+    /// [`COQTOP`]: crate::coqtop::coqidetop::COQTOP
+    Restart(bool),
+}
+
+/// The categories of non-focused goals which are only summarized in the goal buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GoalCategory {
+    /// Unfocused (background) goals.
+    Background,
+    /// Shelved goals (e.g. uninstanciated existentials).
+    Shelved,
+    /// Goals which have been given up (e.g. with `admit`).
+    GivenUp,
 }
 
 /// The type of commands that can be sent back to Kakoune.
+///
+/// All ranges carry the timestamp of the buffer they were computed at,
+/// and are translated to the last known timestamp before being shown.
 #[derive(Debug, Clone)]
 pub enum DisplayCommand {
     /// Refresh the error range.
@@ -48,16 +76,9 @@ pub enum DisplayCommand {
     /// Output the result with colors.
     ColorResult(ProtocolRichPP, bool),
     /// Show some goals.
-    OutputGoals(
-        /// Foreground (focused) goals.
-        Vec<ProtocolValue>,
-        /// Background tasks.
-        Vec<(Vec<ProtocolValue>, Vec<ProtocolValue>)>,
-        /// Given up goals.
-        Vec<ProtocolValue>,
-        /// Shelved goals (e.g. uninstanciated existentials)
-        Vec<ProtocolValue>,
-    ),
+    OutputGoals(Goals),
+    /// Expand (or collapse) a category of goals, and show the last goals again.
+    ToggleGoals(GoalCategory),
     /// Remove a range from the to be processed range.
     RemoveToBeProcessed(Range),
     /// Add a range to the processed range.
@@ -72,4 +93,10 @@ pub enum DisplayCommand {
     RemoveAxiom(Range),
     /// Show the status of the worker.
     ShowStatus(String, String),
+    /// Show the result of a query next to its statement.
+    AddInlineResult(Range, ProtocolRichPP),
+    /// Remove the result shown next to a statement.
+    RemoveInlineResult(Range),
+    /// Set the to be processed ranges to exactly those of the operations currently known.
+    ResetRanges,
 }
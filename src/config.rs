@@ -0,0 +1,339 @@
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::{
+    coqtop::backend::BackendKind,
+    files,
+    options::{self, Options},
+};
+
+/// The name of the configuration file, looked for in the ancestors of the edited file.
+pub const CONFIG_FILE: &str = ".coqide.toml";
+
+/// How many `coqidetop` processes a shared daemon runs at the same time, unless configured otherwise.
+pub const DEFAULT_MAX_COQTOPS: NonZeroUsize = NonZeroUsize::new(4).unwrap();
+
+/// The content of a [`CONFIG_FILE`], where all settings are optional.
+///
+/// ```toml
+/// backend = "coqidetop"
+/// coqtop = "coqidetop.opt"
+/// log-level = "info"
+/// project-file = "theories/_CoqProject"
+/// coq-flags = ["-noinit"]
+/// timeout = 30
+/// dune-coq-top = true
+/// watch-project = false
+/// manager = true
+/// max-coqtops = 2
+/// tcp = true
+/// attach = "127.0.0.1:9000"
+///
+/// [async-proofs]
+/// mode = "on"
+/// jobs = 2
+///
+/// [messages]
+/// hide = ["imports", "warning"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// The prover to talk to.
+    pub backend: Option<BackendKind>,
+    /// The executable of the prover to run.
+    pub coqtop: Option<String>,
+    /// Only log messages at least as important as this level.
+    pub log_level: Option<String>,
+    /// The project file to take flags from, relative to the configuration file.
+    pub project_file: Option<PathBuf>,
+    /// Additional flags given as is to `coqidetop`.
+    pub coq_flags: Vec<String>,
+    /// Interrupt any Coq statement which takes longer than this number of seconds.
+    pub timeout: Option<f64>,
+    /// In dune projects, run `coqidetop` through `dune coq top`.
+    pub dune_coq_top: Option<bool>,
+    /// Restart `coqidetop` when the project files are modified.
+    pub watch_project: Option<bool>,
+    /// Share a single daemon between all the buffers of a project.
+    pub manager: Option<bool>,
+    /// When sharing a daemon, the maximum number of `coqidetop` processes running at the same time.
+    pub max_coqtops: Option<NonZeroUsize>,
+    /// Talk to `coqidetop` over TCP connections instead of its standard input and output.
+    pub tcp: Option<bool>,
+    /// Wait for a `coqidetop` started elsewhere to connect to this address, instead of starting one.
+    pub attach: Option<SocketAddr>,
+    /// How proofs are checked in the background.
+    pub async_proofs: AsyncProofs,
+    /// Which messages are shown in the result buffer.
+    pub messages: Messages,
+}
+
+/// The settings for asynchronous proof checking, which are given to `coqidetop` as `-async-proofs` flags.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AsyncProofs {
+    pub mode: Option<AsyncProofsMode>,
+    /// The number of workers checking proofs in the background.
+    pub jobs: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AsyncProofsMode {
+    On,
+    Off,
+    Lazy,
+}
+
+/// Filters on the messages shown in the result buffer.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Messages {
+    /// The kinds of messages which are not shown.
+    ///
+    /// They are still logged, and shown by [`ClientCommand::MessagesAt`].
+    ///
+    /// [`ClientCommand::MessagesAt`]: crate::client::commands::types::ClientCommand::MessagesAt
+    pub hide: Vec<MessageKind>,
+}
+
+/// The kinds of messages which can be hidden.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageKind {
+    Info,
+    Notice,
+    Warning,
+    /// The modules imported by a statement.
+    Imports,
+}
+
+/// The settings of the daemon, once the command-line and the configuration file have been merged.
+///
+/// Settings given on the command-line (or in their environment variables) take precedence over those in the
+/// configuration file, except for Coq flags which are all kept.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The configuration file which has been read, if any.
+    pub file: Option<PathBuf>,
+    /// The prover to talk to.
+    pub backend: BackendKind,
+    /// The executable of the prover to run.
+    pub coqtop: String,
+    /// Only log messages at least as important as this level.
+    pub log_level: LevelFilter,
+    /// The project file to take flags from, instead of looking for one.
+    pub project_file: Option<PathBuf>,
+    /// Do not take flags from any project file.
+    pub no_project_file: bool,
+    /// Additional flags given as is to `coqidetop`, after those coming from the project file.
+    pub coq_flags: Vec<String>,
+    /// Interrupt any Coq statement which takes longer than this.
+    pub timeout: Option<Duration>,
+    /// In dune projects, run `coqidetop` through `dune coq top` to get the exact flags dune would use.
+    pub dune_coq_top: bool,
+    /// Restart `coqidetop` (and process again all the statements) when the project files are modified.
+    pub watch_project: bool,
+    /// Share a single daemon (and its `coqidetop` processes) between all the buffers of a project.
+    pub manager: bool,
+    /// When sharing a daemon, the maximum number of `coqidetop` processes running at the same time.
+    pub max_coqtops: NonZeroUsize,
+    /// Talk to `coqidetop` over TCP connections on the loopback interface, instead of its standard input and
+    /// output.
+    pub tcp: bool,
+    /// Wait for a `coqidetop` started elsewhere to connect to this address and the three next ports, instead of
+    /// starting one.
+    pub attach: Option<SocketAddr>,
+    /// The kinds of messages which are not shown in the result buffer.
+    pub hidden_messages: Vec<MessageKind>,
+}
+
+impl Config {
+    /// Reads the configuration file (given in the options or found in the ancestors of the edited file),
+    /// and merges it with the options.
+    pub fn load(options: &Options) -> io::Result<Arc<Self>> {
+        let path = match &options.config {
+            _ if options.no_config => None,
+            Some(path) => Some(path.clone()),
+            None => files::find_in_ancestors(&options.file, CONFIG_FILE)?,
+        };
+
+        let file = match &path {
+            Some(path) => parse_file(path)?,
+            None => ConfigFile::default(),
+        };
+        Self::merge(options, file, path).map(Arc::new)
+    }
+
+    /// Merges the options with the content of a configuration file found at the given path.
+    pub fn merge(options: &Options, file: ConfigFile, path: Option<PathBuf>) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let log_level = match (options.log_level, &file.log_level) {
+            (Some(level), _) => level,
+            (None, Some(level)) => LevelFilter::from_str(level)
+                .map_err(|_| invalid(format!("Invalid log level `{}`", level)))?,
+            (None, None) => LevelFilter::Debug,
+        };
+        let timeout = match (options.timeout, file.timeout) {
+            (Some(timeout), _) => Some(timeout),
+            (None, Some(timeout)) => Some(options::seconds(timeout).ok_or_else(|| {
+                invalid(format!("`{}` is not a positive number of seconds", timeout))
+            })?),
+            (None, None) => None,
+        };
+        // NOTE: paths in the configuration file are relative to the file itself
+        let directory = path.as_deref().and_then(Path::parent);
+        let project_file = options.project_file.clone().or_else(|| {
+            file.project_file.map(|project_file| match directory {
+                Some(directory) => directory.join(project_file),
+                None => project_file,
+            })
+        });
+
+        let mut coq_flags = file.coq_flags;
+        if let Some(mode) = file.async_proofs.mode {
+            coq_flags.push("-async-proofs".to_string());
+            coq_flags.push(mode.to_string());
+        }
+        if let Some(jobs) = file.async_proofs.jobs {
+            coq_flags.push("-async-proofs-j".to_string());
+            coq_flags.push(jobs.to_string());
+        }
+        coq_flags.extend(options.coq_flags.iter().cloned());
+
+        let backend = options
+            .backend
+            .or(file.backend)
+            .unwrap_or(BackendKind::CoqIdeTop);
+
+        Ok(Self {
+            file: path,
+            backend,
+            coqtop: options
+                .coqtop
+                .clone()
+                .or(file.coqtop)
+                .unwrap_or_else(|| backend.executable().to_string()),
+            log_level,
+            project_file,
+            no_project_file: options.no_project_file,
+            coq_flags,
+            timeout,
+            dune_coq_top: options.dune_coq_top || file.dune_coq_top.unwrap_or_default(),
+            watch_project: !options.no_watch_project && file.watch_project.unwrap_or(true),
+            manager: options.manager || file.manager.unwrap_or_default(),
+            max_coqtops: options
+                .max_coqtops
+                .or(file.max_coqtops)
+                .unwrap_or(DEFAULT_MAX_COQTOPS),
+            tcp: options.tcp || file.tcp.unwrap_or_default(),
+            attach: options.attach.or(file.attach),
+            hidden_messages: file.messages.hide,
+        })
+    }
+
+    /// Whether messages of the given kind are shown in the result buffer.
+    pub fn shows(&self, kind: MessageKind) -> bool {
+        !self.hidden_messages.contains(&kind)
+    }
+}
+
+/// Reads and parses a configuration file.
+pub fn parse_file<P: AsRef<Path>>(path: P) -> io::Result<ConfigFile> {
+    let content = fs::read_to_string(&path)?;
+    toml::from_str(&content).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.as_ref().display(), err),
+        )
+    })
+}
+
+impl fmt::Display for AsyncProofsMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsyncProofsMode::On => write!(f, "on"),
+            AsyncProofsMode::Off => write!(f, "off"),
+            AsyncProofsMode::Lazy => write!(f, "lazy"),
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageKind::Info => write!(f, "info"),
+            MessageKind::Notice => write!(f, "notice"),
+            MessageKind::Warning => write!(f, "warning"),
+            MessageKind::Imports => write!(f, "imports"),
+        }
+    }
+}
+
+/// Shows the configuration in the same format as the configuration file.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quoted = |items: Vec<String>| {
+            items
+                .iter()
+                .map(|item| format!("{:?}", item))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        match &self.file {
+            Some(path) => writeln!(f, "# read from {}", path.display())?,
+            None => writeln!(f, "# no {} file", CONFIG_FILE)?,
+        }
+        writeln!(f, "backend = \"{}\"", self.backend)?;
+        writeln!(f, "coqtop = {:?}", self.coqtop)?;
+        writeln!(
+            f,
+            "log-level = {:?}",
+            self.log_level.to_string().to_lowercase()
+        )?;
+        match &self.project_file {
+            _ if self.no_project_file => writeln!(f, "# no project file")?,
+            Some(path) => writeln!(f, "project-file = {:?}", path.display().to_string())?,
+            None => writeln!(f, "# project file looked for in ancestors")?,
+        }
+        writeln!(f, "coq-flags = [{}]", quoted(self.coq_flags.clone()))?;
+        match self.timeout {
+            Some(timeout) => writeln!(f, "timeout = {}", timeout.as_secs_f64())?,
+            None => writeln!(f, "# no timeout")?,
+        }
+        writeln!(f, "dune-coq-top = {}", self.dune_coq_top)?;
+        writeln!(f, "watch-project = {}", self.watch_project)?;
+        writeln!(f, "manager = {}", self.manager)?;
+        writeln!(f, "max-coqtops = {}", self.max_coqtops)?;
+        writeln!(f, "tcp = {}", self.tcp)?;
+        match self.attach {
+            Some(address) => writeln!(f, "attach = \"{}\"", address)?,
+            None => writeln!(f, "# {} started by the daemon", self.coqtop)?,
+        }
+        writeln!(f)?;
+        writeln!(f, "[messages]")?;
+        write!(
+            f,
+            "hide = [{}]",
+            quoted(
+                self.hidden_messages
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect()
+            )
+        )
+    }
+}
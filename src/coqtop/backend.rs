@@ -0,0 +1,349 @@
+use std::{collections::VecDeque, fmt, future, future::Future, io, sync::Arc, time::Duration};
+
+use async_signals::Signals;
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tokio_stream::StreamExt;
+
+use crate::{
+    config::Config,
+    coqtop::{
+        coqidetop::{CoqIdeTop, COQTOP, SHUTDOWN_SIGNALS},
+        project::Project,
+        sertop::{SerTop, SERTOP},
+        xml_protocol::types::{MessageType, ProtocolRichPP},
+    },
+    session::Session,
+};
+
+/// The provers the daemon can talk to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+pub enum BackendKind {
+    /// [`COQTOP`], through the XML protocol of CoqIDE.
+    #[serde(rename = "coqidetop")]
+    #[value(name = "coqidetop")]
+    CoqIdeTop,
+    /// [`SERTOP`], through the S-expressions of SerAPI.
+    #[serde(rename = "sertop")]
+    #[value(name = "sertop")]
+    SerTop,
+}
+
+/// What interrupts the processing of a statement, besides [`SHUTDOWN_SIGNALS`].
+#[derive(Clone, Debug)]
+pub enum Interrupt {
+    /// `SIGUSR1`, sent to the daemon.
+    Signal,
+    /// A notification, when the daemon serves several buffers and a signal cannot tell which one to interrupt.
+    Notified(Arc<Notify>),
+}
+
+/// What the daemon asks of a prover (see [`ProverBackend`]).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// See [`ProverBackend::init`].
+    Init,
+    /// See [`ProverBackend::add`].
+    Add(String, i64),
+    /// See [`ProverBackend::cancel`].
+    Cancel(i64),
+    /// See [`ProverBackend::goals`].
+    Goals,
+    /// See [`ProverBackend::query`].
+    Query(String, i64),
+    /// See [`ProverBackend::status`].
+    Status,
+}
+
+/// What a prover answered to a [`Call`].
+#[derive(Clone, Debug)]
+pub enum Response {
+    /// The initial state.
+    Initialized(i64),
+    /// The state a statement leads to.
+    Added(i64),
+    /// The statements after some state have been forgotten about.
+    Cancelled,
+    /// The goals at the current state, if there is a proof in progress.
+    Goals(Option<Goals>),
+    /// A query has been processed, and its results given as [`Feedback`].
+    Queried,
+    /// Where the current state is.
+    Status(Status),
+}
+
+/// The answer of a prover, or the error it reported instead.
+pub type Answer<T> = Result<T, Failure>;
+
+/// An error reported by a prover (e.g. because a statement is wrong), as opposed to failing to talk to it.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    /// Where the error is in the statement, in bytes from its beginning, if known.
+    pub location: Option<(usize, usize)>,
+    /// The state the prover went back to, if any.
+    pub safe_state_id: Option<i64>,
+    /// What went wrong.
+    pub message: ProtocolRichPP,
+}
+
+/// A goal to prove.
+#[derive(Clone, Debug)]
+pub struct Goal {
+    /// A unique identifier.
+    pub name: String,
+    pub hypotheses: Vec<ProtocolRichPP>,
+    pub conclusion: ProtocolRichPP,
+    /// The name given by the user (e.g. with `refine ?[lhs]`).
+    pub user_name: Option<String>,
+}
+
+/// All the goals of the proof in progress.
+#[derive(Clone, Debug, Default)]
+pub struct Goals {
+    pub focused: Vec<Goal>,
+    /// The unfocused goals, as those before and those after the focused ones, from the innermost focus.
+    pub background: Vec<(Vec<Goal>, Vec<Goal>)>,
+    /// The goals put aside (e.g. uninstantiated existential variables).
+    pub shelved: Vec<Goal>,
+    pub given_up: Vec<Goal>,
+}
+
+/// Where the current state is.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    /// The path of the current module.
+    pub path: Vec<String>,
+    /// The name of the proof in progress, if any.
+    pub proof: Option<String>,
+}
+
+/// What a prover tells about a state while processing a call.
+#[derive(Clone, Debug)]
+pub struct Feedback {
+    pub state_id: i64,
+    pub content: FeedbackContent,
+}
+
+/// The content of some [`Feedback`].
+#[derive(Clone, Debug)]
+pub enum FeedbackContent {
+    /// A message about the statement leading to the state.
+    Message(MessageType, ProtocolRichPP),
+    /// A module has been loaded, given with its name and path.
+    FileLoaded(String, String),
+    /// The statement uses an axiom (e.g. because it has been admitted).
+    AddedAxiom,
+    /// The statement has been processed.
+    Processed,
+}
+
+/// What the daemon asks of a prover, whatever the protocol it speaks.
+///
+/// Each protocol is translated from and into the types of the daemon.
+/// Statements are identified by the state ID they lead to, starting from the one given by
+/// [`ProverBackend::init`].
+pub trait ProverBackend: Send {
+    /// Initializes the prover, and gives back its initial state.
+    fn init(&mut self) -> impl Future<Output = io::Result<Answer<i64>>> + Send;
+
+    /// Processes a statement on top of the given state, and gives back the state it leads to.
+    fn add(
+        &mut self,
+        code: String,
+        state_id: i64,
+    ) -> impl Future<Output = io::Result<Answer<i64>>> + Send;
+
+    /// Goes back to the given state, forgetting about all the statements processed after it.
+    fn cancel(&mut self, state_id: i64) -> impl Future<Output = io::Result<Answer<()>>> + Send;
+
+    /// The goals at the current state, if there is a proof in progress.
+    fn goals(&mut self) -> impl Future<Output = io::Result<Answer<Option<Goals>>>> + Send;
+
+    /// Processes a statement in a disposable context on top of the given state, whose messages are given as
+    /// feedback.
+    fn query(
+        &mut self,
+        query: String,
+        state_id: i64,
+    ) -> impl Future<Output = io::Result<Answer<()>>> + Send;
+
+    /// Where the current state is.
+    fn status(&mut self) -> impl Future<Output = io::Result<Answer<Status>>> + Send;
+
+    /// All the feedback received while waiting for the last answer.
+    fn feedback(&mut self) -> VecDeque<Feedback>;
+
+    /// Whether the prover has been stopped because it could not be interrupted otherwise, and cannot be used
+    /// anymore.
+    fn is_stopped(&self) -> bool;
+
+    /// Asks the prover to quit, and kills it if it does not.
+    fn quit(self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Sends a call (as made by the client bridge) to the prover.
+    fn ask(&mut self, call: Call) -> impl Future<Output = io::Result<Answer<Response>>> + Send {
+        async move {
+            Ok(match call {
+                Call::Init => self.init().await?.map(Response::Initialized),
+                Call::Add(code, state_id) => self.add(code, state_id).await?.map(Response::Added),
+                Call::Cancel(state_id) => {
+                    self.cancel(state_id).await?.map(|()| Response::Cancelled)
+                }
+                Call::Goals => self.goals().await?.map(Response::Goals),
+                Call::Query(query, state_id) => self
+                    .query(query, state_id)
+                    .await?
+                    .map(|()| Response::Queried),
+                Call::Status => self.status().await?.map(Response::Status),
+            })
+        }
+    }
+}
+
+/// The prover chosen in the configuration.
+pub enum Backend {
+    CoqIdeTop(CoqIdeTop),
+    SerTop(SerTop),
+}
+
+impl Backend {
+    /// Starts the prover chosen in the configuration (see [`Config::backend`]) for the edited file.
+    pub async fn spawn(
+        session: Arc<Session>,
+        config: &Config,
+        project: Option<&Project>,
+        interrupt: Interrupt,
+    ) -> io::Result<Self> {
+        match config.backend {
+            BackendKind::CoqIdeTop => CoqIdeTop::spawn(session, config, project, interrupt)
+                .await
+                .map(Backend::CoqIdeTop),
+            BackendKind::SerTop => SerTop::spawn(session, config, project, interrupt)
+                .await
+                .map(Backend::SerTop),
+        }
+    }
+}
+
+impl ProverBackend for Backend {
+    async fn init(&mut self) -> io::Result<Answer<i64>> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.init().await,
+            Backend::SerTop(prover) => prover.init().await,
+        }
+    }
+
+    async fn add(&mut self, code: String, state_id: i64) -> io::Result<Answer<i64>> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.add(code, state_id).await,
+            Backend::SerTop(prover) => prover.add(code, state_id).await,
+        }
+    }
+
+    async fn cancel(&mut self, state_id: i64) -> io::Result<Answer<()>> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.cancel(state_id).await,
+            Backend::SerTop(prover) => prover.cancel(state_id).await,
+        }
+    }
+
+    async fn goals(&mut self) -> io::Result<Answer<Option<Goals>>> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.goals().await,
+            Backend::SerTop(prover) => prover.goals().await,
+        }
+    }
+
+    async fn query(&mut self, query: String, state_id: i64) -> io::Result<Answer<()>> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.query(query, state_id).await,
+            Backend::SerTop(prover) => prover.query(query, state_id).await,
+        }
+    }
+
+    async fn status(&mut self) -> io::Result<Answer<Status>> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.status().await,
+            Backend::SerTop(prover) => prover.status().await,
+        }
+    }
+
+    fn feedback(&mut self) -> VecDeque<Feedback> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.feedback(),
+            Backend::SerTop(prover) => prover.feedback(),
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.is_stopped(),
+            Backend::SerTop(prover) => prover.is_stopped(),
+        }
+    }
+
+    async fn quit(self) -> io::Result<()> {
+        match self {
+            Backend::CoqIdeTop(prover) => prover.quit().await,
+            Backend::SerTop(prover) => prover.quit().await,
+        }
+    }
+}
+
+impl BackendKind {
+    /// The executable run for this prover, unless configured otherwise.
+    pub fn executable(&self) -> &'static str {
+        match self {
+            BackendKind::CoqIdeTop => COQTOP,
+            BackendKind::SerTop => SERTOP,
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.executable())
+    }
+}
+
+/// Waits until the statement being processed must be interrupted (by the given [`Interrupt`], a shutdown signal
+/// or once the timeout has elapsed), and tells why.
+///
+/// Signals are only listened to from now on, so that those received before do not interrupt the statement.
+pub fn interruption(
+    interrupt: &Interrupt,
+    timeout: Option<Duration>,
+) -> io::Result<impl Future<Output = &'static str>> {
+    let (mut signals, notify) = match interrupt {
+        Interrupt::Signal => (
+            Signals::new([&[libc::SIGUSR1][..], &SHUTDOWN_SIGNALS].concat())?,
+            None,
+        ),
+        Interrupt::Notified(notify) => (Signals::new(SHUTDOWN_SIGNALS)?, Some(notify.clone())),
+    };
+
+    Ok(async move {
+        let notified = async move {
+            match notify {
+                Some(notify) => notify.notified().await,
+                None => future::pending().await,
+            }
+        };
+        let deadline = async move {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            // NOTE: the main loop also receives shutdown signals, and quits once the statement is interrupted
+            Some(signal) = signals.next() => match signal {
+                libc::SIGUSR1 => "Processing of Coq statement has been interrupted",
+                _ => "Processing of Coq statement has been interrupted to quit",
+            },
+            _ = notified => "Processing of Coq statement has been interrupted",
+            _ = deadline => "Processing of Coq statement has timed out",
+        }
+    })
+}
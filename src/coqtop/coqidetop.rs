@@ -1,86 +1,114 @@
-use std::{collections::VecDeque, io, process::Stdio, sync::Arc};
+use std::{collections::VecDeque, io, path::Path, sync::Arc, time::Duration};
 
-use async_signals::Signals;
-use tokio::{
-    io::AsyncWriteExt,
-    process::{Child, ChildStdin, ChildStdout, Command},
-};
-use tokio_stream::StreamExt;
+use tokio::{io::AsyncWriteExt, process::Command};
 use tokio_util::codec::FramedRead;
 
 use crate::{
+    config::Config,
     coqtop::{
-        coqproject::{self, COQPROJECT},
-        xml_protocol::{parser::xml_decoder, types::ProtocolResult},
+        backend::{
+            interruption, Answer, Failure, Feedback, FeedbackContent, Goal, Goals, Interrupt,
+            ProverBackend, Status,
+        },
+        project::{synthetic_module_name, Project},
+        transport::{Reader, Transport, Writer},
+        xml_protocol::{
+            parser::xml_decoder,
+            types::{self, ProtocolResult, ProtocolValue},
+        },
     },
-    session::{edited_file, temporary_folder, Session},
+    error::{DaemonError, ProtocolError},
+    session::{edited_file, Session},
 };
 
-use super::xml_protocol::{parser::XMLDecoder, types::ProtocolCall};
+use super::xml_protocol::{
+    parser::{XMLDecoder, XMLNode},
+    types::ProtocolCall,
+};
 
 /// The name of the `coqtop` process.
 pub const COQTOP: &'static str = "coqidetop";
 
+/// The signals asking the daemon to quit.
+pub const SHUTDOWN_SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGHUP];
+
+/// How long a prover is given to quit by itself, before being killed.
+pub const QUIT_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub struct CoqIdeTop {
-    /// The main channel where [`COQTOP`] sends its responses.
-    //main_r: TcpStream,
     /// The main channel to send commands (calls, see [`ProtocolCall`]) to [`COQTOP`].
     ///
     /// [`ProtocolCall`]: crate::coqtop::xml_protocol::types::ProtocolCall
-    main_w: ChildStdin,
-    /// The underlying process.
-    _process: Child,
-    /// The framed reader which decodes all input coming from [`COQTOP`]'s stdout.
-    reader: FramedRead<ChildStdout, XMLDecoder>,
+    main_w: Writer,
+    /// The framed reader which decodes all input coming from the main channel where [`COQTOP`] sends its
+    /// responses.
+    reader: FramedRead<Reader, XMLDecoder>,
+    /// The channels to [`COQTOP`], and the underlying process if it has been started by the daemon.
+    transport: Transport,
+    /// How long to wait for a response before interrupting [`COQTOP`].
+    timeout: Option<Duration>,
+    /// What interrupts the statement being processed.
+    interrupt: Interrupt,
+    /// The feedback received while waiting for the last response.
+    feedback: VecDeque<Feedback>,
 }
 
 impl CoqIdeTop {
     /// Creates a new [`COQTOP`] wrapper which allows asynchronously processing messages coming
     /// from an unbounded channel.
-    pub async fn spawn(session: Arc<Session>) -> io::Result<Self> {
+    ///
+    /// Flags are taken from the project (if any), followed by the additional flags given in the
+    /// configuration.
+    /// In dune projects, `dune coq top` may be asked for the flags instead.
+    ///
+    /// When [`Config::attach`] is set, the daemon waits for a [`COQTOP`] started by someone else instead,
+    /// which must be given the flags itself.
+    pub async fn spawn(
+        session: Arc<Session>,
+        config: &Config,
+        project: Option<&Project>,
+        interrupt: Interrupt,
+    ) -> io::Result<Self> {
         let file = edited_file(session.clone());
-        let additional_flags = coqproject::find_and_parse_from(file.clone()).await;
-        let mut flags = vec!["-topfile".to_string(), file];
-
-        match additional_flags {
-            Ok(mut additional_flags) => {
-                if additional_flags.is_empty() {
-                    log::warn!(
-                        "No {} file found in parent directories...",
-                        coqproject::COQPROJECT
-                    );
-                }
 
-                flags.append(&mut additional_flags);
+        let (command, mut flags) = match project {
+            Some(Project::Dune(project)) if config.dune_coq_top => {
+                (project.coq_top(&config.coqtop), vec![])
             }
-            Err(_) => {
-                log::warn!("Malformed or not found: {}", COQPROJECT);
+            project => {
+                let mut flags = top_flags(file, project);
+                if let Some(project) = project {
+                    flags.append(&mut project.coqtop_flags());
+                }
+                (Command::new(&config.coqtop), flags)
             }
-        }
+        };
+        flags.extend(config.coq_flags.iter().cloned());
 
-        let mut coqidetop = coqidetop(&temporary_folder(session.clone()), [0, 0], flags).await?;
+        let (transport, reader, main_w) = match config.attach {
+            Some(address) => Transport::attach(address, &flags).await?,
+            None if config.tcp => Transport::tcp(command, flags).await?,
+            None => Transport::stdio(command, flags)?,
+        };
 
-        log::info!(
-            "{} (process {}) is up and running!",
-            COQTOP,
-            coqidetop.id().unwrap_or(0)
-        );
-
-        let reader = xml_decoder(coqidetop.stdout.take().unwrap());
+        match transport.id() {
+            Some(id) => log::info!("{} (process {}) is up and running!", config.coqtop, id),
+            None => log::info!("{} is up and running!", config.coqtop),
+        }
 
         Ok(Self {
-            main_w: coqidetop.stdin.take().unwrap(),
-            _process: coqidetop,
-            reader,
+            main_w,
+            reader: xml_decoder(reader),
+            transport,
+            timeout: config.timeout,
+            interrupt,
+            feedback: VecDeque::new(),
         })
     }
 
     /// Send a [`ProtocolCall`] to [`COQTOP`] and wait until a response is received,
     /// potentially accumulating some feedback along the way.
-    pub async fn ask(
-        &mut self,
-        call: ProtocolCall,
-    ) -> io::Result<(ProtocolResult, VecDeque<ProtocolResult>)> {
+    async fn call(&mut self, call: ProtocolCall) -> io::Result<ProtocolResult> {
         let encoded = call.encode();
         log::debug!(
             "Sending XML-encoded command `{}` to {} process",
@@ -88,47 +116,268 @@ impl CoqIdeTop {
             COQTOP
         );
 
+        self.feedback.clear();
         self.main_w.write_all(encoded.as_bytes()).await?;
 
-        let mut feedback = VecDeque::new();
-        let mut signals = Signals::new(vec![libc::SIGUSR1])?;
+        let interrupted = interruption(&self.interrupt, self.timeout)?;
+        tokio::pin!(interrupted);
 
         loop {
             tokio::select! {
-                Some(libc::SIGUSR1) = signals.next() => {
-                    unsafe { libc::kill(self._process.id().unwrap() as i32, libc::SIGINT) };
+                message = &mut interrupted => {
+                    self.transport.interrupt().await;
 
-                    break Err(io::Error::new(io::ErrorKind::Interrupted, "Processing of Coq statement has been interrupted"));
+                    break Err(io::Error::new(io::ErrorKind::Interrupted, message));
                 }
-                Ok(response) = ProtocolResult::decode_stream(&mut self.reader) => {
-                    if response.is_feedback() {
-                        feedback.push_back(response);
-                    } else {
-                        break Ok((response, feedback));
+                node = XMLNode::decode_stream(&mut self.reader) => {
+                    let node = node?;
+                    let is_feedback = node.name == "feedback";
+
+                    match ProtocolResult::decode(node) {
+                        Ok(response) if response.is_feedback() => match translate_feedback(response) {
+                            Ok(Some(feedback)) => self.feedback.push_back(feedback),
+                            Ok(None) => {}
+                            Err(err) => log::error!("Ignoring feedback: {}", err),
+                        },
+                        Ok(response) => break Ok(response),
+                        // NOTE: feedback is only informative, and a malformed one must not prevent
+                        //       us from receiving the response
+                        Err(err) if is_feedback => log::error!("Ignoring feedback: {}", err),
+                        Err(err) => break Err(err),
                     }
                 }
             }
         }
     }
+}
+
+impl ProverBackend for CoqIdeTop {
+    async fn init(&mut self) -> io::Result<Answer<i64>> {
+        let response = self
+            .call(ProtocolCall::Init(ProtocolValue::Optional(None)))
+            .await?;
+        translate_response(response, state_id)
+    }
+
+    async fn add(&mut self, code: String, state_id: i64) -> io::Result<Answer<i64>> {
+        use ProtocolValue::*;
+
+        let response = self.call(ProtocolCall::Add(code, state_id)).await?;
+        translate_response(response, |value| match value {
+            // NOTE: the new state is on the right when the statement closes a proof focused on another state
+            Pair(box StateId(state_id), box Pair(box Inl(box Unit), _)) => Ok(state_id),
+            Pair(box StateId(_), box Pair(box Inr(box state_id), _)) => self::state_id(state_id),
+            value => Err(ProtocolError::UnexpectedValue("an added state", value)),
+        })
+    }
+
+    async fn cancel(&mut self, state_id: i64) -> io::Result<Answer<()>> {
+        let response = self.call(ProtocolCall::EditAt(state_id)).await?;
+        translate_response(response, |_| Ok(()))
+    }
+
+    async fn goals(&mut self) -> io::Result<Answer<Option<Goals>>> {
+        let response = self.call(ProtocolCall::Goal).await?;
+        translate_response(response, |value| match value {
+            ProtocolValue::Optional(None) => Ok(None),
+            ProtocolValue::Optional(Some(box ProtocolValue::Goals(fg, bg, sg, gg))) => {
+                let goals = |goals: Vec<ProtocolValue>| {
+                    goals.into_iter().map(goal).collect::<Result<Vec<_>, _>>()
+                };
+
+                Ok(Some(Goals {
+                    focused: goals(fg)?,
+                    background: bg
+                        .into_iter()
+                        .map(|(before, after)| Ok((goals(before)?, goals(after)?)))
+                        .collect::<Result<_, _>>()?,
+                    shelved: goals(sg)?,
+                    given_up: goals(gg)?,
+                }))
+            }
+            value => Err(ProtocolError::UnexpectedValue("goals", value)),
+        })
+    }
+
+    async fn query(&mut self, query: String, state_id: i64) -> io::Result<Answer<()>> {
+        use ProtocolValue::*;
+
+        // NOTE: the results of the query are given as feedback on the default route
+        let response = self
+            .call(ProtocolCall::Query(Pair(
+                Box::new(RouteId(0)),
+                Box::new(Pair(Box::new(Str(query)), Box::new(StateId(state_id)))),
+            )))
+            .await?;
+        translate_response(response, |_| Ok(()))
+    }
+
+    async fn status(&mut self) -> io::Result<Answer<Status>> {
+        let response = self
+            .call(ProtocolCall::Status(ProtocolValue::Boolean(false)))
+            .await?;
+        translate_response(response, |value| match value {
+            ProtocolValue::Status(
+                box ProtocolValue::List(path),
+                box ProtocolValue::Optional(proof),
+                _,
+                _,
+            ) => Ok(Status {
+                path: path.into_iter().map(string).collect::<Result<_, _>>()?,
+                proof: proof.map(|box proof| string(proof)).transpose()?,
+            }),
+            value => Err(ProtocolError::UnexpectedValue("a status", value)),
+        })
+    }
 
-    /// Stops the underlying [`COQTOP`] process dirtily.
-    pub async fn quit(mut self) -> io::Result<()> {
-        self._process.kill().await?;
+    fn feedback(&mut self) -> VecDeque<Feedback> {
+        std::mem::take(&mut self.feedback)
+    }
+
+    /// Whether [`COQTOP`] has been stopped because it could not be interrupted otherwise (see
+    /// [`Transport::interrupt`]), and cannot be used anymore.
+    fn is_stopped(&self) -> bool {
+        self.transport.is_stopped()
+    }
+
+    /// Asks the underlying [`COQTOP`] process to quit, and kills it if it has not quit after [`QUIT_TIMEOUT`].
+    async fn quit(mut self) -> io::Result<()> {
+        if self.is_stopped() {
+            return Ok(());
+        }
+
+        let quit = async {
+            self.call(ProtocolCall::Quit).await?;
+            self.transport.wait().await
+        };
+
+        match tokio::time::timeout(QUIT_TIMEOUT, quit).await {
+            Ok(Ok(Some(status))) => {
+                log::info!("{} has quit ({})", COQTOP, status);
+                return Ok(());
+            }
+            Ok(Ok(None)) => {
+                log::info!("{} has quit", COQTOP);
+                return Ok(());
+            }
+            Ok(Err(err)) => log::warn!("{} could not quit by itself: {}", COQTOP, err),
+            Err(_) => log::warn!(
+                "{} has not quit after {} seconds",
+                COQTOP,
+                QUIT_TIMEOUT.as_secs()
+            ),
+        }
+
+        log::info!("Killing {}", COQTOP);
+        self.transport.kill().await
+    }
+}
+
+/// Translates a response of the XML protocol, whose value (if it is good) is translated by `good`.
+///
+/// Responses which do not fit what was asked are given back as [`DaemonError`]s.
+pub fn translate_response<T, F>(response: ProtocolResult, good: F) -> io::Result<Answer<T>>
+where
+    F: FnOnce(ProtocolValue) -> Result<T, ProtocolError>,
+{
+    match response {
+        ProtocolResult::Good(value) => Ok(Ok(good(value).map_err(DaemonError::from)?)),
+        ProtocolResult::Fail(start, stop, ProtocolValue::StateId(safe_state_id), message) => {
+            Ok(Err(Failure {
+                location: start
+                    .zip(stop)
+                    .map(|(start, stop)| (start as usize, stop as usize)),
+                safe_state_id: Some(safe_state_id).filter(|state_id| *state_id > 0),
+                message,
+            }))
+        }
+        ProtocolResult::Fail(_, _, state_id, _) => {
+            Err(DaemonError::from(ProtocolError::UnexpectedValue("a state ID", state_id)).into())
+        }
+        result => Err(DaemonError::from(ProtocolError::UnexpectedResult(
+            "a response",
+            Box::new(result),
+        ))
+        .into()),
+    }
+}
+
+/// Translates some feedback of the XML protocol, unless it is of no use to the daemon.
+pub fn translate_feedback(feedback: ProtocolResult) -> Result<Option<Feedback>, ProtocolError> {
+    let (state_id, content) = match feedback {
+        ProtocolResult::Feedback(_, _, ProtocolValue::StateId(state_id), content) => {
+            (state_id, content)
+        }
+        ProtocolResult::Feedback(_, _, state_id, _) => {
+            return Err(ProtocolError::UnexpectedValue("a state ID", state_id))
+        }
+        result => {
+            return Err(ProtocolError::UnexpectedResult(
+                "some feedback",
+                Box::new(result),
+            ))
+        }
+    };
+
+    let content = match content {
+        types::FeedbackContent::Message(level, message) => FeedbackContent::Message(level, message),
+        types::FeedbackContent::FileLoaded(ProtocolValue::Str(name), ProtocolValue::Str(path)) => {
+            FeedbackContent::FileLoaded(name, path)
+        }
+        types::FeedbackContent::AddedAxiom => FeedbackContent::AddedAxiom,
+        types::FeedbackContent::Processed => FeedbackContent::Processed,
+        content => {
+            log::debug!("Received feedback object @{}: {:?}", state_id, content);
+            return Ok(None);
+        }
+    };
+    Ok(Some(Feedback { state_id, content }))
+}
+
+fn state_id(value: ProtocolValue) -> Result<i64, ProtocolError> {
+    match value {
+        ProtocolValue::StateId(state_id) => Ok(state_id),
+        value => Err(ProtocolError::UnexpectedValue("a state ID", value)),
+    }
+}
+
+fn string(value: ProtocolValue) -> Result<String, ProtocolError> {
+    match value {
+        ProtocolValue::Str(string) => Ok(string),
+        value => Err(ProtocolError::UnexpectedValue("a string", value)),
+    }
+}
 
-        Ok(())
+fn goal(value: ProtocolValue) -> Result<Goal, ProtocolError> {
+    match value {
+        ProtocolValue::Goal(
+            box ProtocolValue::Str(name),
+            hypotheses,
+            conclusion,
+            box user_name,
+        ) => Ok(Goal {
+            name,
+            hypotheses,
+            conclusion,
+            user_name: match user_name {
+                ProtocolValue::Optional(Some(box ProtocolValue::Str(user_name))) => Some(user_name),
+                _ => None,
+            },
+        }),
+        value => Err(ProtocolError::UnexpectedValue("a goal", value)),
     }
 }
 
-/// Spawns a new [`COQTOP`] process by feeding it additional flags to take in account.
-async fn coqidetop(_tmp_dir: &String, _ports: [u16; 2], flags: Vec<String>) -> io::Result<Child> {
-    Command::new(COQTOP)
-        .arg("-main-channel")
-        // .arg(format!("127.0.0.1:{}:{}", ports[0], ports[1]))
-        .arg("stdfds")
-        .args(&flags)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .kill_on_drop(true)
-        .spawn()
+/// The flags naming the module defined by the edited file.
+///
+/// The name is derived from the mappings of the project, so that it is the same as when building the project.
+/// Buffers which are not files on disk are given a made-up name, as [`COQTOP`] would reject them.
+fn top_flags(file: String, project: Option<&Project>) -> Vec<String> {
+    let flags = match project.and_then(|project| project.module_name(&file)) {
+        Some(name) => vec!["-top".to_string(), name],
+        None if Path::new(&file).is_file() => vec!["-topfile".to_string(), file],
+        None => vec!["-top".to_string(), synthetic_module_name(&file)],
+    };
+    log::debug!("Naming the edited module with {}", flags.join(" "));
+    flags
 }
@@ -1,54 +1,144 @@
-use std::{io, path::Path};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::files;
+
+use self::parser::{Diagnostic, ProjectOption};
 
 pub mod parser;
 
-pub const COQPROJECT: &'static str = "_CoqProject";
-
-pub async fn find_and_parse_from(file: String) -> io::Result<Vec<String>> {
-    let file_path = Path::new(&file).canonicalize()?;
-    let directory = file_path.parent().unwrap();
-
-    for dir in directory.ancestors() {
-        let dir = dir.join(COQPROJECT);
-        if dir.exists() {
-            let file_args = parser::parse_file(&dir).await;
-            let mut args = vec![];
-
-            let mut iterator = file_args.into_iter();
-            while let Some(opt) = iterator.next() {
-                match opt.as_str() {
-                    "-R" => {
-                        args.append(&mut vec![
-                            opt,
-                            iterator.next().ok_or(io::ErrorKind::InvalidData)?,
-                            iterator.next().ok_or(io::ErrorKind::InvalidData)?,
-                        ]);
-                    }
-                    "-I" => {
-                        args.append(&mut vec![
-                            opt,
-                            iterator.next().ok_or(io::ErrorKind::InvalidData)?,
-                        ]);
-                    }
-                    "-Q" => {
-                        args.append(&mut vec![
-                            opt,
-                            iterator.next().ok_or(io::ErrorKind::InvalidData)?,
-                            iterator.next().ok_or(io::ErrorKind::InvalidData)?,
-                        ]);
-                    }
-                    "-arg" => {
-                        args.append(&mut parser::parse(
-                            &iterator.next().ok_or(io::ErrorKind::InvalidData)?,
-                        ));
+pub const COQPROJECT: &str = "_CoqProject";
+
+/// How deep `-f` options can be nested, so that project files including each other are not read forever.
+const MAX_DEPTH: usize = 16;
+
+/// A parsed project file.
+#[derive(Clone, Debug)]
+pub struct CoqProject {
+    /// The path to the project file.
+    pub path: PathBuf,
+    /// All the options of the project, where project files included with `-f` have been read already.
+    pub options: Vec<ProjectOption>,
+    /// All the problems found in the project file and in the files it includes.
+    pub diagnostics: Vec<(PathBuf, Diagnostic)>,
+    /// All the project files which have been read, starting with the main one.
+    pub sources: Vec<PathBuf>,
+}
+
+/// Looks for a [`COQPROJECT`] file in the directory of the given file, or in any of its ancestors.
+pub fn find_from(file: &str) -> io::Result<Option<PathBuf>> {
+    files::find_in_ancestors(file, COQPROJECT)
+}
+
+impl CoqProject {
+    /// Reads and parses a project file, along with all the project files it includes.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read_to_string(&path)?;
+        Ok(Self::parse(path, &content))
+    }
+
+    /// Parses the content of a project file, as if it was found at the given path.
+    pub fn parse<P: AsRef<Path>>(path: P, content: &str) -> Self {
+        let mut project = Self {
+            path: path.as_ref().to_path_buf(),
+            options: vec![],
+            diagnostics: vec![],
+            sources: vec![],
+        };
+        project.include(path.as_ref(), content.to_string(), 0);
+        project
+    }
+
+    fn include(&mut self, path: &Path, content: String, depth: usize) {
+        self.sources.push(path.to_path_buf());
+
+        let (options, diagnostics) = parser::parse(&content);
+        self.diagnostics.extend(
+            diagnostics
+                .into_iter()
+                .map(|diagnostic| (path.to_path_buf(), diagnostic)),
+        );
+
+        for (line, option) in options {
+            match option {
+                ProjectOption::ProjectFile(file) => {
+                    let included = relative_to(&self.path, &file);
+                    let content = match fs::read_to_string(&included) {
+                        _ if depth >= MAX_DEPTH => Err(format!(
+                            "Too many nested project files when including `{}`",
+                            file
+                        )),
+                        Ok(content) => Ok(content),
+                        Err(err) => Err(format!("Cannot read `{}`: {}", file, err)),
+                    };
+                    match content {
+                        Ok(content) => self.include(&included, content, depth + 1),
+                        Err(message) => self
+                            .diagnostics
+                            .push((path.to_path_buf(), Diagnostic { line, message })),
                     }
-                    _ => {}
                 }
+                option => self.options.push(option),
             }
+        }
+    }
+
+    /// All the files which are part of the project.
+    pub fn files(&self) -> impl Iterator<Item = &String> {
+        self.options.iter().filter_map(|option| match option {
+            ProjectOption::File(file) => Some(file),
+            _ => None,
+        })
+    }
+
+    /// The directories bound to logical paths (with `-R` or `-Q`), relative to the project file.
+    pub fn mappings(&self) -> Vec<(PathBuf, String)> {
+        self.options
+            .iter()
+            .filter_map(|option| match option {
+                ProjectOption::Recursive(physical, logical)
+                | ProjectOption::Qualified(physical, logical) => {
+                    Some((relative_to(&self.path, physical), logical.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 
-            return Ok(args);
+    /// The flags to give to `coqidetop`, where physical paths are relative to the project file.
+    ///
+    /// As with `coq_makefile`, paths in included project files are also relative to the main project file.
+    pub fn coqtop_flags(&self) -> Vec<String> {
+        let path = |physical: &String| relative_to(&self.path, physical).display().to_string();
+
+        let mut flags = vec![];
+        for option in &self.options {
+            match option {
+                ProjectOption::Include(dir) => flags.extend(["-I".to_string(), path(dir)]),
+                ProjectOption::Recursive(physical, logical) => {
+                    flags.extend(["-R".to_string(), path(physical), logical.clone()])
+                }
+                ProjectOption::Qualified(physical, logical) => {
+                    flags.extend(["-Q".to_string(), path(physical), logical.clone()])
+                }
+                ProjectOption::Arg(args) => flags.extend(args.iter().cloned()),
+                ProjectOption::NativeCompiler(mode) => {
+                    flags.extend(["-native-compiler".to_string(), mode.to_string()])
+                }
+                // NOTE: everything else is only used when building the project
+                _ => {}
+            }
         }
+        flags
     }
+}
 
-    Ok(vec![])
+/// Resolves a path found in a project file, which is relative to the directory of this file.
+fn relative_to(project_file: &Path, path: &str) -> PathBuf {
+    match project_file.parent() {
+        Some(directory) => directory.join(path),
+        None => PathBuf::from(path),
+    }
 }
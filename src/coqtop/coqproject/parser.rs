@@ -1,67 +1,247 @@
-use std::{convert::identity, fs::File, io::Read, path::Path};
+use std::fmt;
 
 use nom::{
     branch::alt,
-    character::complete::{anychar, char, line_ending, multispace1, satisfy},
-    combinator::{cut, map, peek, value},
-    multi::{many1, many_till, separated_list0},
-    sequence::{delimited, preceded},
+    bytes::complete::{take_till, take_till1},
+    character::complete::{char, multispace1, not_line_ending},
+    combinator::{map, value},
+    multi::many0_count,
+    sequence::{delimited, pair},
     IResult,
 };
 
-pub async fn parse_file<P>(path: P) -> Vec<String>
-where
-    P: AsRef<Path>,
-{
-    let mut file = File::open(path).unwrap();
-    tokio::task::block_in_place(move || {
-        let mut content = String::new();
-        file.read_to_string(&mut content).unwrap();
-        parse(&content)
-    })
+/// A word of a project file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    /// The line the word starts on (starting from 1).
+    pub line: usize,
+    pub text: String,
+    /// Was the word written between double quotes?
+    pub quoted: bool,
 }
 
-pub fn parse(text: &str) -> Vec<String> {
-    separated_list0(multispace1, parse_flag)(text)
-        .unwrap()
-        .1
-        .into_iter()
-        .filter_map(identity)
-        .collect()
+/// A problem found in a project file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The line the problem was found on (starting from 1).
+    pub line: usize,
+    pub message: String,
 }
 
-// --------------------------------------------------------------------------
+/// All the options which can be given in a `_CoqProject` file, as documented for `coq_makefile`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProjectOption {
+    /// `-I <dir>`: look for OCaml dependencies in the given directory.
+    Include(String),
+    /// `-R <physical path> <logical path>`: bind a directory (recursively) to a logical path, which is
+    /// also loaded implicitly.
+    Recursive(String, String),
+    /// `-Q <physical path> <logical path>`: bind a directory (recursively) to a logical path.
+    Qualified(String, String),
+    /// `-arg <args>`: additional arguments for Coq, already split.
+    Arg(Vec<String>),
+    /// `-native-compiler <mode>`: whether Coq should compile to native code.
+    NativeCompiler(NativeCompiler),
+    /// `-docroot <path>`: where documentation is installed, relative to `user-contrib`.
+    Docroot(String),
+    /// `-o <file>`: the name of the generated makefile.
+    Output(String),
+    /// `-f <file>`: read more options from another project file.
+    ProjectFile(String),
+    /// `-generate-meta-for-package <name>`: generate a `META` file for the given `findlib` package.
+    GenerateMetaForPackage(String),
+    /// `<name> = <value>`: a variable defined in the generated makefile.
+    Variable(String, String),
+    /// A Coq (`.v`) or OCaml (`.ml`, `.mli`, `.mlg`, `.mllib`, `.mlpack`) file belonging to the project.
+    File(String),
+}
 
-fn parse_flag(input: &str) -> IResult<&str, Option<String>> {
-    alt((parse_option, parse_string, parse_word, parse_comment))(input)
+/// The modes of `-native-compiler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NativeCompiler {
+    Yes,
+    No,
+    OnDemand,
 }
 
-fn parse_option(input: &str) -> IResult<&str, Option<String>> {
-    preceded(
-        char('-'),
-        cut(map(parse_word, |txt| txt.map(|txt| "-".to_string() + &txt))),
-    )(input)
+/// The extensions of the files which can be part of a project.
+pub const FILE_EXTENSIONS: [&str; 6] = ["v", "ml", "mli", "mlg", "mllib", "mlpack"];
+
+/// Parses the content of a project file, returning all the options found (along with the line they were
+/// found on) and all the problems encountered.
+///
+/// Malformed options are skipped, so that the rest of the file can still be used.
+pub fn parse(text: &str) -> (Vec<(usize, ProjectOption)>, Vec<Diagnostic>) {
+    let (tokens, mut diagnostics) = tokenize(text);
+
+    let mut options = vec![];
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let line = token.line;
+        let mut argument = |count: usize| {
+            // NOTE: options are never arguments (unless they are quoted), except for Coq arguments
+            match tokens
+                .next_if(|arg| arg.quoted || !arg.text.starts_with('-') || token.text == "-arg")
+            {
+                Some(arg) => Ok(arg.text),
+                None => Err(Diagnostic {
+                    line,
+                    message: format!(
+                        "`{}` expects {} argument{}",
+                        token.text,
+                        count,
+                        if count > 1 { "s" } else { "" }
+                    ),
+                }),
+            }
+        };
+
+        let option = match token.text.as_str() {
+            _ if token.quoted => Err(Diagnostic {
+                line,
+                message: format!("Unexpected string \"{}\"", token.text),
+            }),
+            "-I" => argument(1).map(ProjectOption::Include),
+            "-R" => argument(2)
+                .and_then(|physical| Ok(ProjectOption::Recursive(physical, argument(2)?))),
+            "-Q" => argument(2)
+                .and_then(|physical| Ok(ProjectOption::Qualified(physical, argument(2)?))),
+            "-arg" => argument(1).map(|args| {
+                let (args, errors) = tokenize(&args);
+                diagnostics.extend(errors.into_iter().map(|err| Diagnostic { line, ..err }));
+                ProjectOption::Arg(args.into_iter().map(|arg| arg.text).collect())
+            }),
+            "-native-compiler" => argument(1).and_then(|mode| match mode.as_str() {
+                "yes" => Ok(ProjectOption::NativeCompiler(NativeCompiler::Yes)),
+                "no" => Ok(ProjectOption::NativeCompiler(NativeCompiler::No)),
+                "ondemand" => Ok(ProjectOption::NativeCompiler(NativeCompiler::OnDemand)),
+                _ => Err(Diagnostic {
+                    line,
+                    message: format!(
+                        "`-native-compiler` expects `yes`, `no` or `ondemand`, not `{}`",
+                        mode
+                    ),
+                }),
+            }),
+            "-docroot" => argument(1).map(ProjectOption::Docroot),
+            "-o" => argument(1).map(ProjectOption::Output),
+            "-f" => argument(1).map(ProjectOption::ProjectFile),
+            "-generate-meta-for-package" => argument(1).map(ProjectOption::GenerateMetaForPackage),
+            "-h" | "--help" => Err(Diagnostic {
+                line,
+                message: format!("`{}` cannot be used in a project file", token.text),
+            }),
+            option if option.starts_with('-') => Err(Diagnostic {
+                line,
+                message: format!("Unknown option `{}`", option),
+            }),
+            name if tokens
+                .peek()
+                .is_some_and(|next| next.text == "=" && !next.quoted) =>
+            {
+                tokens.next();
+                match tokens.next() {
+                    Some(value) => Ok(ProjectOption::Variable(name.to_string(), value.text)),
+                    None => Err(Diagnostic {
+                        line,
+                        message: format!("Missing value for variable `{}`", name),
+                    }),
+                }
+            }
+            file if FILE_EXTENSIONS
+                .iter()
+                .any(|ext| file.strip_suffix(ext).is_some_and(|f| f.ends_with('.'))) =>
+            {
+                Ok(ProjectOption::File(file.to_string()))
+            }
+            other => Err(Diagnostic {
+                line,
+                message: format!("Unknown option or file `{}`", other),
+            }),
+        };
+
+        match option {
+            Ok(option) => options.push((line, option)),
+            Err(diagnostic) => diagnostics.push(diagnostic),
+        }
+    }
+
+    (options, diagnostics)
 }
 
-fn parse_word(input: &str) -> IResult<&str, Option<String>> {
-    let not_whitespace = satisfy(char::is_whitespace);
+/// Splits some text into words separated by blanks, which may be quoted.
+///
+/// Comments (from a `#` to the end of the line) are ignored.
+/// Quoted words cannot contain double quotes, as there is no escape sequence.
+pub fn tokenize(text: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let line_of = |rest: &str| text[..text.len() - rest.len()].matches('\n').count() + 1;
+
+    let mut tokens = vec![];
+    let mut input = text;
+    loop {
+        // NOTE: skipping blanks never fails
+        input = blanks(input).map_or(input, |(rest, _)| rest);
+        if input.is_empty() {
+            break (tokens, vec![]);
+        }
 
-    map(many1(not_whitespace), |txt| {
-        Some(String::from_iter(txt.into_iter()))
-    })(input)
+        let line = line_of(input);
+        match token(input) {
+            Ok((rest, (text, quoted))) => {
+                tokens.push(Token {
+                    line,
+                    text: text.to_string(),
+                    quoted,
+                });
+                input = rest;
+            }
+            Err(_) => {
+                break (
+                    tokens,
+                    vec![Diagnostic {
+                        line,
+                        message: "Unterminated string".to_string(),
+                    }],
+                )
+            }
+        }
+    }
 }
 
-fn parse_string(input: &str) -> IResult<&str, Option<String>> {
-    map(
-        delimited(
-            char('"'),
-            cut(map(many_till(anychar, peek(char('"'))), |s| s.0)),
-            char('"'),
+// --------------------------------------------------------------------------
+
+fn blanks(input: &str) -> IResult<&str, usize> {
+    many0_count(alt((
+        value((), multispace1),
+        value((), pair(char('#'), not_line_ending)),
+    )))(input)
+}
+
+fn token(input: &str) -> IResult<&str, (&str, bool)> {
+    alt((
+        map(
+            delimited(char('"'), take_till(|c| c == '"'), char('"')),
+            |text| (text, true),
         ),
-        |txt| Some(String::from_iter(txt.into_iter())),
-    )(input)
+        map(
+            take_till1(|c: char| c.is_whitespace() || c == '"'),
+            |text| (text, false),
+        ),
+    ))(input)
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
 }
 
-fn parse_comment(input: &str) -> IResult<&str, Option<String>> {
-    preceded(char('#'), cut(value(None, many_till(anychar, line_ending))))(input)
+impl fmt::Display for NativeCompiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NativeCompiler::Yes => write!(f, "yes"),
+            NativeCompiler::No => write!(f, "no"),
+            NativeCompiler::OnDemand => write!(f, "ondemand"),
+        }
+    }
 }
@@ -0,0 +1,233 @@
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use crate::{
+    coqtop::{
+        coqproject::parser::Diagnostic,
+        sexp::{self, Sexp},
+    },
+    files,
+};
+
+/// The file at the root of every dune project.
+pub const DUNE_PROJECT: &str = "dune-project";
+
+/// The files containing the stanzas of each directory.
+pub const DUNE: &str = "dune";
+
+/// The directory where dune builds everything, relative to the root of the project.
+pub const BUILD_DIR: &str = "_build/default";
+
+/// A Coq theory, described by a `coq.theory` stanza.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Theory {
+    /// The logical name of the theory (e.g. `Foo.Bar`).
+    pub name: String,
+    /// The directory containing the `dune` file, relative to the root of the project.
+    pub dir: PathBuf,
+    /// The names of the theories it depends on.
+    pub theories: Vec<String>,
+}
+
+/// A dune project, along with all the Coq theories it defines.
+#[derive(Clone, Debug)]
+pub struct DuneProject {
+    /// The directory containing the `dune-project` file.
+    pub root: PathBuf,
+    /// All the theories of the project.
+    pub theories: Vec<Theory>,
+    /// All the problems found in `dune` files.
+    pub diagnostics: Vec<(PathBuf, Diagnostic)>,
+    /// The `dune-project` file and all the `dune` files which have been read.
+    pub sources: Vec<PathBuf>,
+    /// The edited file, relative to the root of the project.
+    file: PathBuf,
+}
+
+/// Looks for a [`DUNE_PROJECT`] file in the directory of the given file, or in any of its ancestors.
+pub fn find_from(file: &str) -> io::Result<Option<PathBuf>> {
+    files::find_in_ancestors(file, DUNE_PROJECT)
+}
+
+/// The edited file, relative to the root of the project (if it is inside it).
+fn relative_to(root: &Path, file: &str) -> io::Result<PathBuf> {
+    let file = Path::new(file).canonicalize()?;
+    Ok(file.strip_prefix(root).unwrap_or(&file).to_path_buf())
+}
+
+impl DuneProject {
+    /// Reads all the `dune` files of the project whose [`DUNE_PROJECT`] is given, for the given edited file.
+    pub fn read<P: AsRef<Path>>(dune_project: P, file: &str) -> io::Result<Self> {
+        let root = dune_project
+            .as_ref()
+            .parent()
+            .ok_or(io::ErrorKind::NotFound)?
+            .to_path_buf();
+        let mut project = Self {
+            file: relative_to(&root, file)?,
+            root,
+            theories: vec![],
+            diagnostics: vec![],
+            sources: vec![dune_project.as_ref().to_path_buf()],
+        };
+        project.read_dir(&PathBuf::new())?;
+        Ok(project)
+    }
+
+    /// The same project, for another edited file.
+    pub fn with_file(&self, file: &str) -> io::Result<Self> {
+        Ok(Self {
+            file: relative_to(&self.root, file)?,
+            ..self.clone()
+        })
+    }
+
+    /// Reads the `dune` file of a directory (relative to the root of the project), and those of all its
+    /// subdirectories.
+    fn read_dir(&mut self, dir: &Path) -> io::Result<()> {
+        let path = self.root.join(dir);
+
+        let dune = path.join(DUNE);
+        if dune.is_file() {
+            let content = fs::read_to_string(&dune)?;
+            self.sources.push(dune.clone());
+            self.add_stanzas(dir, &content)
+                .unwrap_or_else(|diagnostic| self.diagnostics.push((dune, diagnostic)));
+        }
+
+        let mut entries = fs::read_dir(&path)?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_ok_and(|ty| ty.is_dir()))
+            .map(|entry| entry.file_name())
+            // NOTE: just like dune, ignore `_build`, `_opam`, `.git`, etc.
+            .filter(|name| !name.to_string_lossy().starts_with(['.', '_']))
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        for name in entries {
+            self.read_dir(&dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Adds all the theories found in the content of a `dune` file, found in the given directory.
+    pub fn add_stanzas(&mut self, dir: &Path, content: &str) -> Result<(), Diagnostic> {
+        for stanza in sexp::parse(content)? {
+            let fields = match stanza.field("coq.theory") {
+                Some(fields) => fields,
+                None => continue,
+            };
+
+            let name = fields
+                .iter()
+                .find_map(|field| field.field("name"))
+                .and_then(|name| name.first())
+                .and_then(Sexp::atom);
+            let theories = fields
+                .iter()
+                .find_map(|field| field.field("theories"))
+                .unwrap_or_default()
+                .iter()
+                .filter_map(Sexp::atom)
+                .map(str::to_string)
+                .collect();
+
+            match name {
+                Some(name) => self.theories.push(Theory {
+                    name: name.to_string(),
+                    dir: dir.to_path_buf(),
+                    theories,
+                }),
+                None => {
+                    return Err(Diagnostic {
+                        line: content
+                            .find("coq.theory")
+                            .map_or(1, |offset| content[..offset].matches('\n').count() + 1),
+                        message: "A `coq.theory` stanza must have a name".to_string(),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The theory the edited file belongs to, which is the one defined in its closest ancestor.
+    pub fn theory(&self) -> Option<&Theory> {
+        self.theories
+            .iter()
+            .filter(|theory| self.file.starts_with(&theory.dir))
+            .max_by_key(|theory| theory.dir.components().count())
+    }
+
+    /// The source directories of all the theories, bound to the names of the theories.
+    pub fn mappings(&self) -> Vec<(PathBuf, String)> {
+        self.theories
+            .iter()
+            .map(|theory| (self.root.join(&theory.dir), theory.name.clone()))
+            .collect()
+    }
+
+    /// The flags to give to `coqidetop`, mapping the theory of the edited file (recursively) and all the
+    /// theories it depends on to their build directories.
+    ///
+    /// If the edited file does not belong to any theory, all the theories of the project are mapped.
+    /// Theories which are not part of the project are assumed to be installed.
+    pub fn coqtop_flags(&self) -> Vec<String> {
+        let build_dir = |theory: &Theory| {
+            self.root
+                .join(BUILD_DIR)
+                .join(&theory.dir)
+                .display()
+                .to_string()
+        };
+
+        let current = match self.theory() {
+            Some(theory) => theory,
+            None => {
+                return self
+                    .theories
+                    .iter()
+                    .flat_map(|theory| ["-Q".to_string(), build_dir(theory), theory.name.clone()])
+                    .collect()
+            }
+        };
+
+        let mut flags = vec!["-R".to_string(), build_dir(current), current.name.clone()];
+        let mut seen = vec![current.name.as_str()];
+        let mut queue = current.theories.iter().collect::<VecDeque<_>>();
+        while let Some(name) = queue.pop_front() {
+            if seen.contains(&name.as_str()) {
+                continue;
+            }
+            seen.push(name);
+
+            match self.theories.iter().find(|theory| &theory.name == name) {
+                Some(theory) => {
+                    flags.extend(["-Q".to_string(), build_dir(theory), theory.name.clone()]);
+                    queue.extend(theory.theories.iter());
+                }
+                None => log::debug!("Theory {} is not part of the dune project", name),
+            }
+        }
+        flags
+    }
+
+    /// The command running `dune coq top`, which builds the dependencies of the edited file before running
+    /// the given `coqidetop` with the exact flags dune would use.
+    ///
+    /// Additional flags for `coqidetop` must be given after it.
+    pub fn coq_top(&self, coqtop: &str) -> Command {
+        let mut command = Command::new("dune");
+        command
+            .current_dir(&self.root)
+            .args(["coq", "top", "--toplevel", coqtop])
+            .arg(&self.file)
+            .arg("--");
+        command
+    }
+}
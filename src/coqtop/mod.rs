@@ -1,4 +1,11 @@
+pub mod backend;
 pub mod coqidetop;
 pub mod coqproject;
+pub mod dune;
+pub mod pool;
 pub mod processor;
+pub mod project;
+pub mod sertop;
+pub mod sexp;
+pub mod transport;
 pub mod xml_protocol;
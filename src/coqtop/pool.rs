@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+/// Limits how many [`COQTOP`] processes run at the same time, for all the buffers served by a single daemon.
+///
+/// When all processes are in use, the buffer which has not used its process for the longest time is asked to
+/// stop it, so that another buffer can start its own.
+///
+/// [`COQTOP`]: crate::coqtop::coqidetop::COQTOP
+pub struct Pool {
+    permits: Arc<Semaphore>,
+    /// The buffers currently running a process, along with how to ask them to stop it, from the least
+    /// recently used to the most recently used.
+    holders: Mutex<Vec<(usize, Arc<Notify>)>>,
+}
+
+/// The right for a buffer to run a [`COQTOP`] process, which is given back to the pool once dropped.
+///
+/// [`COQTOP`]: crate::coqtop::coqidetop::COQTOP
+pub struct Lease {
+    pool: Arc<Pool>,
+    id: usize,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Pool {
+    /// Creates a pool running at most `size` processes.
+    pub fn new(size: usize) -> Arc<Self> {
+        Arc::new(Self {
+            permits: Arc::new(Semaphore::new(size)),
+            holders: Mutex::new(vec![]),
+        })
+    }
+
+    /// Waits until the buffer with the given id can run a process.
+    ///
+    /// If none is available, the least recently used buffer is notified through the `release` it was given
+    /// (and is not asked again), and the first process stopped is used.
+    pub async fn acquire(self: &Arc<Self>, id: usize, release: Arc<Notify>) -> Lease {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let evicted = {
+                    let mut holders = self.holders.lock().unwrap();
+                    (!holders.is_empty()).then(|| holders.remove(0))
+                };
+                if let Some((evicted, release)) = evicted {
+                    log::info!("Asking buffer {} to make room for buffer {}", evicted, id);
+                    release.notify_one();
+                }
+
+                self.permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore of the pool is never closed")
+            }
+        };
+
+        self.holders.lock().unwrap().push((id, release));
+        Lease {
+            pool: self.clone(),
+            id,
+            _permit: permit,
+        }
+    }
+
+    /// The buffers currently running a process, from the least recently used to the most recently used.
+    pub fn holders(&self) -> Vec<usize> {
+        self.holders
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+impl Lease {
+    /// Marks the process as the most recently used one.
+    pub fn touch(&self) {
+        let mut holders = self.pool.holders.lock().unwrap();
+        if let Some(index) = holders.iter().position(|(id, _)| *id == self.id) {
+            let holder = holders.remove(index);
+            holders.push(holder);
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool
+            .holders
+            .lock()
+            .unwrap()
+            .retain(|(id, _)| *id != self.id);
+    }
+}
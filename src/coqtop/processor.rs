@@ -4,26 +4,29 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use itertools::{enumerate, partition};
 use tokio::sync::broadcast;
 
 use crate::{
     client::commands::types::{ClientCommand, DisplayCommand},
-    coqtop::xml_protocol::types::{
-        FeedbackContent, MessageType, ProtocolRichPP, ProtocolRichPPPart,
+    config::{Config, MessageKind},
+    coqtop::{
+        backend::{Answer, Failure, Feedback, FeedbackContent, Response},
+        xml_protocol::types::{MessageType, ProtocolRichPP, ProtocolRichPPPart},
     },
+    error::DaemonError,
+    error_state::{ErrorEvent, ErrorState},
     range::Range,
     session::Session,
-    state::{ErrorState, Operation, State},
+    state::{Operation, State},
 };
 
-use super::xml_protocol::types::{ProtocolResult, ProtocolValue};
-
 pub struct CoqIdeTopProcessor {
     /// The global session to communicate with Kakoune.
     _session: Arc<Session>,
     /// The global state of the application.
     state: Arc<RwLock<State>>,
+    /// The configuration of the daemon.
+    config: Arc<Config>,
     /// Internal channel sender to send more commands.
     command_tx: broadcast::Sender<ClientCommand>,
 }
@@ -32,96 +35,119 @@ impl CoqIdeTopProcessor {
     pub fn new(
         session: Arc<Session>,
         state: Arc<RwLock<State>>,
+        config: Arc<Config>,
         command_tx: broadcast::Sender<ClientCommand>,
     ) -> io::Result<Self> {
         Ok(Self {
             _session: session,
             state,
+            config,
             command_tx,
         })
     }
 
     pub async fn process_feedback(
         &mut self,
-        feedback: VecDeque<ProtocolResult>,
+        feedback: VecDeque<Feedback>,
     ) -> io::Result<VecDeque<DisplayCommand>> {
-        use ProtocolValue::*;
-
         let mut commands = VecDeque::new();
 
-        let (last_op, error_state) = {
+        let (last_op, rejection) = {
             let state = self.state.read().unwrap();
-            (state.operations.front().cloned(), state.error_state)
+            (
+                state.operations.tip().cloned(),
+                state.error_state.accepts_feedback().err(),
+            )
         };
 
-        for feedback in feedback {
-            match feedback {
-                ProtocolResult::Good(_) => unreachable!(),
-                ProtocolResult::Fail(_, _, _, _) => unreachable!(),
-                ProtocolResult::Feedback(_, _, StateId(state_id), content) => {
-                    match last_op {
-                        Some(Operation { state_id: id, .. }) if id > state_id => {}
-                        // NOTE: ignore messages which are for previous states
-                        _ => match content {
-                            FeedbackContent::Message(message_type, message) => match message_type {
-                                MessageType::Error => {
-                                    log::error!("@{}: {}", state_id, message.strip());
-                                }
-                                _ if error_state != ErrorState::Ok => {}
-                                MessageType::Notice | MessageType::Info => {
-                                    commands.push_back(DisplayCommand::ColorResult(message, true));
-                                }
-                                MessageType::Debug => {
-                                    log::debug!("@{}: {}", state_id, message.strip())
-                                }
-                                MessageType::Warning => {
-                                    commands.push_back(DisplayCommand::ColorResult(
-                                        message.warning(),
-                                        true,
-                                    ));
-                                }
-                            },
-                            _ if error_state != ErrorState::Ok => {}
-                            FeedbackContent::FileLoaded(Str(name), Str(path)) => {
-                                use ProtocolRichPPPart::*;
-
-                                commands.push_back(DisplayCommand::ColorResult(
-                                    ProtocolRichPP::RichPP(vec![
-                                        Raw("module \"".to_string()),
-                                        Reference(name),
-                                        Raw("\" (".to_string()),
-                                        Path(path),
-                                        Raw(") imported.".to_string()),
-                                    ]),
-                                    true,
+        for Feedback { state_id, content } in feedback {
+            match last_op {
+                Some(Operation { state_id: id, .. }) if id > state_id => {}
+                // NOTE: ignore messages which are for previous states
+                _ => match content {
+                    FeedbackContent::Message(message_type, message) => match message_type {
+                        MessageType::Error => {
+                            log::error!("@{}: {}", state_id, message.strip());
+                        }
+                        _ if rejection.is_some() => log::debug!(
+                            "Ignoring message on state ID {}: {}",
+                            state_id,
+                            rejection.unwrap_or_default()
+                        ),
+                        MessageType::Notice | MessageType::Info => {
+                            let kind = match message_type {
+                                MessageType::Notice => MessageKind::Notice,
+                                _ => MessageKind::Info,
+                            };
+
+                            if let Some(Operation {
+                                range,
+                                is_query: true,
+                                ..
+                            }) = self.find_operation(state_id)
+                            {
+                                commands.push_back(DisplayCommand::AddInlineResult(
+                                    range,
+                                    message.clone(),
                                 ));
                             }
-                            FeedbackContent::AddedAxiom => {
-                                let state_range = self.find_range(state_id);
-                                match state_range {
-                                    Some(range) => {
-                                        commands.push_back(DisplayCommand::AddAxiom(range))
-                                    }
-                                    None => {}
-                                }
-                            }
-                            FeedbackContent::Processed => {
-                                log::debug!(
-                                    "Currently known states: {:?}",
-                                    self.state.read().unwrap().operations
-                                );
-
-                                if let Some(range) = self.find_range(state_id) {
-                                    commands.push_back(DisplayCommand::AddToProcessed(range));
-                                }
-                            }
-                            _ => {
-                                log::debug!("Received feedback object @{}: {:?}", state_id, content)
-                            }
-                        },
+
+                            self.record_message(state_id, message.clone());
+                            self.show(kind, message, &mut commands);
+                        }
+                        MessageType::Debug => {
+                            log::debug!("@{}: {}", state_id, message.strip())
+                        }
+                        MessageType::Warning => {
+                            let message = message.warning();
+
+                            self.record_message(state_id, message.clone());
+                            self.show(MessageKind::Warning, message, &mut commands);
+                        }
+                    },
+                    _ if rejection.is_some() => log::debug!(
+                        "Ignoring feedback on state ID {}: {}",
+                        state_id,
+                        rejection.unwrap_or_default()
+                    ),
+                    FeedbackContent::FileLoaded(name, path) => {
+                        use ProtocolRichPPPart::*;
+
+                        let message = ProtocolRichPP::RichPP(vec![
+                            Raw("module \"".to_string()),
+                            Reference(name),
+                            Raw("\" (".to_string()),
+                            Path(path),
+                            Raw(") imported.".to_string()),
+                        ]);
+
+                        self.record_message(state_id, message.clone());
+                        self.show(MessageKind::Imports, message, &mut commands);
                     }
-                }
-                ProtocolResult::Feedback(_, _, _, _) => unreachable!(),
+                    FeedbackContent::AddedAxiom => {
+                        let state_range = {
+                            let mut state = self.state.write().unwrap();
+                            state.operations.get_mut(state_id).map(|op| {
+                                op.has_axiom = true;
+                                op.range
+                            })
+                        };
+                        match state_range {
+                            Some(range) => commands.push_back(DisplayCommand::AddAxiom(range)),
+                            None => {}
+                        }
+                    }
+                    FeedbackContent::Processed => {
+                        log::debug!(
+                            "Currently known states: {:?}",
+                            self.state.read().unwrap().operations
+                        );
+
+                        if let Some(range) = self.find_range(state_id) {
+                            commands.push_back(DisplayCommand::AddToProcessed(range));
+                        }
+                    }
+                },
             }
         }
 
@@ -130,23 +156,30 @@ impl CoqIdeTopProcessor {
 
     pub async fn process_response(
         &mut self,
-        response: ProtocolResult,
+        answer: Answer<Response>,
         command: ClientCommand,
     ) -> io::Result<VecDeque<DisplayCommand>> {
-        use ProtocolValue::*;
-
         let mut commands = VecDeque::new();
 
-        let error_state = self.state.read().unwrap().error_state;
+        let (error_state, rejection) = {
+            let state = self.state.read().unwrap();
+            (
+                state.error_state.state(),
+                state.error_state.accepts_response(&command).err(),
+            )
+        };
 
-        match response {
-            ProtocolResult::Good(value) => match (value, command) {
-                (StateId(state_id), ClientCommand::Init) => {
+        match answer {
+            Ok(response) => match (response, command) {
+                (Response::Initialized(state_id), ClientCommand::Init) => {
                     {
                         let mut state = self.state.write().unwrap();
-                        state.operations.push_front(Operation {
+                        state.operations.push(Operation {
                             state_id,
                             range: Range::default(),
+                            is_query: false,
+                            has_axiom: false,
+                            code: String::new(),
                         });
                     }
 
@@ -156,9 +189,10 @@ impl CoqIdeTopProcessor {
                 (_, ClientCommand::Previous) => {
                     let old_op = {
                         let mut state = self.state.write().unwrap();
-                        let old_op = state.operations.pop_front();
+                        let old_op = state.operations.pop();
+                        state.forget_messages();
                         state.last_error_range = None;
-                        state.error_state = ErrorState::Ok;
+                        state.error_state.transition(ErrorEvent::Cleared);
                         old_op
                     };
                     match old_op {
@@ -166,6 +200,7 @@ impl CoqIdeTopProcessor {
                             commands.push_back(DisplayCommand::RemoveProcessed(range));
                             commands.push_back(DisplayCommand::RemoveToBeProcessed(range));
                             commands.push_back(DisplayCommand::RemoveAxiom(range));
+                            commands.push_back(DisplayCommand::RemoveInlineResult(range));
                         }
                         _ => {}
                     }
@@ -177,19 +212,9 @@ impl CoqIdeTopProcessor {
                 (_, ClientCommand::BackTo(Operation { state_id, .. })) => {
                     let to_remove = {
                         let mut state = self.state.write().unwrap();
-                        let mut ops_to_remove = Vec::new();
-
-                        loop {
-                            if let Some(op) = state.operations.front() {
-                                if op.state_id == state_id {
-                                    break;
-                                } else {
-                                    ops_to_remove.push(state.operations.pop_front().unwrap());
-                                }
-                            } else {
-                                break;
-                            }
-                        }
+                        let ops_to_remove = state.operations.truncate_after(state_id);
+
+                        state.forget_messages();
 
                         if error_state == ErrorState::Ok {
                             state.last_error_range = None;
@@ -212,24 +237,26 @@ impl CoqIdeTopProcessor {
                         commands.push_back(DisplayCommand::RemoveProcessed(op.range));
                         commands.push_back(DisplayCommand::RemoveToBeProcessed(op.range));
                         commands.push_back(DisplayCommand::RemoveAxiom(op.range));
+                        commands.push_back(DisplayCommand::RemoveInlineResult(op.range));
                     }
+                    // Kakoune may have moved the ranges around if the buffer has been edited,
+                    // in which case the above commands may have had no effect.
+                    commands.push_back(DisplayCommand::ResetRanges);
                 }
-                (_, _) if error_state != ErrorState::Ok => {}
-                (
-                    Pair(box StateId(state_id), box Pair(box union, _)),
-                    ClientCommand::Next(append, range, _),
-                ) => {
-                    let new_state_id = match union {
-                        Inl(box Unit) => state_id,
-                        Inr(box StateId(state_id)) => state_id,
-                        _ => unreachable!(),
-                    };
-
+                (_, command) if rejection.is_some() => log::debug!(
+                    "Ignoring response to {:?}: {}",
+                    command,
+                    rejection.unwrap_or_default()
+                ),
+                (Response::Added(new_state_id), ClientCommand::Next(append, range, code)) => {
                     {
                         let mut state = self.state.write().unwrap();
-                        state.operations.push_front(Operation {
+                        state.operations.push(Operation {
                             state_id: new_state_id,
                             range,
+                            is_query: is_query(&code),
+                            has_axiom: false,
+                            code,
                         });
                     }
 
@@ -240,47 +267,51 @@ impl CoqIdeTopProcessor {
                     commands.push_back(DisplayCommand::RefreshErrorRange(None, false));
                     // commands.push_back(DisplayCommand::AddToProcessed(range));
                 }
-                (Optional(None), ClientCommand::ShowGoals(_)) => {
-                    commands.push_back(DisplayCommand::OutputGoals(vec![], vec![], vec![], vec![]));
+                (Response::Goals(goals), ClientCommand::ShowGoals(_)) => {
+                    commands.push_back(DisplayCommand::OutputGoals(goals.unwrap_or_default()));
                 }
-                (Optional(Some(box Goals(fg, bg, sg, gg))), ClientCommand::ShowGoals(_)) => {
-                    commands.push_back(DisplayCommand::OutputGoals(fg, bg, gg, sg));
-                }
-                (Status(box List(path), box Optional(proof), _, _), ClientCommand::Status) => {
-                    let path = path
-                        .into_iter()
-                        .map(|v| match v {
-                            Str(str) => str,
-                            _ => panic!(),
-                        })
-                        .collect::<Vec<_>>();
-
-                    let proof_name = match proof {
-                        Some(box Str(proof_name)) => proof_name,
-                        None => "".to_string(),
-                        _ => "?".to_string(),
-                    };
-
-                    commands.push_back(DisplayCommand::ShowStatus(path.join("."), proof_name));
+                (Response::Status(status), ClientCommand::Status) => {
+                    commands.push_back(DisplayCommand::ShowStatus(
+                        status.path.join("."),
+                        status.proof.unwrap_or_default(),
+                    ));
                 }
                 (r, _) => {
                     log::warn!("Unhandled response {:?}", r);
                 }
             },
-            ProtocolResult::Fail(_, _, StateId(safe_state_id), message) => match command {
-                ClientCommand::Init => todo!(),
-                ClientCommand::Query(_) => todo!(),
-                ClientCommand::Next(_, range, _) | ClientCommand::ShowGoals(range) => {
-                    if safe_state_id > 0 {
+            Err(Failure {
+                location,
+                safe_state_id,
+                message,
+            }) => match command {
+                ClientCommand::Next(_, range, code) => {
+                    if let Some(safe_state_id) = safe_state_id {
                         self.discard_states_until(safe_state_id, &mut commands)
                             .await?;
                     }
                     self.handle_error(Some(range), message, false, &mut commands)
                         .await?;
+
+                    // Only highlight the part of the statement where the error is.
+                    if let Some((start, stop)) = location {
+                        let location = range.sub_range(&code, start, stop);
+                        commands.push_back(DisplayCommand::RefreshErrorRange(Some(location), true));
+                    }
                 }
-                ClientCommand::BackTo(Operation { range, state_id }) => {
-                    if state_id != safe_state_id {
-                        if safe_state_id > 0 {
+                ClientCommand::ShowGoals(range) => {
+                    if let Some(safe_state_id) = safe_state_id {
+                        self.discard_states_until(safe_state_id, &mut commands)
+                            .await?;
+                    }
+                    self.handle_error(Some(range), message, false, &mut commands)
+                        .await?;
+                }
+                ClientCommand::BackTo(Operation {
+                    range, state_id, ..
+                }) => {
+                    if Some(state_id) != safe_state_id {
+                        if let Some(safe_state_id) = safe_state_id {
                             self.discard_states_until(safe_state_id, &mut commands)
                                 .await?;
                         }
@@ -289,7 +320,7 @@ impl CoqIdeTopProcessor {
                     }
                 }
                 _ => {
-                    if safe_state_id > 0 {
+                    if let Some(safe_state_id) = safe_state_id {
                         self.discard_states_until(safe_state_id, &mut commands)
                             .await?;
                     }
@@ -297,8 +328,30 @@ impl CoqIdeTopProcessor {
                         .await?;
                 }
             },
-            ProtocolResult::Fail(_, _, _, _) => unreachable!(),
-            ProtocolResult::Feedback(_, _, _, _) => unreachable!(),
+        }
+
+        Ok(commands)
+    }
+
+    /// Handles an error which happened while asking something to `coqidetop` or processing its response.
+    ///
+    /// If a statement was being processed, `coqidetop` is brought back to the last known state (as for any
+    /// other error on this statement), because it may have processed it nonetheless.
+    pub async fn process_error(
+        &mut self,
+        err: DaemonError,
+        command: ClientCommand,
+    ) -> io::Result<VecDeque<DisplayCommand>> {
+        let mut commands = VecDeque::new();
+
+        match command {
+            ClientCommand::Next(_, range, _) => {
+                log::error!("{}", err);
+
+                self.handle_error(Some(range), err.message(), false, &mut commands)
+                    .await?;
+            }
+            _ => self.report(err, &mut commands),
         }
 
         Ok(commands)
@@ -315,10 +368,10 @@ impl CoqIdeTopProcessor {
     ) -> io::Result<()> {
         let safe_state = {
             let mut state = self.state.write().unwrap();
-            state.error_state = ErrorState::Error;
+            state.error_state.transition(ErrorEvent::Failed);
             state.last_error_range = error_range.clone();
 
-            state.operations.front().cloned()
+            state.operations.tip().cloned()
         };
 
         log::debug!("Handling error with range begin {:?}", error_range);
@@ -337,6 +390,7 @@ impl CoqIdeTopProcessor {
             commands.push_back(DisplayCommand::RemoveToBeProcessed(range));
             commands.push_back(DisplayCommand::RemoveProcessed(range));
             commands.push_back(DisplayCommand::RemoveAxiom(range));
+            commands.push_back(DisplayCommand::RemoveInlineResult(range));
             commands.push_back(DisplayCommand::RefreshErrorRange(error_range, true));
         }
 
@@ -348,47 +402,89 @@ impl CoqIdeTopProcessor {
         state_id: i64,
         commands: &mut VecDeque<DisplayCommand>,
     ) -> io::Result<()> {
-        let mut operations = {
+        let operations = {
             let mut state = self.state.write().unwrap();
-            let operations = state.operations.clone();
-
-            state.operations.retain(|op| op.state_id <= state_id);
+            let operations = state.operations.truncate_after(state_id);
+            state.forget_messages();
 
             operations
         };
 
         log::debug!("Rewinding to state ID {}", state_id);
 
-        let split_index = partition(&mut operations, |op: &Operation| op.state_id > state_id);
-        for (i, op) in enumerate(operations) {
-            if i >= split_index {
-                break;
-            }
-
+        for op in operations {
             log::debug!("Removing operation on state ID {}", op.state_id);
 
             commands.push_back(DisplayCommand::RemoveProcessed(op.range));
             commands.push_back(DisplayCommand::RemoveToBeProcessed(op.range));
             commands.push_back(DisplayCommand::RemoveAxiom(op.range));
+            commands.push_back(DisplayCommand::RemoveInlineResult(op.range));
         }
 
         Ok(())
     }
 
-    fn find_range(&mut self, state_id: i64) -> Option<Range> {
-        let state = self.state.read().unwrap();
-        let len = state.operations.len();
-        let mut i = 0usize;
-
-        while i < len {
-            if let Some(op) = state.operations.get(i) {
-                if op.state_id == state_id {
-                    return Some(op.range);
-                }
-            }
-            i += 1;
+    /// Logs an error and shows it in the result buffer.
+    fn report(&self, err: DaemonError, commands: &mut VecDeque<DisplayCommand>) {
+        log::error!("{}", err);
+
+        commands.push_back(DisplayCommand::ColorResult(err.message(), false));
+    }
+
+    /// Shows a message in the result buffer, unless messages of this kind are hidden in the configuration.
+    fn show(
+        &self,
+        kind: MessageKind,
+        message: ProtocolRichPP,
+        commands: &mut VecDeque<DisplayCommand>,
+    ) {
+        if self.config.shows(kind) {
+            commands.push_back(DisplayCommand::ColorResult(message, true));
+        } else {
+            log::debug!("Hiding {} message: {}", kind, message.strip());
         }
+    }
+
+    fn record_message(&mut self, state_id: i64, message: ProtocolRichPP) {
+        self.state
+            .write()
+            .unwrap()
+            .record_message(state_id, message);
+    }
 
-        None
+    fn find_operation(&mut self, state_id: i64) -> Option<Operation> {
+        self.state.read().unwrap().operations.get(state_id).cloned()
     }
+
+    fn find_range(&mut self, state_id: i64) -> Option<Range> {
+        self.state
+            .read()
+            .unwrap()
+            .operations
+            .get(state_id)
+            .map(|op| op.range)
+    }
+}
+
+/// The Coq commands whose results are shown inline, next to the statement.
+const QUERY_COMMANDS: [&str; 4] = ["Check", "Compute", "Search", "Print"];
+
+/// Checks whether a piece of Coq code is a query (see [`QUERY_COMMANDS`]), ignoring
+/// leading whitespaces and comments.
+///
+/// Only the whole first word is compared, so that e.g. `Printing` is not mistaken for `Print`.
+pub fn is_query(code: &str) -> bool {
+    let mut code = code.trim_start();
+    while let Some(rest) = code.strip_prefix("(*") {
+        code = match rest.find("*)") {
+            Some(end) => rest[end + 2..].trim_start(),
+            None => return false,
+        };
+    }
+
+    let command = code
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or("");
+    QUERY_COMMANDS.contains(&command)
 }
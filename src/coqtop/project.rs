@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    config::Config,
+    coqtop::xml_protocol::types::{ProtocolRichPP, ProtocolRichPPPart},
+};
+
+use super::{
+    coqproject::{self, parser::Diagnostic, CoqProject, COQPROJECT},
+    dune::{self, DuneProject, DUNE_PROJECT},
+};
+
+/// How often the files of a project are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the load paths of the edited file come from.
+#[derive(Clone, Debug)]
+pub enum Project {
+    /// A `_CoqProject` file.
+    CoqProject(CoqProject),
+    /// A dune project with `coq.theory` stanzas.
+    Dune(DuneProject),
+}
+
+impl Project {
+    /// Reads the project of the edited file (see [`Location::find`]).
+    ///
+    /// Problems are only logged, as the edited file can still be processed without any project.
+    pub async fn load(file: &str, config: &Config) -> Option<Self> {
+        Self::read(&Location::find(file, config)?, file)
+    }
+
+    /// Reads the project at the given location, for the given edited file.
+    fn read(location: &Location, file: &str) -> Option<Self> {
+        let project = match location {
+            Location::CoqProject(path) => {
+                tokio::task::block_in_place(|| CoqProject::read(path)).map(Self::CoqProject)
+            }
+            Location::Dune(path) => {
+                tokio::task::block_in_place(|| DuneProject::read(path, file)).map(Self::Dune)
+            }
+        };
+
+        match project {
+            Ok(project) => Some(project.logged()),
+            Err(err) => {
+                log::warn!("Could not read {}: {}", location.path().display(), err);
+                None
+            }
+        }
+    }
+
+    fn logged(self) -> Self {
+        for (path, diagnostic) in self.diagnostics() {
+            log::warn!("{}, {}", path.display(), diagnostic);
+        }
+        self
+    }
+
+    /// The file describing the project.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            Project::CoqProject(project) => project.path.clone(),
+            Project::Dune(project) => project.root.join(DUNE_PROJECT),
+        }
+    }
+
+    /// All the problems found when reading the project.
+    pub fn diagnostics(&self) -> &[(PathBuf, Diagnostic)] {
+        match self {
+            Project::CoqProject(project) => &project.diagnostics,
+            Project::Dune(project) => &project.diagnostics,
+        }
+    }
+
+    /// All the files the project has been read from.
+    pub fn sources(&self) -> &[PathBuf] {
+        match self {
+            Project::CoqProject(project) => &project.sources,
+            Project::Dune(project) => &project.sources,
+        }
+    }
+
+    /// Watches all the files the project has been read from in the background, until one of them is
+    /// modified (or removed).
+    ///
+    /// The path of the first file modified is sent only once.
+    /// Watching stops as soon as the receiver is dropped.
+    pub fn watch(&self) -> oneshot::Receiver<PathBuf> {
+        let (mut tx, rx) = oneshot::channel();
+        let sources = self.sources().to_vec();
+
+        tokio::spawn(async move {
+            let initial = modification_times(&sources);
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+            let changed = loop {
+                tokio::select! {
+                    _ = tx.closed() => return,
+                    _ = interval.tick() => {
+                        let changed = sources
+                            .iter()
+                            .zip(&initial)
+                            .find(|(path, time)| modification_time(path) != **time);
+                        if let Some((path, _)) = changed {
+                            break path.clone();
+                        }
+                    }
+                }
+            };
+
+            log::debug!("{} has been modified", changed.display());
+            let _ = tx.send(changed);
+        });
+
+        rx
+    }
+
+    /// The flags to give to `coqidetop`.
+    pub fn coqtop_flags(&self) -> Vec<String> {
+        match self {
+            Project::CoqProject(project) => project.coqtop_flags(),
+            Project::Dune(project) => project.coqtop_flags(),
+        }
+    }
+
+    /// The directories bound to logical paths in the project.
+    pub fn mappings(&self) -> Vec<(PathBuf, String)> {
+        match self {
+            Project::CoqProject(project) => project.mappings(),
+            Project::Dune(project) => project.mappings(),
+        }
+    }
+
+    /// The logical name of the module defined by the given file, as the build of the project would name it.
+    ///
+    /// When several directories contain the file, the deepest one is used (just like Coq does).
+    pub fn module_name(&self, file: &str) -> Option<String> {
+        let file = normalize(Path::new(file));
+        let (logical, relative) = self
+            .mappings()
+            .into_iter()
+            .filter_map(|(physical, logical)| {
+                let relative = file.strip_prefix(normalize(&physical)).ok()?;
+                Some((logical, relative.with_extension("")))
+            })
+            .min_by_key(|(_, relative)| relative.components().count())?;
+
+        let mut name = logical.split('.').map(str::to_string).collect::<Vec<_>>();
+        name.retain(|part| !part.is_empty());
+        for component in relative.components() {
+            name.push(component.as_os_str().to_str()?.to_string());
+        }
+
+        name.iter()
+            .all(|part| is_ident(part))
+            .then(|| name.join("."))
+    }
+
+    /// The message to show in the result buffer if problems were found, mentioning their line numbers.
+    pub fn report(&self) -> Option<ProtocolRichPP> {
+        use ProtocolRichPPPart::*;
+
+        if self.diagnostics().is_empty() {
+            return None;
+        }
+
+        let mut parts = vec![Warning(format!(
+            "Problems were found in {}:",
+            self.path().display()
+        ))];
+        for (path, diagnostic) in self.diagnostics() {
+            parts.push(Raw("\n".to_string()));
+            parts.push(Path(path.display().to_string()));
+            parts.push(Warning(format!(", {}", diagnostic)));
+        }
+        Some(ProtocolRichPP::RichPP(parts))
+    }
+}
+
+/// Where the project of an edited file is described.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    /// A `_CoqProject` file (or any file in the same format given in the configuration).
+    CoqProject(PathBuf),
+    /// The `dune-project` file at the root of a dune project.
+    Dune(PathBuf),
+}
+
+impl Location {
+    /// Finds the project file given in the configuration, or else the closest [`COQPROJECT`] to the edited
+    /// file, or else the closest [`DUNE_PROJECT`].
+    pub fn find(file: &str, config: &Config) -> Option<Self> {
+        if config.no_project_file {
+            return None;
+        }
+        if let Some(path) = &config.project_file {
+            return Some(Self::CoqProject(path.clone()));
+        }
+
+        match coqproject::find_from(file) {
+            Ok(Some(path)) => return Some(Self::CoqProject(path)),
+            Ok(None) => {}
+            Err(err) => log::warn!("Could not look for a {} file: {}", COQPROJECT, err),
+        }
+        match dune::find_from(file) {
+            Ok(Some(path)) => return Some(Self::Dune(path)),
+            Ok(None) => {}
+            Err(err) => log::warn!("Could not look for a {} file: {}", DUNE_PROJECT, err),
+        }
+
+        log::warn!(
+            "No {} nor {} file found in parent directories...",
+            COQPROJECT,
+            DUNE_PROJECT
+        );
+        None
+    }
+
+    /// The file describing the project.
+    pub fn path(&self) -> &Path {
+        match self {
+            Location::CoqProject(path) | Location::Dune(path) => path,
+        }
+    }
+}
+
+/// When each file of a project was last modified, if it still exists.
+type ModificationTimes = Vec<Option<SystemTime>>;
+
+/// All the projects read by a daemon serving several buffers, so that buffers of the same project do not read
+/// it again.
+///
+/// A project is read again once any of its files has been modified.
+#[derive(Debug, Default)]
+pub struct ProjectCache {
+    projects: Mutex<HashMap<Location, (Project, ModificationTimes)>>,
+}
+
+impl ProjectCache {
+    /// Reads the project of the edited file (see [`Location::find`]), unless it has already been read.
+    pub async fn load(&self, file: &str, config: &Config) -> Option<Project> {
+        let location = Location::find(file, config)?;
+
+        let cached = self
+            .projects
+            .lock()
+            .unwrap()
+            .get(&location)
+            .filter(|(project, times)| modification_times(project.sources()) == *times)
+            .map(|(project, _)| project.clone());
+
+        match cached {
+            // NOTE: the flags of dune projects depend on the theory the edited file belongs to
+            Some(Project::Dune(project)) => match project.with_file(file) {
+                Ok(project) => return Some(Project::Dune(project)),
+                Err(err) => log::warn!("Could not reuse dune project: {}", err),
+            },
+            Some(project) => {
+                log::debug!("Reusing project {}", location.path().display());
+                return Some(project);
+            }
+            None => {}
+        }
+
+        let project = Project::read(&location, file)?;
+        let times = modification_times(project.sources());
+        self.projects
+            .lock()
+            .unwrap()
+            .insert(location, (project.clone(), times));
+        Some(project)
+    }
+}
+
+/// When the given file was last modified, if it still exists.
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn modification_times(paths: &[PathBuf]) -> ModificationTimes {
+    paths.iter().map(|path| modification_time(path)).collect()
+}
+
+/// A valid module name for a buffer which is not a file on disk (e.g. `*scratch*`), made of the characters
+/// of its name which can be part of an identifier.
+pub fn synthetic_module_name(buffer: &str) -> String {
+    let name = Path::new(buffer)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if is_ident_char(c) { c } else { '_' })
+        .collect::<String>();
+    let name = name.trim_matches('_');
+
+    if name.starts_with(|c: char| c.is_alphabetic()) {
+        name.to_string()
+    } else {
+        format!("Scratch{}", name)
+    }
+}
+
+/// Is this a valid Coq identifier?
+fn is_ident(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_') && name.chars().all(is_ident_char)
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '\''
+}
+
+/// Makes a path absolute and without symbolic links if it exists, or else only removes `.` components.
+fn normalize(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| {
+        path.components()
+            .filter(|component| *component != Component::CurDir)
+            .collect()
+    })
+}
@@ -0,0 +1,536 @@
+use std::{collections::VecDeque, io, path::Path, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::Command,
+};
+
+use crate::{
+    config::Config,
+    coqtop::{
+        backend::{
+            interruption, Answer, Failure, Feedback, FeedbackContent, Goal, Goals, Interrupt,
+            ProverBackend, Status,
+        },
+        coqidetop::QUIT_TIMEOUT,
+        project::Project,
+        sexp::{parse, Sexp},
+        transport::{Reader, Transport, Writer},
+        xml_protocol::types::{MessageType, ProtocolRichPP, ProtocolRichPPPart},
+    },
+    session::{edited_file, Session},
+};
+
+/// The name of the SerAPI process.
+pub const SERTOP: &str = "sertop";
+
+/// The state [`SERTOP`] starts in, before any statement is added.
+const INITIAL_STATE: i64 = 1;
+
+/// A [`SERTOP`] process, talked to with the S-expressions of SerAPI on its standard input and output.
+///
+/// SerAPI separates parsing statements (`Add`) from processing them (`Exec`), and only answers once all the
+/// answers to a command have been sent (`Completed`).
+/// Commands are tagged, so that the answers to an interrupted command are not mistaken for those of the next one.
+pub struct SerTop {
+    /// Where commands are sent.
+    writer: Writer,
+    /// Where answers and feedback are received, one S-expression per line.
+    lines: Lines<BufReader<Reader>>,
+    /// The underlying process.
+    transport: Transport,
+    /// How long to wait for a command to be completed before interrupting [`SERTOP`].
+    timeout: Option<Duration>,
+    /// What interrupts the statement being processed.
+    interrupt: Interrupt,
+    /// The feedback received while waiting for the last response.
+    feedback: VecDeque<Feedback>,
+    /// All the states added after the initial one, in order.
+    states: Vec<i64>,
+    /// The tag of the next command.
+    next_tag: u64,
+}
+
+impl SerTop {
+    /// Starts [`SERTOP`] for the edited file.
+    ///
+    /// The load paths of the project (if any) are given in the syntax of SerAPI, followed by the additional flags
+    /// given in the configuration, which must be understood by [`SERTOP`].
+    pub async fn spawn(
+        session: Arc<Session>,
+        config: &Config,
+        project: Option<&Project>,
+        interrupt: Interrupt,
+    ) -> io::Result<Self> {
+        let file = edited_file(session.clone());
+        if config.tcp || config.attach.is_some() {
+            log::warn!(
+                "{} is only talked to on its standard input and output",
+                SERTOP
+            );
+        }
+
+        let mut flags = vec![];
+        if Path::new(&file).is_file() {
+            flags.push(format!("--topfile={}", file));
+        }
+        if let Some(project) = project {
+            flags.append(&mut serapi_flags(project.coqtop_flags()));
+        }
+        flags.extend(config.coq_flags.iter().cloned());
+
+        let (transport, reader, writer) = Transport::pipes(Command::new(&config.coqtop), flags)?;
+        match transport.id() {
+            Some(id) => log::info!("{} (process {}) is up and running!", config.coqtop, id),
+            None => log::info!("{} is up and running!", config.coqtop),
+        }
+
+        Ok(Self {
+            writer,
+            lines: BufReader::new(reader).lines(),
+            transport,
+            timeout: config.timeout,
+            interrupt,
+            feedback: VecDeque::new(),
+            states: vec![],
+            next_tag: 0,
+        })
+    }
+
+    /// Sends a command to [`SERTOP`] and waits until it is completed, giving back all the answers received along
+    /// the way (except the acknowledgement).
+    async fn run(&mut self, command: Sexp) -> io::Result<Vec<Sexp>> {
+        self.next_tag += 1;
+        let tag = self.next_tag.to_string();
+        let encoded = format!("({} {})", tag, command);
+        log::debug!("Sending command `{}` to {} process", encoded, SERTOP);
+
+        self.writer
+            .write_all(format!("{}\n", encoded).as_bytes())
+            .await?;
+
+        let interrupted = interruption(&self.interrupt, self.timeout)?;
+        tokio::pin!(interrupted);
+
+        let mut answers = vec![];
+        loop {
+            let line = tokio::select! {
+                message = &mut interrupted => {
+                    self.transport.interrupt().await;
+
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, message));
+                }
+                line = self.lines.next_line() => match line? {
+                    Some(line) => line,
+                    None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("{} has quit", SERTOP))),
+                },
+            };
+
+            let sexps = match parse(&line) {
+                Ok(sexps) => sexps,
+                Err(err) => {
+                    log::error!("Ignoring malformed output {:?}: {}", line, err.message);
+                    continue;
+                }
+            };
+            for sexp in sexps {
+                if let Some(feedback) = sexp.field("Feedback") {
+                    // NOTE: feedback is only informative, and a malformed one must not prevent
+                    //       us from receiving the answers
+                    match translate_feedback(feedback) {
+                        Some(feedback) => self.feedback.push_back(feedback),
+                        None => log::debug!("Ignoring feedback {}", sexp),
+                    }
+                    continue;
+                }
+
+                match sexp.field("Answer") {
+                    Some([Sexp::Atom(answered), answer]) if *answered == tag => {
+                        match answer.atom() {
+                            Some("Ack") => {}
+                            Some("Completed") => return Ok(answers),
+                            _ => answers.push(answer.clone()),
+                        }
+                    }
+                    Some(_) => log::debug!("Ignoring answer to a previous command: {}", sexp),
+                    None => log::warn!("Unexpected output from {}: {}", SERTOP, sexp),
+                }
+            }
+        }
+    }
+
+    /// Adds a statement on top of the given state, and gives back the states it leads to (usually one), or the
+    /// error which prevented it from being parsed.
+    async fn add_statement(&mut self, code: String, state_id: i64) -> io::Result<Answer<Vec<i64>>> {
+        let answers = self
+            .run(Sexp::List(vec![
+                atom("Add"),
+                Sexp::List(vec![Sexp::List(vec![atom("ontop"), atom(state_id)])]),
+                Sexp::Atom(code),
+            ]))
+            .await?;
+        if let Some(failure) = failure(&answers, state_id) {
+            return Ok(Err(failure));
+        }
+
+        let added = answers
+            .iter()
+            .filter_map(|answer| answer.field("Added"))
+            .filter_map(|added| added.first()?.atom()?.parse().ok())
+            .collect::<Vec<_>>();
+        if added.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has not added any state", SERTOP),
+            ));
+        }
+        Ok(Ok(added))
+    }
+
+    /// Processes the given state, which must have been added on top of the given previous one.
+    ///
+    /// Added states which cannot be processed are cancelled, so that the previous state is the current one again.
+    async fn exec(&mut self, added: &[i64], previous: i64) -> io::Result<Answer<()>> {
+        let tip = *added.last().unwrap();
+        let answers = self.run(Sexp::List(vec![atom("Exec"), atom(tip)])).await?;
+
+        match failure(&answers, previous) {
+            Some(failure) => {
+                self.run(cancel_command(added)).await?;
+                Ok(Err(failure))
+            }
+            None => Ok(Ok(())),
+        }
+    }
+
+    /// The state all the statements added so far lead to.
+    fn tip(&self) -> i64 {
+        self.states.last().copied().unwrap_or(INITIAL_STATE)
+    }
+}
+
+impl ProverBackend for SerTop {
+    async fn init(&mut self) -> io::Result<Answer<i64>> {
+        if let Err(failure) = self.cancel(INITIAL_STATE).await? {
+            return Ok(Err(failure));
+        }
+
+        Ok(Ok(INITIAL_STATE))
+    }
+
+    async fn add(&mut self, code: String, state_id: i64) -> io::Result<Answer<i64>> {
+        self.feedback.clear();
+        if state_id != self.tip() {
+            if let Err(failure) = self.cancel(state_id).await? {
+                return Ok(Err(failure));
+            }
+        }
+
+        let added = match self.add_statement(code, state_id).await? {
+            Ok(added) => added,
+            Err(failure) => return Ok(Err(failure)),
+        };
+        self.states.extend(&added);
+        if let Err(failure) = self.exec(&added, state_id).await? {
+            self.states.truncate(self.states.len() - added.len());
+            return Ok(Err(failure));
+        }
+
+        Ok(Ok(*added.last().unwrap()))
+    }
+
+    async fn cancel(&mut self, state_id: i64) -> io::Result<Answer<()>> {
+        self.feedback.clear();
+
+        let index = self.states.partition_point(|id| *id <= state_id);
+        let cancelled = self.states.split_off(index);
+        if !cancelled.is_empty() {
+            let answers = self.run(cancel_command(&cancelled)).await?;
+            if let Some(failure) = failure(&answers, state_id) {
+                return Ok(Err(failure));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    async fn goals(&mut self) -> io::Result<Answer<Option<Goals>>> {
+        self.feedback.clear();
+
+        let tip = self.tip();
+        let answers = self
+            .run(Sexp::List(vec![
+                atom("Query"),
+                Sexp::List(vec![
+                    Sexp::List(vec![atom("sid"), atom(tip)]),
+                    Sexp::List(vec![
+                        atom("pp"),
+                        Sexp::List(vec![Sexp::List(vec![atom("pp_format"), atom("PpStr")])]),
+                    ]),
+                ]),
+                atom("Goals"),
+            ]))
+            .await?;
+        if let Some(failure) = failure(&answers, tip) {
+            return Ok(Err(failure));
+        }
+
+        let goals = answers
+            .iter()
+            .filter_map(|answer| answer.field("ObjList"))
+            .flat_map(|objects| objects.iter().flat_map(list))
+            .filter_map(|object| object.field("CoqString"))
+            .filter_map(|text| text.first()?.atom())
+            .enumerate()
+            .map(|(i, text)| goal(i + 1, text))
+            .collect::<Vec<_>>();
+
+        Ok(Ok((!goals.is_empty()).then(|| Goals {
+            focused: goals,
+            ..Goals::default()
+        })))
+    }
+
+    async fn query(&mut self, query: String, state_id: i64) -> io::Result<Answer<()>> {
+        self.feedback.clear();
+
+        // NOTE: the query is processed on top of the given state, and cancelled right after
+        let added = match self.add_statement(query, state_id).await? {
+            Ok(added) => added,
+            Err(failure) => return Ok(Err(failure)),
+        };
+        if let Err(failure) = self.exec(&added, state_id).await? {
+            return Ok(Err(failure));
+        }
+        self.run(cancel_command(&added)).await?;
+
+        Ok(Ok(()))
+    }
+
+    /// SerAPI does not tell which module and proof the current state is in, so both are left empty.
+    async fn status(&mut self) -> io::Result<Answer<Status>> {
+        self.feedback.clear();
+
+        Ok(Ok(Status::default()))
+    }
+
+    fn feedback(&mut self) -> VecDeque<Feedback> {
+        std::mem::take(&mut self.feedback)
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.transport.is_stopped()
+    }
+
+    /// Asks [`SERTOP`] to quit, and kills it if it has not quit after [`QUIT_TIMEOUT`].
+    async fn quit(mut self) -> io::Result<()> {
+        self.next_tag += 1;
+        let quit = async {
+            self.writer
+                .write_all(format!("({} Quit)\n", self.next_tag).as_bytes())
+                .await?;
+            self.transport.wait().await
+        };
+
+        match tokio::time::timeout(QUIT_TIMEOUT, quit).await {
+            Ok(Ok(status)) => {
+                log::info!("{} has quit ({:?})", SERTOP, status);
+                return Ok(());
+            }
+            Ok(Err(err)) => log::warn!("{} could not quit by itself: {}", SERTOP, err),
+            Err(_) => log::warn!(
+                "{} has not quit after {} seconds",
+                SERTOP,
+                QUIT_TIMEOUT.as_secs()
+            ),
+        }
+
+        log::info!("Killing {}", SERTOP);
+        self.transport.kill().await
+    }
+}
+
+/// Translates `coqtop` flags to those of [`SERTOP`], which binds directories to logical paths with a single
+/// argument (e.g. `-R theories,Foo` instead of `-R theories Foo`).
+pub fn serapi_flags(flags: Vec<String>) -> Vec<String> {
+    let mut translated = vec![];
+    let mut flags = flags.into_iter();
+
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "-R" | "-Q" => match (flags.next(), flags.next()) {
+                (Some(physical), Some(logical)) => {
+                    translated.extend([flag, format!("{},{}", physical, logical)])
+                }
+                _ => log::warn!("Ignoring incomplete flag {}", flag),
+            },
+            // NOTE: the native compiler can only be set when building the project
+            "-native-compiler" => {
+                flags.next();
+            }
+            _ => translated.push(flag),
+        }
+    }
+    translated
+}
+
+/// Translates the content of a `Feedback` S-expression (e.g.
+/// `((doc_id 0) (span_id 2) (route 0) (contents Processed))`), unless it is of no use to the daemon.
+pub fn translate_feedback(feedback: &[Sexp]) -> Option<Feedback> {
+    let fields = record(feedback);
+    let state_id = field(fields, "span_id")?.first()?.atom()?.parse().ok()?;
+
+    let contents = field(fields, "contents")?.first()?;
+    let content = match contents.atom() {
+        Some("Processed") => FeedbackContent::Processed,
+        Some("AddedAxiom") => FeedbackContent::AddedAxiom,
+        Some(_) => return None,
+        None => match (contents.field("Message"), contents.field("FileLoaded")) {
+            (Some(message), _) => {
+                let fields = record(message);
+                let level = match field(fields, "level")?.first()?.atom()? {
+                    "Debug" => MessageType::Debug,
+                    "Info" => MessageType::Info,
+                    "Notice" => MessageType::Notice,
+                    "Warning" => MessageType::Warning,
+                    "Error" => MessageType::Error,
+                    _ => return None,
+                };
+                FeedbackContent::Message(level, text(fields)?)
+            }
+            (_, Some([name, path])) => {
+                FeedbackContent::FileLoaded(name.atom()?.to_string(), path.atom()?.to_string())
+            }
+            _ => return None,
+        },
+    };
+
+    Some(Feedback { state_id, content })
+}
+
+/// The failure corresponding to the first exception among the answers to a command, if any.
+///
+/// The location of the exception is given in bytes from the beginning of the statement, as `coqidetop` does.
+pub fn failure(answers: &[Sexp], safe_state_id: i64) -> Option<Failure> {
+    let exception = answers.iter().find_map(|answer| answer.field("CoqExn"))?;
+    let fields = record(exception);
+
+    // NOTE: the location is optional, i.e. either `()` or `(((fname ToplevelInput) ... (bp 0) (ep 3)))`
+    let location = field(fields, "loc")
+        .and_then(|loc| loc.first())
+        .and_then(|loc| list(loc).first())
+        .map(list)
+        .unwrap_or_default();
+    let offset =
+        |name| field(location, name).and_then(|offset| offset.first()?.atom()?.parse().ok());
+    let message = text(fields).unwrap_or_else(|| {
+        ProtocolRichPP::RichPP(vec![ProtocolRichPPPart::Raw(
+            Sexp::List(exception.to_vec()).to_string(),
+        )])
+    });
+
+    Some(Failure {
+        location: offset("bp").zip(offset("ep")),
+        safe_state_id: Some(safe_state_id),
+        message,
+    })
+}
+
+/// A goal printed by [`SERTOP`], whose hypotheses are above a line of `=` and whose conclusion is below.
+fn goal(index: usize, text: &str) -> Goal {
+    let lines = text.lines().collect::<Vec<_>>();
+    let (hypotheses, conclusion) = match lines
+        .iter()
+        .position(|line| line.trim_start().starts_with("==="))
+    {
+        Some(separator) => (&lines[..separator], &lines[separator + 1..]),
+        None => (&lines[..0], &lines[..]),
+    };
+
+    let richpp = |text: String| ProtocolRichPP::RichPP(vec![ProtocolRichPPPart::Raw(text)]);
+    Goal {
+        name: index.to_string(),
+        hypotheses: hypotheses
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| richpp(line.to_string()))
+            .collect(),
+        conclusion: richpp(
+            conclusion
+                .iter()
+                .map(|line| line.trim())
+                .collect::<Vec<_>>()
+                .join("\n")
+                .trim()
+                .to_string(),
+        ),
+        user_name: None,
+    }
+}
+
+/// The text of a message or an exception, either already printed (`(str "...")`) or made of the strings of
+/// the pretty-printing document (`(pp ...)`).
+fn text(fields: &[Sexp]) -> Option<ProtocolRichPP> {
+    let text = match field(fields, "str").and_then(|text| text.first()?.atom()) {
+        Some(text) => text.to_string(),
+        None => {
+            let mut text = String::new();
+            for pp in field(fields, "pp")? {
+                flatten(pp, &mut text);
+            }
+            text
+        }
+    };
+
+    Some(ProtocolRichPP::RichPP(vec![ProtocolRichPPPart::Raw(text)]))
+}
+
+/// Appends the strings of a pretty-printing document to some text.
+fn flatten(pp: &Sexp, text: &mut String) {
+    match pp {
+        Sexp::Atom(atom) if atom == "Pp_force_newline" => text.push('\n'),
+        Sexp::Atom(_) => {}
+        Sexp::List(items) => match items.as_slice() {
+            [Sexp::Atom(tag), Sexp::Atom(string)] if tag == "Pp_string" => text.push_str(string),
+            [Sexp::Atom(tag), Sexp::Atom(spaces), _] if tag == "Pp_print_break" => {
+                text.push_str(&" ".repeat(spaces.parse().unwrap_or(1)))
+            }
+            items => items.iter().for_each(|item| flatten(item, text)),
+        },
+    }
+}
+
+/// The command cancelling the given states.
+fn cancel_command(states: &[i64]) -> Sexp {
+    Sexp::List(vec![
+        atom("Cancel"),
+        Sexp::List(states.iter().map(atom).collect()),
+    ])
+}
+
+fn atom<T: ToString>(value: T) -> Sexp {
+    Sexp::Atom(value.to_string())
+}
+
+/// The elements of a list, or nothing for an atom.
+fn list(sexp: &Sexp) -> &[Sexp] {
+    match sexp {
+        Sexp::List(items) => items,
+        Sexp::Atom(_) => &[],
+    }
+}
+
+/// The fields of a record, which may be wrapped in a list (e.g. `(Message ((level Info) ...))` instead of
+/// `(Message (level Info) ...)`).
+fn record(sexps: &[Sexp]) -> &[Sexp] {
+    match sexps {
+        [Sexp::List(fields)] if fields.iter().all(|field| matches!(field, Sexp::List(_))) => fields,
+        fields => fields,
+    }
+}
+
+/// The value of a field of a record (e.g. `(level Info)`).
+fn field<'a>(fields: &'a [Sexp], name: &str) -> Option<&'a [Sexp]> {
+    fields.iter().find_map(|sexp| sexp.field(name))
+}
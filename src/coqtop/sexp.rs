@@ -0,0 +1,163 @@
+use std::fmt;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_until, take_while1, take_while_m_n},
+    character::complete::{anychar, char, multispace1, none_of, not_line_ending},
+    combinator::{map, map_res, value},
+    multi::{many0, many0_count},
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
+};
+
+use crate::coqtop::coqproject::parser::Diagnostic;
+
+/// An S-expression, as found in `dune` and `dune-project` files, and as exchanged with `sertop`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sexp {
+    /// An atom or a string.
+    Atom(String),
+    /// A parenthesized list of S-expressions.
+    List(Vec<Sexp>),
+}
+
+/// Parses all the S-expressions of a file (or of some output).
+///
+/// Comments (either `; ...` until the end of the line, or `#| ... |#`) are ignored.
+pub fn parse(text: &str) -> Result<Vec<Sexp>, Diagnostic> {
+    let line_of = |rest: &str| text[..text.len() - rest.len()].matches('\n').count() + 1;
+
+    match many0(preceded(blanks, sexp))(text) {
+        // NOTE: `many0` stops at the first S-expression which cannot be parsed, which is what remains
+        Ok((rest, sexps)) => {
+            let rest = blanks(rest).map_or(rest, |(rest, _)| rest);
+            if rest.is_empty() {
+                Ok(sexps)
+            } else {
+                Err(Diagnostic {
+                    line: line_of(rest),
+                    message: format!(
+                        "Cannot parse S-expression starting with `{}`",
+                        rest.chars().next().unwrap_or_default()
+                    ),
+                })
+            }
+        }
+        Err(_) => Err(Diagnostic {
+            line: 1,
+            message: "Malformed file".to_string(),
+        }),
+    }
+}
+
+impl Sexp {
+    /// The content of an atom.
+    pub fn atom(&self) -> Option<&str> {
+        match self {
+            Sexp::Atom(atom) => Some(atom),
+            Sexp::List(_) => None,
+        }
+    }
+
+    /// The elements of a list starting with the given atom (e.g. `(name Foo)`), without this atom.
+    pub fn field(&self, name: &str) -> Option<&[Sexp]> {
+        match self {
+            Sexp::List(items) if items.first().and_then(Sexp::atom) == Some(name) => {
+                Some(&items[1..])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Prints an S-expression so that it can be parsed back, quoting atoms when needed.
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sexp::Atom(atom)
+                if !atom.is_empty()
+                    && !atom
+                        .chars()
+                        .any(|c| c.is_whitespace() || "();\"\\".contains(c)) =>
+            {
+                write!(f, "{}", atom)
+            }
+            Sexp::Atom(atom) => {
+                write!(f, "\"")?;
+                for c in atom.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Sexp::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+// --------------------------------------------------------------------------
+
+fn blanks(input: &str) -> IResult<&str, usize> {
+    many0_count(alt((
+        value((), multispace1),
+        value((), pair(char(';'), not_line_ending)),
+        value((), tuple((tag("#|"), take_until("|#"), tag("|#")))),
+    )))(input)
+}
+
+fn sexp(input: &str) -> IResult<&str, Sexp> {
+    alt((
+        map(
+            delimited(
+                char('('),
+                many0(preceded(blanks, sexp)),
+                preceded(blanks, char(')')),
+            ),
+            Sexp::List,
+        ),
+        map(string, Sexp::Atom),
+        map(
+            take_while1(|c: char| !c.is_whitespace() && !"();\"".contains(c)),
+            |atom: &str| Sexp::Atom(atom.to_string()),
+        ),
+    ))(input)
+}
+
+fn string(input: &str) -> IResult<&str, String> {
+    // NOTE: bytes may be escaped as 3 decimal digits (e.g. `\226\136\128` for `∀`), as done by OCaml
+    let decimal = map_res(
+        take_while_m_n(3, 3, |c: char| c.is_ascii_digit()),
+        |digits: &str| digits.parse::<u8>().map(|byte| vec![byte]),
+    );
+    let escaped = preceded(
+        char('\\'),
+        alt((
+            decimal,
+            map(anychar, |c| match c {
+                'n' => vec![b'\n'],
+                't' => vec![b'\t'],
+                c => c.to_string().into_bytes(),
+            }),
+        )),
+    );
+    let unescaped = map(none_of("\\\""), |c| c.to_string().into_bytes());
+
+    map(
+        delimited(char('"'), many0(alt((escaped, unescaped))), char('"')),
+        |bytes| String::from_utf8_lossy(&bytes.concat()).into_owned(),
+    )(input)
+}
@@ -0,0 +1,271 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use async_signals::Signals;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command},
+};
+use tokio_stream::StreamExt;
+
+use super::coqidetop::{COQTOP, SHUTDOWN_SIGNALS};
+
+/// How long a [`COQTOP`] process started by the daemon is given to connect to its channels.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the daemon waits for a [`COQTOP`] started by someone else to connect to its channels.
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The request to quit, as understood on the control channel of [`COQTOP`].
+///
+/// This is the constant constructor `ReqDie` of Coq's `Spawn.req`, serialized with OCaml's `Marshal` module:
+/// a 20 bytes header (magic number, size of the data, number of shared objects and sizes of the value on
+/// 32 and 64 bits architectures) followed by the small integer `0`.
+const REQ_DIE: [u8; 21] = [
+    0x84, 0x95, 0xA6, 0xBE, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x40,
+];
+
+/// The main channel where [`COQTOP`] sends its responses and feedback.
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The main channel where calls are sent to [`COQTOP`].
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// How the daemon is connected to [`COQTOP`] (besides its main channel), along with the process itself when it
+/// has been started by the daemon.
+pub struct Transport {
+    /// The control channel (where requests are sent, and where they are answered), only when the main channel
+    /// is a TCP connection.
+    control: Option<(TcpStream, TcpStream)>,
+    /// The underlying process, unless [`COQTOP`] has been started by someone else.
+    process: Option<Child>,
+    /// Whether [`COQTOP`] has been asked to quit on its control channel.
+    stopped: bool,
+}
+
+impl Transport {
+    /// Starts [`COQTOP`] with the given command, talking to it on its standard input and output.
+    pub fn stdio(command: Command, flags: Vec<String>) -> io::Result<(Self, Reader, Writer)> {
+        let channels = ["-main-channel".to_string(), "stdfds".to_string()];
+        Self::pipes(command, channels.into_iter().chain(flags).collect())
+    }
+
+    /// Starts a prover with the given command and flags, talking to it on its standard input and output.
+    pub fn pipes(mut command: Command, flags: Vec<String>) -> io::Result<(Self, Reader, Writer)> {
+        command.stdin(Stdio::piped()).stdout(Stdio::piped());
+        let mut process = spawn(command, [], flags)?;
+
+        let (reader, writer) = (
+            process.stdout.take().unwrap(),
+            process.stdin.take().unwrap(),
+        );
+        let transport = Self {
+            control: None,
+            process: Some(process),
+            stopped: false,
+        };
+        Ok((transport, Box::new(reader), Box::new(writer)))
+    }
+
+    /// Starts [`COQTOP`] with the given command, and waits until it connects to main and control channels
+    /// listening on the loopback interface.
+    pub async fn tcp(
+        mut command: Command,
+        flags: Vec<String>,
+    ) -> io::Result<(Self, Reader, Writer)> {
+        command.stdin(Stdio::null()).stdout(Stdio::null());
+        let listeners =
+            Listeners::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let mut process = spawn(command, listeners.flags()?, flags)?;
+
+        let (mut transport, reader, writer) = tokio::select! {
+            accepted = tokio::time::timeout(CONNECT_TIMEOUT, listeners.accept()) => match accepted {
+                Ok(transport) => transport?,
+                // NOTE: the process is killed once dropped
+                Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} has not connected to the daemon", COQTOP))),
+            },
+            status = process.wait() => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("{} has quit ({}) before connecting to the daemon", COQTOP, status?)));
+            }
+        };
+        transport.process = Some(process);
+        Ok((transport, reader, writer))
+    }
+
+    /// Waits until a [`COQTOP`] started by someone else (e.g. in a container, or on another machine through
+    /// an SSH tunnel) connects to main and control channels listening on the four ports starting at the given
+    /// address.
+    ///
+    /// The flags [`COQTOP`] must be started with are logged, along with the given flags.
+    pub async fn attach(
+        address: SocketAddr,
+        flags: &[String],
+    ) -> io::Result<(Self, Reader, Writer)> {
+        let listeners = Listeners::bind(address).await?;
+        log::info!(
+            "Waiting for {} to connect, which must be started with `{} {} {}`",
+            COQTOP,
+            COQTOP,
+            listeners.flags()?.join(" "),
+            flags.join(" ")
+        );
+
+        let mut signals = Signals::new(SHUTDOWN_SIGNALS)?;
+        tokio::select! {
+            accepted = tokio::time::timeout(ATTACH_TIMEOUT, listeners.accept()) => match accepted {
+                Ok(transport) => transport,
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} has not connected to {} after {} seconds", COQTOP, address, ATTACH_TIMEOUT.as_secs()),
+                )),
+            },
+            Some(signal) = signals.next() => Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("Received signal {} while waiting for {} to connect", signal, COQTOP),
+            )),
+        }
+    }
+
+    /// The identifier of the underlying process, if it has been started by the daemon.
+    pub fn id(&self) -> Option<u32> {
+        self.process.as_ref().and_then(Child::id)
+    }
+
+    /// Whether [`COQTOP`] has been asked to quit on its control channel, and cannot be used anymore.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Interrupts the processing of the current Coq statement.
+    ///
+    /// Processes started by the daemon are interrupted in the same way as CoqIDE.
+    /// Those started by someone else cannot be sent signals, and the control channel only accepts a request to
+    /// quit, so they are stopped instead (see [`Transport::is_stopped`]).
+    pub async fn interrupt(&mut self) {
+        match self.id() {
+            Some(id) => unsafe {
+                libc::kill(id as i32, libc::SIGINT);
+            },
+            None => {
+                log::warn!("{} cannot be interrupted remotely, stopping it", COQTOP);
+                if let Err(err) = self.stop().await {
+                    log::warn!("Cannot stop {}: {}", COQTOP, err);
+                }
+            }
+        }
+    }
+
+    /// Waits until the underlying process has quit, if it has been started by the daemon.
+    pub async fn wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match &mut self.process {
+            Some(process) => process.wait().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Kills the underlying process, or asks [`COQTOP`] to quit on its control channel if it has not been
+    /// started by the daemon.
+    pub async fn kill(&mut self) -> io::Result<()> {
+        match &mut self.process {
+            Some(process) => process.kill().await,
+            None => self.stop().await,
+        }
+    }
+
+    /// Asks [`COQTOP`] to quit on its control channel.
+    async fn stop(&mut self) -> io::Result<()> {
+        let (control, _) = self.control.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "No control channel to write to")
+        })?;
+
+        self.stopped = true;
+        control.write_all(&REQ_DIE).await
+    }
+}
+
+/// The listeners which [`COQTOP`] connects to, on four consecutive ports.
+struct Listeners {
+    /// Where calls are sent.
+    main_r: TcpListener,
+    /// Where responses are received.
+    main_w: TcpListener,
+    /// Where requests on the control channel are sent.
+    control_r: TcpListener,
+    /// Where [`COQTOP`] answers requests on the control channel, which are never sent any.
+    control_w: TcpListener,
+}
+
+impl Listeners {
+    /// Listens on the given address and the three next ports, or on any four ports if the port is `0`.
+    async fn bind(address: SocketAddr) -> io::Result<Self> {
+        let port = |offset: u16| match address.port() {
+            0 => Ok(address),
+            port => port
+                .checked_add(offset)
+                .map(|port| SocketAddr::new(address.ip(), port))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Port is too high")),
+        };
+
+        Ok(Self {
+            main_r: TcpListener::bind(port(0)?).await?,
+            main_w: TcpListener::bind(port(1)?).await?,
+            control_r: TcpListener::bind(port(2)?).await?,
+            control_w: TcpListener::bind(port(3)?).await?,
+        })
+    }
+
+    /// The flags telling [`COQTOP`] where to connect to, e.g. `-main-channel 127.0.0.1:9000:9001`.
+    ///
+    /// [`COQTOP`] reads from the first port of each channel, and writes to the second one.
+    fn flags(&self) -> io::Result<Vec<String>> {
+        let channel = |r: &TcpListener, w: &TcpListener| -> io::Result<String> {
+            let (r, w) = (r.local_addr()?, w.local_addr()?);
+            Ok(format!("{}:{}:{}", r.ip(), r.port(), w.port()))
+        };
+
+        Ok(vec![
+            "-main-channel".to_string(),
+            channel(&self.main_r, &self.main_w)?,
+            "-control-channel".to_string(),
+            channel(&self.control_r, &self.control_w)?,
+        ])
+    }
+
+    /// Waits until [`COQTOP`] has connected to all the channels.
+    async fn accept(self) -> io::Result<(Transport, Reader, Writer)> {
+        let ((main_r, _), (main_w, _), (control_r, _), (control_w, address)) = tokio::try_join!(
+            self.main_r.accept(),
+            self.main_w.accept(),
+            self.control_r.accept(),
+            self.control_w.accept(),
+        )?;
+        log::info!("{} has connected from {}", COQTOP, address);
+
+        let transport = Transport {
+            // NOTE: nothing is ever read from the control channel, but the connection must stay open
+            control: Some((control_r, control_w)),
+            process: None,
+            stopped: false,
+        };
+        Ok((transport, Box::new(main_w), Box::new(main_r)))
+    }
+}
+
+/// Spawns a new [`COQTOP`] process (using the given command), telling it where its channels are and feeding it
+/// additional flags to take in account.
+fn spawn<I>(mut command: Command, channels: I, flags: Vec<String>) -> io::Result<Child>
+where
+    I: IntoIterator<Item = String>,
+{
+    command
+        .args(channels)
+        .args(&flags)
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+}
@@ -8,12 +8,16 @@ use super::{
         ProtocolValue::{self, *},
     },
 };
+use crate::error::DaemonError;
 use std::io;
 use tokio::io::AsyncRead;
 
+/// Ways in which a message sent by `coqidetop` can be malformed.
+#[derive(Debug)]
 pub enum DecodeError {
     InvalidUnit,
     InvalidList,
+    InvalidUnion,
     InvalidString,
     InvalidInteger,
     InvalidBoolean,
@@ -25,18 +29,24 @@ pub enum DecodeError {
     InvalidStateId,
     InvalidFeedback,
     InvalidFeedbackContent,
+    InvalidMessageLevel,
     InvalidGoals,
     InvalidGoal,
+    /// A number which could not be parsed.
+    InvalidNumber(String),
+    /// A kind of feedback which is not known (yet).
+    UnknownFeedbackContent(String),
 }
 
 use tokio_util::codec::FramedRead;
 use DecodeError::*;
 
-impl std::fmt::Debug for DecodeError {
+impl std::fmt::Display for DecodeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             InvalidUnit => write!(f, "Invalid <unit/> tag"),
             InvalidList => write!(f, "Invalid <list/> tag"),
+            InvalidUnion => write!(f, "Invalid <union/> tag"),
             InvalidString => write!(f, "Invalid <string/> tag"),
             InvalidInteger => write!(f, "Invalid <int/> tag"),
             InvalidBoolean => write!(f, "Invalid <bool/> tag"),
@@ -48,12 +58,21 @@ impl std::fmt::Debug for DecodeError {
             InvalidStateId => write!(f, "Invalid <state_id/> tag"),
             InvalidFeedback => write!(f, "Invalid <feedback/> tag"),
             InvalidFeedbackContent => write!(f, "Invalid <feedback_content/> tag"),
+            InvalidMessageLevel => write!(f, "Invalid <message_level/> tag"),
             InvalidGoals => write!(f, "Invalid <goals/> tag"),
             InvalidGoal => write!(f, "Invalid <goal> tag"),
+            InvalidNumber(number) => write!(f, "Invalid number '{}'", number),
+            UnknownFeedbackContent(kind) => write!(f, "Unknown feedback content type '{}'", kind),
         }
     }
 }
 
+impl From<DecodeError> for io::Error {
+    fn from(err: DecodeError) -> Self {
+        DaemonError::Decode(err).into()
+    }
+}
+
 impl ProtocolValue {
     /// Tries to decode a protocol value from an XML `Element`.
     ///
@@ -76,14 +95,18 @@ impl ProtocolValue {
                     .collect::<Result<Vec<_>, _>>()
                     .map(List)
             }
-            "union" => match xml.attributes.get("val").unwrap().as_str() {
-                "in_l" => Ok(Inl(Box::new(ProtocolValue::decode(
-                    xml.children[0].as_node().cloned().unwrap(),
-                )?))),
-                "in_r" => Ok(Inr(Box::new(ProtocolValue::decode(
-                    xml.children[0].as_node().cloned().unwrap(),
-                )?))),
-                _ => unreachable!(),
+            "union" => match attribute(&xml, "val", || InvalidUnion)?.as_str() {
+                "in_l" => Ok(Inl(Box::new(ProtocolValue::decode(child_node(
+                    &xml,
+                    0,
+                    || InvalidUnion,
+                )?)?))),
+                "in_r" => Ok(Inr(Box::new(ProtocolValue::decode(child_node(
+                    &xml,
+                    0,
+                    || InvalidUnion,
+                )?)?))),
+                _ => Err(io::Error::from(InvalidUnion)),
             },
             "string" => {
                 assert_decode_error(xml.attributes.is_empty(), || InvalidString)?;
@@ -93,37 +116,28 @@ impl ProtocolValue {
             "int" => {
                 assert_decode_error(xml.attributes.is_empty(), || InvalidInteger)?;
 
-                xml.get_text()
-                    .parse::<i64>()
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))
-                    .map(Int)
+                parse_number(&xml.get_text()).map(Int)
             }
             "bool" => {
                 assert_decode_error(xml.children.is_empty(), || InvalidBoolean)?;
-                assert_decode_error(xml.attributes.get("val").is_some(), || InvalidBoolean)?;
 
-                let val = xml.attributes.get("val").unwrap();
+                let val = attribute(&xml, "val", || InvalidBoolean)?;
                 val.parse::<bool>()
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))
+                    .map_err(|_| io::Error::from(InvalidBoolean))
                     .map(Boolean)
             }
             "pair" => {
                 assert_decode_error(xml.children.len() == 2, || InvalidPair)?;
                 assert_decode_error(xml.attributes.is_empty(), || InvalidPair)?;
 
-                let mut vals = xml
-                    .children
-                    .iter()
-                    .filter_map(|el| el.as_node())
-                    .map(|el| ProtocolValue::decode(el.clone()))
-                    .collect::<Result<Vec<_>, _>>()?;
-                Ok(Pair(Box::new(vals.remove(0)), Box::new(vals.remove(0))))
+                let fst = ProtocolValue::decode(child_node(&xml, 0, || InvalidPair)?)?;
+                let snd = ProtocolValue::decode(child_node(&xml, 1, || InvalidPair)?)?;
+                Ok(Pair(Box::new(fst), Box::new(snd)))
             }
             "option" => {
                 assert_decode_error(!xml.attributes.is_empty(), || InvalidOption)?;
-                assert_decode_error(xml.attributes.get("val").is_some(), || InvalidOption)?;
 
-                let val = xml.attributes.get("val").unwrap();
+                let val = attribute(&xml, "val", || InvalidOption)?;
                 match val.as_str() {
                     "some" => {
                         assert_decode_error(xml.children.len() == 1, || InvalidOption)?;
@@ -140,78 +154,44 @@ impl ProtocolValue {
 
                         Ok(Optional(None))
                     }
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("{:?}", InvalidOption),
-                    )),
+                    _ => Err(io::Error::from(InvalidOption)),
                 }
             }
             "state_id" => {
                 assert_decode_error(!xml.attributes.is_empty(), || InvalidStateId)?;
-                assert_decode_error(xml.attributes.get("val").is_some(), || InvalidStateId)?;
-
-                let val = xml
-                    .attributes
-                    .get("val")
-                    .unwrap()
-                    .parse::<i64>()
-                    .map_err(|err| {
-                        io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))
-                    })?;
+
+                let val = parse_number(attribute(&xml, "val", || InvalidStateId)?)?;
                 Ok(StateId(val))
             }
             "route_id" => {
                 assert_decode_error(!xml.attributes.is_empty(), || InvalidStateId)?;
-                assert_decode_error(xml.attributes.get("val").is_some(), || InvalidStateId)?;
-
-                let val = xml
-                    .attributes
-                    .get("val")
-                    .unwrap()
-                    .parse::<i64>()
-                    .map_err(|err| {
-                        io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))
-                    })?;
+
+                let val = parse_number(attribute(&xml, "val", || InvalidStateId)?)?;
                 Ok(RouteId(val))
             }
             "status" => {
                 assert_decode_error(xml.children.len() == 4, || InvalidStatus)?;
 
-                let mut children = xml
-                    .children
-                    .iter()
-                    .filter_map(|el| el.as_node())
-                    .map(|el| ProtocolValue::decode(el.clone()))
-                    .collect::<Result<Vec<_>, _>>()?;
-
-                Ok(Status(
-                    Box::new(children.remove(0)),
-                    Box::new(children.remove(0)),
-                    Box::new(children.remove(0)),
-                    Box::new(children.remove(0)),
-                ))
+                let status = |i| -> io::Result<Box<ProtocolValue>> {
+                    ProtocolValue::decode(child_node(&xml, i, || InvalidStatus)?).map(Box::new)
+                };
+
+                Ok(Status(status(0)?, status(1)?, status(2)?, status(3)?))
             }
             "goals" => {
                 assert_decode_error(xml.children.len() == 4, || InvalidGoals)?;
-                let fg =
-                    ProtocolValue::decode(xml.children[0].clone().as_node().cloned().unwrap())?;
+                let fg = ProtocolValue::decode(child_node(&xml, 0, || InvalidGoals)?)?;
                 // <list> of <goal>s
-                let bg =
-                    ProtocolValue::decode(xml.children[1].clone().as_node().cloned().unwrap())?;
+                let bg = ProtocolValue::decode(child_node(&xml, 1, || InvalidGoals)?)?;
                 // <list> of <pair>s of <list>s of <goal>s
-                let sg =
-                    ProtocolValue::decode(xml.children[2].clone().as_node().cloned().unwrap())?;
+                let sg = ProtocolValue::decode(child_node(&xml, 2, || InvalidGoals)?)?;
                 // <list> of <goal>s
-                let gg =
-                    ProtocolValue::decode(xml.children[3].clone().as_node().cloned().unwrap())?;
+                let gg = ProtocolValue::decode(child_node(&xml, 3, || InvalidGoals)?)?;
                 // <list> of <goal>s
 
                 let fg = match fg {
                     List(vs) => Ok(vs),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("{:?}", InvalidGoals),
-                    )),
+                    _ => Err(io::Error::from(InvalidGoals)),
                 }?;
                 let bg = match bg {
                     List(vs) => Ok(vs
@@ -231,49 +211,48 @@ impl ProtocolValue {
                             _ => None,
                         })
                         .collect()),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("{:?}", InvalidGoals),
-                    )),
+                    _ => Err(io::Error::from(InvalidGoals)),
                 }?;
                 let sg = match sg {
                     List(vs) => Ok(vs),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("{:?}", InvalidGoals),
-                    )),
+                    _ => Err(io::Error::from(InvalidGoals)),
                 }?;
                 let gg = match gg {
                     List(vs) => Ok(vs),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("{:?}", InvalidGoals),
-                    )),
+                    _ => Err(io::Error::from(InvalidGoals)),
                 }?;
 
                 Ok(Goals(fg, bg, sg, gg))
             }
             "goal" => {
-                assert_decode_error(xml.children.len() == 3, || InvalidGoal)?;
+                // NOTE: the `user-name` field is not sent by older versions of `coqidetop`.
+                assert_decode_error(xml.children.len() == 3 || xml.children.len() == 4, || {
+                    InvalidGoal
+                })?;
 
-                let name =
-                    ProtocolValue::decode(xml.children[0].clone().as_node().cloned().unwrap())?;
+                let name = ProtocolValue::decode(child_node(&xml, 0, || InvalidGoal)?)?;
                 let hyps = {
-                    let node = xml.children[1].as_node().cloned().unwrap();
+                    let node = child_node(&xml, 1, || InvalidGoal)?;
                     match node.name.as_str() {
                         "list" => node
                             .children
                             .into_iter()
                             .filter_map(|child| {
-                                ProtocolRichPP::decode(child.as_node().cloned().unwrap()).ok()
+                                child
+                                    .as_node()
+                                    .and_then(|node| ProtocolRichPP::decode(node.clone()).ok())
                             })
                             .collect(),
                         _ => Vec::new(),
                     }
                 };
-                let ccl = ProtocolRichPP::decode(xml.children[2].as_node().cloned().unwrap())?;
+                let ccl = ProtocolRichPP::decode(child_node(&xml, 2, || InvalidGoal)?)?;
+                let user_name = match xml.children.get(3).and_then(|c| c.as_node()) {
+                    Some(node) => ProtocolValue::decode(node.clone())?,
+                    None => Optional(None),
+                };
 
-                Ok(Goal(Box::new(name), hyps, ccl))
+                Ok(Goal(Box::new(name), hyps, ccl, Box::new(user_name)))
             }
             _ => Ok(Unknown(xml)),
         }
@@ -286,81 +265,52 @@ impl ProtocolResult {
         match xml.name.as_str() {
             "value" => {
                 assert_decode_error(xml.attributes.len() >= 1, || InvalidValue)?;
-                assert_decode_error(xml.attributes.get("val").is_some(), || InvalidValue)?;
 
-                let val = xml.attributes.get("val").unwrap();
+                let val = attribute(&xml, "val", || InvalidValue)?;
                 match val.as_str() {
-                    "good" => ProtocolValue::decode(
-                        xml.children[0]
-                            .as_node()
-                            .ok_or_else(|| {
-                                io::Error::new(
-                                    io::ErrorKind::InvalidData,
-                                    format!("{:?}", InvalidValue),
-                                )
-                            })?
-                            .clone(),
-                    )
-                    .map(Good),
+                    "good" => {
+                        ProtocolValue::decode(child_node(&xml, 0, || InvalidValue)?).map(Good)
+                    }
                     "fail" => {
                         let loc_s = xml
                             .attributes
                             .get("loc_s")
-                            .map(|str| str.parse::<i64>())
-                            .transpose()
-                            .map_err(|err| {
-                                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))
-                            })?;
+                            .map(|str| parse_number(str))
+                            .transpose()?;
                         let loc_e = xml
                             .attributes
                             .get("loc_e")
-                            .map(|str| str.parse::<i64>())
-                            .transpose()
-                            .map_err(|err| {
-                                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))
-                            })?;
+                            .map(|str| parse_number(str))
+                            .transpose()?;
 
                         let richpp_elem = xml
                             .get_child("richpp".to_string())
-                            .ok_or_else(|| {
-                                io::Error::new(
-                                    io::ErrorKind::InvalidData,
-                                    format!("{:?}", InvalidValue),
-                                )
-                            })?
+                            .ok_or_else(|| io::Error::from(InvalidValue))?
                             .clone();
                         let richpp = ProtocolRichPP::decode(richpp_elem)?;
                         let state_id =
-                            ProtocolValue::decode(xml.children[0].as_node().cloned().unwrap())?;
+                            ProtocolValue::decode(child_node(&xml, 0, || InvalidValue)?)?;
 
                         Ok(Fail(loc_s, loc_e, state_id, richpp))
                     }
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("{:?}", InvalidValue),
-                    )),
+                    _ => Err(io::Error::from(InvalidValue)),
                 }
             }
             "feedback" => {
                 assert_decode_error(!xml.attributes.is_empty(), || InvalidFeedback)?;
                 assert_decode_error(!xml.children.is_empty(), || InvalidFeedback)?;
-                assert_decode_error(xml.attributes.get("object").is_some(), || InvalidFeedback)?;
-                assert_decode_error(xml.attributes.get("route").is_some(), || InvalidFeedback)?;
 
-                let object = xml.attributes.get("object").unwrap().clone();
-                let route = xml.attributes.get("route").unwrap().clone();
+                let object = attribute(&xml, "object", || InvalidFeedback)?.clone();
+                let route = attribute(&xml, "route", || InvalidFeedback)?.clone();
 
-                let state_id = xml.children[0].as_node().cloned().unwrap();
-                let feedback_content = xml.children[1].as_node().cloned().unwrap();
+                let state_id = child_node(&xml, 0, || InvalidFeedback)?;
+                let feedback_content = child_node(&xml, 1, || InvalidFeedback)?;
                 let feedback_content = FeedbackContent::decode(feedback_content)?;
 
                 ProtocolValue::decode(state_id)
                     .map(|val| ProtocolResult::Feedback(object, route, val, feedback_content))
             }
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("{:?}", InvalidValue),
-            )),
+            _ => Err(io::Error::from(InvalidValue)),
         }
     }
 
@@ -382,15 +332,11 @@ impl ProtocolRichPP {
     pub fn decode(xml: XMLNode) -> io::Result<Self> {
         let inner1 = xml
             .get_child("_".to_string())
-            .ok_or_else(|| {
-                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", InvalidRichPP))
-            })?
+            .ok_or_else(|| io::Error::from(InvalidRichPP))?
             .clone();
         let inner2 = inner1
             .get_child("pp".to_string())
-            .ok_or_else(|| {
-                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", InvalidRichPP))
-            })?
+            .ok_or_else(|| io::Error::from(InvalidRichPP))?
             .clone();
 
         let parts = inner2
@@ -446,11 +392,7 @@ impl ProtocolRichPP {
 impl FeedbackContent {
     /// Tries to decode a [`FeedbackContent`] from a [`XMLNode`].
     pub fn decode(xml: XMLNode) -> io::Result<Self> {
-        assert_decode_error(xml.attributes.get("val").is_some(), || {
-            InvalidFeedbackContent
-        })?;
-
-        match xml.attributes.get("val").unwrap().as_str() {
+        match attribute(&xml, "val", || InvalidFeedbackContent)?.as_str() {
             "processed" => Ok(FeedbackContent::Processed),
             "message" => {
                 assert_decode_error(!xml.children.is_empty(), || InvalidFeedbackContent)?;
@@ -462,62 +404,63 @@ impl FeedbackContent {
                 //      <_><pp>...</pp></_>
                 //    </richpp>
                 // </message>
-                let message = xml.children[0].as_node().unwrap().clone();
+                let message = child_node(&xml, 0, || InvalidFeedbackContent)?;
                 assert_decode_error(!message.children.is_empty(), || InvalidFeedbackContent)?;
                 assert_decode_error(message.children.len() >= 3, || InvalidFeedbackContent)?;
 
                 let content =
-                    ProtocolRichPP::decode(message.children[2].as_node().cloned().unwrap())?;
-                let level = MessageType::decode(message.children[0].as_node().cloned().unwrap())?;
+                    ProtocolRichPP::decode(child_node(&message, 2, || InvalidFeedbackContent)?)?;
+                let level =
+                    MessageType::decode(child_node(&message, 0, || InvalidFeedbackContent)?)?;
 
                 Ok(FeedbackContent::Message(level, content))
             }
             "workerstatus" => {
                 assert_decode_error(!xml.children.is_empty(), || InvalidFeedbackContent)?;
 
-                Ok(FeedbackContent::WorkerStatus(
-                    xml.children[0].as_node().cloned().unwrap(),
-                ))
+                Ok(FeedbackContent::WorkerStatus(child_node(&xml, 0, || {
+                    InvalidFeedbackContent
+                })?))
             }
             "processingin" => {
                 assert_decode_error(!xml.children.is_empty(), || InvalidFeedbackContent)?;
 
-                Ok(FeedbackContent::ProcessingIn(
-                    xml.children[0].as_node().cloned().unwrap(),
-                ))
+                Ok(FeedbackContent::ProcessingIn(child_node(&xml, 0, || {
+                    InvalidFeedbackContent
+                })?))
             }
             "addedaxiom" => Ok(FeedbackContent::AddedAxiom),
             "complete" => Ok(FeedbackContent::Complete),
             "incomplete" => Ok(FeedbackContent::Incomplete),
-            "globref" => Ok(FeedbackContent::GlobRef(
-                xml.children[0].as_node().cloned().unwrap(),
-            )),
-            "globdef" => Ok(FeedbackContent::GlobDef(
-                xml.children[0].as_node().cloned().unwrap(),
-            )),
-            "inprogress" => Ok(FeedbackContent::InProgress(
-                xml.children[0].as_node().cloned().unwrap(),
-            )),
+            "globref" => Ok(FeedbackContent::GlobRef(child_node(&xml, 0, || {
+                InvalidFeedbackContent
+            })?)),
+            "globdef" => Ok(FeedbackContent::GlobDef(child_node(&xml, 0, || {
+                InvalidFeedbackContent
+            })?)),
+            "inprogress" => Ok(FeedbackContent::InProgress(child_node(&xml, 0, || {
+                InvalidFeedbackContent
+            })?)),
             "filedependency" => {
                 assert_decode_error(xml.children.len() == 2, || InvalidFeedbackContent)?;
 
-                let name = ProtocolValue::decode(xml.children[0].as_node().cloned().unwrap())?;
-                let path = ProtocolValue::decode(xml.children[1].as_node().cloned().unwrap())?;
+                let name = ProtocolValue::decode(child_node(&xml, 0, || InvalidFeedbackContent)?)?;
+                let path = ProtocolValue::decode(child_node(&xml, 1, || InvalidFeedbackContent)?)?;
 
                 Ok(FeedbackContent::FileDependency(name, path))
             }
             "fileloaded" => {
                 assert_decode_error(xml.children.len() == 2, || InvalidFeedbackContent)?;
 
-                let name = ProtocolValue::decode(xml.children[0].as_node().cloned().unwrap())?;
-                let path = ProtocolValue::decode(xml.children[1].as_node().cloned().unwrap())?;
+                let name = ProtocolValue::decode(child_node(&xml, 0, || InvalidFeedbackContent)?)?;
+                let path = ProtocolValue::decode(child_node(&xml, 1, || InvalidFeedbackContent)?)?;
 
                 Ok(FeedbackContent::FileLoaded(name, path))
             }
-            "custom" => Ok(FeedbackContent::Custom(
-                xml.children[0].as_node().cloned().unwrap(),
-            )),
-            v => panic!("Unrecognized feedback content type '{}'", v),
+            "custom" => Ok(FeedbackContent::Custom(child_node(&xml, 0, || {
+                InvalidFeedbackContent
+            })?)),
+            v => Err(io::Error::from(UnknownFeedbackContent(v.to_string()))),
         }
     }
 }
@@ -525,13 +468,13 @@ impl FeedbackContent {
 impl MessageType {
     /// Tries to decode a
     pub fn decode(xml: XMLNode) -> io::Result<Self> {
-        match xml.attributes.get("val").unwrap().as_str() {
+        match attribute(&xml, "val", || InvalidMessageLevel)?.as_str() {
             "notice" => Ok(MessageType::Notice),
             "debug" => Ok(MessageType::Debug),
             "info" => Ok(MessageType::Info),
             "error" => Ok(MessageType::Error),
             "warning" => Ok(MessageType::Warning),
-            _ => unreachable!(),
+            _ => Err(io::Error::from(InvalidMessageLevel)),
         }
     }
 }
@@ -544,9 +487,36 @@ where
     if cond {
         Ok(())
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("{:?}", gen()),
-        ))
+        Err(io::Error::from(gen()))
     }
 }
+
+/// Retrieves the child node at the given index, or throw the [`DecodeError`] generated by the function
+/// if there is none.
+fn child_node<F>(xml: &XMLNode, index: usize, gen: F) -> io::Result<XMLNode>
+where
+    F: FnOnce() -> DecodeError,
+{
+    xml.children
+        .get(index)
+        .and_then(|child| child.as_node())
+        .cloned()
+        .ok_or_else(|| io::Error::from(gen()))
+}
+
+/// Retrieves the value of the given attribute, or throw the [`DecodeError`] generated by the function
+/// if it is not present.
+fn attribute<'a, F>(xml: &'a XMLNode, name: &str, gen: F) -> io::Result<&'a String>
+where
+    F: FnOnce() -> DecodeError,
+{
+    xml.attributes
+        .get(name)
+        .ok_or_else(|| io::Error::from(gen()))
+}
+
+/// Parses a number, or throw [`DecodeError::InvalidNumber`] if it is malformed.
+fn parse_number(text: &str) -> io::Result<i64> {
+    text.parse::<i64>()
+        .map_err(|_| io::Error::from(InvalidNumber(text.to_string())))
+}
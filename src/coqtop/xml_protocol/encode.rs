@@ -36,7 +36,7 @@ impl ProtocolValue {
             ),
             // We should never have to encode a goal, only decode them.
             Goals(_, _, _, _) => unreachable!(),
-            Goal(_, _, _) => unreachable!(),
+            Goal(_, _, _, _) => unreachable!(),
             Unknown(_) => format!(""),
         }
     }
@@ -25,14 +25,11 @@ impl ProtocolRichPP {
     pub fn warning(self) -> Self {
         use ProtocolRichPPPart::*;
 
-        let ProtocolRichPP::RichPP(parts) = self.strip();
+        let ProtocolRichPP::RichPP(parts) = self;
         ProtocolRichPP::RichPP(
             parts
                 .into_iter()
-                .map(|part| match part {
-                    Raw(str) => Warning(str),
-                    _ => unreachable!(),
-                })
+                .map(|part| Warning(part.to_string()))
                 .collect::<Vec<_>>(),
         )
     }
@@ -40,14 +37,11 @@ impl ProtocolRichPP {
     pub fn error(self) -> Self {
         use ProtocolRichPPPart::*;
 
-        let ProtocolRichPP::RichPP(parts) = self.strip();
+        let ProtocolRichPP::RichPP(parts) = self;
         ProtocolRichPP::RichPP(
             parts
                 .into_iter()
-                .map(|part| match part {
-                    Raw(str) => Error(str),
-                    _ => unreachable!(),
-                })
+                .map(|part| Error(part.to_string()))
                 .collect::<Vec<_>>(),
         )
     }
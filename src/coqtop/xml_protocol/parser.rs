@@ -81,7 +81,7 @@ impl Decoder for XMLDecoder {
                 .map_position(|p| p.translate_position(&src[..]));
             io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!("{}\nIn input: `{}`", err, std::str::from_utf8(src).unwrap()),
+                format!("{}\nIn input: `{}`", err, String::from_utf8_lossy(src)),
             )
         })?;
 
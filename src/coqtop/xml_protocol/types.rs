@@ -72,6 +72,8 @@ pub enum ProtocolValue {
         Vec<ProtocolRichPP>,
         /// The conclusion of the goal.
         ProtocolRichPP,
+        /// The name given by the user (e.g. with `refine ?[lhs]`), as an [`ProtocolValue::Optional`].
+        Box<ProtocolValue>,
     ),
 
     /// An unknown value has been decoded
@@ -88,9 +90,9 @@ pub enum ProtocolResult {
     ),
     /// An error occured.
     Fail(
-        /// The optional line number where the error occured.
+        /// The optional offset (in bytes, from the beginning of the statement) where the error starts.
         Option<i64>,
-        /// The optional column number where the error occured.
+        /// The optional offset (in bytes, from the beginning of the statement) where the error stops.
         Option<i64>,
         /// The state ID to go back to on error.
         ProtocolValue,
@@ -0,0 +1,140 @@
+use std::{fmt, io};
+
+use crate::{
+    client::commands::protocol::PROTOCOL_VERSION,
+    coqtop::xml_protocol::{
+        decode::DecodeError,
+        types::{ProtocolResult, ProtocolRichPP, ProtocolRichPPPart, ProtocolValue},
+    },
+};
+
+/// Errors which must not stop the daemon.
+///
+/// They travel through the [`io::Result`]s of the processing pipeline inside an [`io::Error`] (of
+/// kind [`io::ErrorKind::InvalidData`]), until the main loop gets them back with
+/// [`DaemonError::try_from`], logs them and shows them in the result buffer.
+#[derive(Debug)]
+pub enum DaemonError {
+    /// A message sent by `coqidetop` could not be decoded.
+    Decode(DecodeError),
+    /// `coqidetop` answered something which does not fit what was asked.
+    Protocol(ProtocolError),
+    /// A command sent by the client cannot be handled.
+    Client(ClientError),
+}
+
+/// Well-formed messages from `coqidetop` which were not expected.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A value of the wrong shape, along with a description of what was expected.
+    UnexpectedValue(&'static str, ProtocolValue),
+    /// A result of the wrong kind (e.g. some feedback instead of a response), along with a description
+    /// of what was expected.
+    UnexpectedResult(&'static str, Box<ProtocolResult>),
+}
+
+/// Commands from the client which cannot be handled.
+#[derive(Debug)]
+pub enum ClientError {
+    /// A line which could not be parsed as a command (e.g. because it is not valid UTF-8).
+    InvalidCommand(String),
+    /// A JSON request which could not be decoded, along with the reason why.
+    MalformedRequest(String),
+    /// A JSON request written for another version of the protocol.
+    UnsupportedVersion(u64),
+    /// A command which is not supported yet.
+    Unsupported(&'static str),
+}
+
+impl DaemonError {
+    /// The message to show in the result buffer.
+    pub fn message(&self) -> ProtocolRichPP {
+        ProtocolRichPP::RichPP(vec![ProtocolRichPPPart::Error(self.to_string())])
+    }
+}
+
+impl fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonError::Decode(err) => {
+                write!(f, "Could not decode message from coqidetop: {}", err)
+            }
+            DaemonError::Protocol(err) => write!(f, "Unexpected message from coqidetop: {}", err),
+            DaemonError::Client(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedValue(expected, value) => {
+                write!(f, "expected {} but got {:?}", expected, value)
+            }
+            ProtocolError::UnexpectedResult(expected, result) => {
+                write!(f, "expected {} but got {:?}", expected, result)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidCommand(line) => write!(f, "Invalid command: {:?}", line),
+            ClientError::MalformedRequest(reason) => write!(f, "Malformed request: {}", reason),
+            ClientError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported protocol version {} (expected {})",
+                version, PROTOCOL_VERSION
+            ),
+            ClientError::Unsupported(what) => write!(f, "{} are not supported yet", what),
+        }
+    }
+}
+
+impl std::error::Error for DaemonError {}
+
+impl From<DecodeError> for DaemonError {
+    fn from(err: DecodeError) -> Self {
+        DaemonError::Decode(err)
+    }
+}
+
+impl From<ProtocolError> for DaemonError {
+    fn from(err: ProtocolError) -> Self {
+        DaemonError::Protocol(err)
+    }
+}
+
+impl From<ClientError> for DaemonError {
+    fn from(err: ClientError) -> Self {
+        DaemonError::Client(err)
+    }
+}
+
+impl From<DaemonError> for io::Error {
+    fn from(err: DaemonError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl TryFrom<io::Error> for DaemonError {
+    type Error = io::Error;
+
+    /// Gets back the [`DaemonError`] carried by an [`io::Error`], or gives back any other error untouched.
+    fn try_from(err: io::Error) -> Result<Self, Self::Error> {
+        if !err.get_ref().is_some_and(|inner| inner.is::<DaemonError>()) {
+            return Err(err);
+        }
+
+        match err
+            .into_inner()
+            .map(|inner| inner.downcast::<DaemonError>())
+        {
+            Some(Ok(err)) => Ok(*err),
+            // NOTE: we just checked that the inner error is a `DaemonError`
+            _ => Err(io::Error::other("Lost daemon error")),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::client::commands::types::ClientCommand;
+
+/// Whether statements can currently be processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorState {
+    /// Everything went fine until now.
+    Ok,
+    /// `coqidetop` reported an error, which must be dealt with before processing further.
+    Error,
+    /// The user interrupted processing, and must explicitly stop interrupting.
+    Interrupted,
+}
+
+/// Everything which can make the [`ErrorState`] change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorEvent {
+    /// `coqidetop` reported an error.
+    Failed,
+    /// The user interrupted `coqidetop`.
+    Interrupted,
+    /// The user stopped interrupting (see [`ClientCommand::StopInterrupt`]).
+    Resumed,
+    /// The last error does not matter anymore, because it has been ignored, edited or backtracked over.
+    Cleared,
+}
+
+/// The state machine owning the [`ErrorState`], which decides what is accepted in each state
+/// and how the state changes.
+#[derive(Clone, Debug)]
+pub struct ErrorStateMachine {
+    state: ErrorState,
+}
+
+impl ErrorStateMachine {
+    pub fn new() -> Self {
+        Self {
+            state: ErrorState::Ok,
+        }
+    }
+
+    /// The current state.
+    pub fn state(&self) -> ErrorState {
+        self.state
+    }
+
+    /// Checks whether a client command can be processed in the current state,
+    /// returning why it cannot otherwise.
+    pub fn accepts(&self, command: &ClientCommand) -> Result<(), &'static str> {
+        use ClientCommand::*;
+
+        match (self.state, command) {
+            (
+                _,
+                Init
+                | Quit
+                | StopInterrupt
+                | BackTo(_)
+                | Status
+                | ToggleGoals(_)
+                | MessagesAt(_, _)
+                | ShowConfig
+                | Restart(_)
+                | Query(_)
+                | Hints
+                // NOTE: Kakoune does not send edits twice, and they must be known to keep the ranges in sync
+                | Edited(_, _),
+            ) => Ok(()),
+            (ErrorState::Ok | ErrorState::Interrupted, IgnoreError) => {
+                Err("there is no error to ignore")
+            }
+            (ErrorState::Ok, _) => Ok(()),
+            (
+                ErrorState::Error,
+                Previous | RewindTo(_, _) | ShowGoals(_) | IgnoreError,
+            ) => Ok(()),
+            (ErrorState::Error, MoveTo(_) | Next(_, _, _)) => {
+                Err("the last error must be ignored or edited before processing further")
+            }
+            (ErrorState::Interrupted, _) => {
+                Err("processing has been interrupted and must be resumed first")
+            }
+        }
+    }
+
+    /// Checks whether the response of `coqidetop` to a command must be processed in the current state,
+    /// returning why it must be ignored otherwise.
+    ///
+    /// Once something went wrong, only responses to commands going back are still meaningful.
+    pub fn accepts_response(&self, command: &ClientCommand) -> Result<(), &'static str> {
+        use ClientCommand::*;
+
+        match (self.state, command) {
+            (_, Init | Quit | Previous | BackTo(_)) => Ok(()),
+            (ErrorState::Ok, _) => Ok(()),
+            (ErrorState::Error, _) => Err("an error happened before it was received"),
+            (ErrorState::Interrupted, _) => Err("processing has been interrupted"),
+        }
+    }
+
+    /// Checks whether feedback (other than errors) must be processed in the current state,
+    /// returning why it must be ignored otherwise.
+    pub fn accepts_feedback(&self) -> Result<(), &'static str> {
+        match self.state {
+            ErrorState::Ok => Ok(()),
+            ErrorState::Error => Err("an error happened before it was received"),
+            ErrorState::Interrupted => Err("processing has been interrupted"),
+        }
+    }
+
+    /// The state reached from the given state on the given event, or why this event is rejected there.
+    pub fn next(state: ErrorState, event: ErrorEvent) -> Result<ErrorState, &'static str> {
+        use ErrorEvent::*;
+
+        match (state, event) {
+            (ErrorState::Ok | ErrorState::Error, Failed) => Ok(ErrorState::Error),
+            (ErrorState::Interrupted, Failed) => {
+                Err("errors caused by interrupting are reported once resumed")
+            }
+            (_, Interrupted) => Ok(ErrorState::Interrupted),
+            (ErrorState::Interrupted | ErrorState::Ok, Resumed) => Ok(ErrorState::Ok),
+            (ErrorState::Error, Resumed) => Err("the last error must be ignored or edited"),
+            (ErrorState::Error | ErrorState::Ok, Cleared) => Ok(ErrorState::Ok),
+            (ErrorState::Interrupted, Cleared) => {
+                Err("processing has been interrupted and must be resumed first")
+            }
+        }
+    }
+
+    /// Make the state change on the given event, logging it if it is rejected in the current state.
+    pub fn transition(&mut self, event: ErrorEvent) -> ErrorState {
+        match Self::next(self.state, event) {
+            Ok(state) => {
+                if state != self.state {
+                    log::debug!("Error state: {:?} -> {:?} ({:?})", self.state, state, event);
+                }
+                self.state = state;
+            }
+            Err(reason) => log::warn!(
+                "Ignoring {:?} in error state {:?}: {}",
+                event,
+                self.state,
+                reason
+            ),
+        }
+
+        self.state
+    }
+}
+
+impl Default for ErrorStateMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<ErrorState> for ErrorStateMachine {
+    fn from(state: ErrorState) -> Self {
+        Self { state }
+    }
+}
@@ -1,17 +1,43 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
 // NOTE: changes to paths in this file must be reflected back in the
 // `rc/coqide.kak` file.
 
 /// Retrieves the path to the goal file given the path to its directory.
-pub fn goal_file(tmp_dir: &String) -> String {
+pub fn goal_file(tmp_dir: &str) -> String {
     format!("{}/goal", tmp_dir)
 }
 
 /// Retrieves the path to the result file given its dirname.
-pub fn result_file(tmp_dir: &String) -> String {
+pub fn result_file(tmp_dir: &str) -> String {
     format!("{}/result", tmp_dir)
 }
 
 /// Retrieves the file where all logging is done.
-pub fn log_file(tmp_dir: &String) -> String {
+pub fn log_file(tmp_dir: &str) -> String {
     format!("{}/log", tmp_dir)
 }
+
+/// Retrieves the socket where buffers attach to the daemon serving all the buffers of a project.
+pub fn manager_socket(dir: &str) -> String {
+    format!("{}/socket", dir)
+}
+
+/// Retrieves the file locked by the daemon serving all the buffers of a project for as long as it runs.
+pub fn manager_lock(dir: &str) -> String {
+    format!("{}/lock", dir)
+}
+
+/// Looks for a file with the given name in the directory of `file`, or in the closest of its ancestors.
+pub fn find_in_ancestors(file: &str, name: &str) -> io::Result<Option<PathBuf>> {
+    let file_path = Path::new(file).canonicalize()?;
+    let directory = file_path.parent().ok_or(io::ErrorKind::NotFound)?;
+
+    Ok(directory
+        .ancestors()
+        .map(|dir| dir.join(name))
+        .find(|path| path.exists()))
+}
@@ -16,7 +16,32 @@ pub async fn kak(session: &String, commands: String) -> io::Result<()> {
         .as_mut()
         .expect("could not get stdin of 'kak' process");
     stdin.write_all(commands.as_bytes()).await?;
-    proc.wait().await?;
 
+    let status = proc.wait().await?;
+    if !status.success() {
+        // NOTE: `kak -p` only fails when it cannot connect to the session
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!(
+                "Cannot send commands to Kakoune session {} ({})",
+                session, status
+            ),
+        ));
+    }
     Ok(())
 }
+
+/// Checks whether the given Kakoune session is still running, by looking for it in the list of all sessions.
+pub async fn is_running(session: &String) -> io::Result<bool> {
+    let output = Command::new("kak")
+        .arg("-l")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await?;
+
+    // NOTE: sessions which have crashed may still be listed, followed by `(dead)`
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line == session))
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     io,
     sync::{Arc, RwLock},
 };
@@ -10,12 +10,16 @@ use tokio::{
 };
 
 use crate::{
-    client::commands::types::DisplayCommand,
-    coqtop::xml_protocol::types::{ProtocolRichPP, ProtocolRichPPPart, ProtocolValue},
+    client::commands::types::{DisplayCommand, GoalCategory},
+    coqtop::{
+        backend::{Goal, Goals},
+        xml_protocol::types::{ProtocolRichPP, ProtocolRichPPPart},
+    },
+    error_state::ErrorState,
     files::{goal_file, result_file},
-    range::Range,
+    range::{ColumnUnit, Range},
     session::{client_name, edited_file, session_id, temporary_folder, Session},
-    state::{ErrorState, State},
+    state::State,
 };
 
 use super::command_line::kak;
@@ -24,6 +28,20 @@ pub struct KakouneUIUpdater {
     session: Arc<Session>,
     state: Arc<RwLock<State>>,
     current_buffer_line: usize,
+    /// The last goals received, kept to be able to redisplay them.
+    last_goals: Option<Goals>,
+    /// The categories of goals which are shown in full in the goal buffer.
+    expanded_goals: HashSet<GoalCategory>,
+    /// The ranges of processed code currently shown.
+    processed: Vec<Range>,
+    /// The ranges of code containing axioms currently shown.
+    axioms: Vec<Range>,
+    /// The range of the last error, if it is shown.
+    error_range: Option<Range>,
+    /// The inline results currently shown, along with the statements they are attached to.
+    inline_results: Vec<(Range, String)>,
+    /// Have any of the ranges above changed since they were last sent to Kakoune?
+    ranges_changed: bool,
 }
 
 impl KakouneUIUpdater {
@@ -32,6 +50,13 @@ impl KakouneUIUpdater {
             session,
             state,
             current_buffer_line: 1,
+            last_goals: None,
+            expanded_goals: HashSet::new(),
+            processed: Vec::new(),
+            axioms: Vec::new(),
+            error_range: None,
+            inline_results: Vec::new(),
+            ranges_changed: false,
         }
     }
 
@@ -41,35 +66,45 @@ impl KakouneUIUpdater {
         while let Some(cmd) = commands.pop_front() {
             log::debug!("Received UI command {:?}", cmd);
 
-            let error_state = self.state.read().unwrap().error_state;
+            let error_state = self.state.read().unwrap().error_state.state();
 
             match cmd {
                 DisplayCommand::ColorResult(richpp, append) => {
                     self.refresh_result_buffer_with(richpp, append).await?
                 }
-                DisplayCommand::AddToProcessed(range) => self.add_to_processed(range).await?,
-                DisplayCommand::OutputGoals(fg, bg, gg, sg) => {
-                    self.output_goals(fg, bg, gg, sg).await?
-                }
-                DisplayCommand::RemoveProcessed(range) => self.remove_processed(range).await?,
+                DisplayCommand::AddToProcessed(range) => self.add_to_processed(range),
+                DisplayCommand::OutputGoals(goals) => self.output_goals(goals).await?,
+                DisplayCommand::ToggleGoals(category) => self.toggle_goals(category).await?,
+                DisplayCommand::RemoveProcessed(range) => self.remove_processed(range),
                 DisplayCommand::RefreshErrorRange(range, force)
                     if force || error_state != ErrorState::Ok =>
                 {
-                    self.refresh_error_range(range).await?
+                    self.refresh_error_range(range)
                 } // TODO: do we need to do this only if it is Ok to continue?
                 DisplayCommand::RemoveToBeProcessed(range) => {
                     self.remove_to_be_processed(range).await?
                 }
                 DisplayCommand::GotoTip => self.goto_tip().await?,
-                DisplayCommand::AddAxiom(range) => self.add_axiom(range).await?,
-                DisplayCommand::RemoveAxiom(range) => self.remove_axiom(range).await?,
+                DisplayCommand::AddAxiom(range) => self.add_axiom(range),
+                DisplayCommand::RemoveAxiom(range) => self.remove_axiom(range),
                 DisplayCommand::ShowStatus(path, proof_name) => {
                     self.show_status(path, proof_name).await?
                 }
+                DisplayCommand::AddInlineResult(range, richpp) => {
+                    self.add_inline_result(range, richpp)
+                }
+                DisplayCommand::RemoveInlineResult(range) => {
+                    self.remove_inline_result(range);
+                }
+                DisplayCommand::ResetRanges => self.reset_ranges().await?,
                 _ => {}
             }
         }
 
+        if self.ranges_changed {
+            self.send_ranges().await?;
+        }
+
         Ok(())
     }
 
@@ -89,25 +124,146 @@ impl KakouneUIUpdater {
         .await
     }
 
-    async fn add_axiom(&mut self, range: Range) -> io::Result<()> {
+    /// Show a message next to the end of the given statement, after the results already shown there.
+    fn add_inline_result(&mut self, range: Range, richpp: ProtocolRichPP) {
+        let message = richpp
+            .strip()
+            .to_string()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if message.is_empty() {
+            return;
+        }
+
+        let message = match self.remove_inline_result(range) {
+            Some(previous) => format!("{}; {}", previous, message),
+            None => message,
+        };
+
+        self.inline_results.push((range, message));
+        self.ranges_changed = true;
+    }
+
+    /// Remove the message shown next to the given statement, returning it if there was any.
+    fn remove_inline_result(&mut self, range: Range) -> Option<String> {
+        let index = self.inline_results.iter().position(|(r, _)| *r == range)?;
+
+        self.ranges_changed = true;
+        Some(self.inline_results.remove(index).1)
+    }
+
+    fn add_axiom(&mut self, range: Range) {
+        self.axioms.push(range);
+        self.ranges_changed = true;
+    }
+
+    fn remove_axiom(&mut self, range: Range) {
+        self.axioms.retain(|r| *r != range);
+        self.ranges_changed = true;
+    }
+
+    fn add_to_processed(&mut self, range: Range) {
+        self.processed.push(range);
+        self.ranges_changed = true;
+    }
+
+    fn remove_processed(&mut self, range: Range) {
+        self.processed.retain(|r| *r != range);
+        self.ranges_changed = true;
+    }
+
+    fn refresh_error_range(&mut self, range: Option<Range>) {
+        self.error_range = range;
+        self.ranges_changed = true;
+    }
+
+    /// Send all the ranges of processed code, axioms and errors (and inline results) to Kakoune.
+    ///
+    /// The ranges are all translated to the last known timestamp of the buffer, and Kakoune
+    /// takes care of moving them through the edits it has made since then.
+    /// Ranges which have been invalidated by an edit are not shown, as the daemon is
+    /// about to go back before them.
+    async fn send_ranges(&mut self) -> io::Result<()> {
+        self.ranges_changed = false;
+
+        let (timestamp, processed, axioms, error_range, inline_results) = {
+            let state = self.state.read().unwrap();
+            let translate_all = |ranges: &Vec<Range>| {
+                ranges
+                    .iter()
+                    .filter_map(|range| state.translate(*range))
+                    .filter_map(Range::spec)
+                    .map(|spec| spec.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+
+            (
+                state.buffer_timestamp,
+                translate_all(&self.processed),
+                translate_all(&self.axioms),
+                self.error_range
+                    .and_then(|range| state.translate(range))
+                    .and_then(Range::spec)
+                    .map(|spec| spec.to_string())
+                    .unwrap_or_default(),
+                self.inline_results
+                    .iter()
+                    .filter_map(|(range, message)| {
+                        state
+                            .translate(*range)
+                            .map(|range| format!("'{}'", inline_result_spec(range, message)))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        };
+
         kak(
             &session_id(self.session.clone()),
             format!(
-                r#"evaluate-commands -buffer '{}' %{{ coqide-push-axiom "{}" }}"#,
+                r#"evaluate-commands -buffer '{}' %§
+                    coqide-set-ranges {} '{}' '{}' '{}'
+                    coqide-set-inline-results {} {}
+                §"#,
                 edited_file(self.session.clone()),
-                range
+                timestamp,
+                processed,
+                axioms,
+                error_range,
+                timestamp,
+                inline_results,
             ),
         )
         .await
     }
 
-    async fn remove_axiom(&mut self, range: Range) -> io::Result<()> {
+    /// Set the ranges of code to be processed to exactly those of the known operations,
+    /// discarding whatever Kakoune did to them when the buffer was edited.
+    async fn reset_ranges(&mut self) -> io::Result<()> {
+        let (timestamp, to_be_processed) = {
+            let state = self.state.read().unwrap();
+            let to_be_processed = state
+                .operations
+                .iter()
+                .filter(|op| op.range != Range::default())
+                .filter_map(|op| state.translate(op.range))
+                .filter_map(Range::spec)
+                .map(|spec| format!("'{}'", spec))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            (state.buffer_timestamp, to_be_processed)
+        };
+
         kak(
             &session_id(self.session.clone()),
             format!(
-                r#"evaluate-commands -buffer '{}' %{{ coqide-remove-axiom "{}" }}"#,
+                r#"evaluate-commands -buffer '{}' %§ coqide-set-to-be-processed-ranges {} {} §"#,
                 edited_file(self.session.clone()),
-                range
+                timestamp,
+                to_be_processed,
             ),
         )
         .await
@@ -125,60 +281,22 @@ impl KakouneUIUpdater {
     }
 
     async fn remove_to_be_processed(&mut self, range: Range) -> io::Result<()> {
-        kak(
-            &session_id(self.session.clone()),
-            format!(
-                r#"evaluate-commands -buffer '{}' %{{ coqide-remove-to-be-processed '{}' }}"#,
-                edited_file(self.session.clone()),
-                range
-            ),
-        )
-        .await
-    }
-
-    async fn remove_processed(&mut self, range: Range) -> io::Result<()> {
-        kak(
-            &session_id(self.session.clone()),
-            format!(
-                r#"evaluate-commands -buffer '{}' %{{ coqide-remove-processed '{}' }}"#,
-                edited_file(self.session.clone()),
-                range
-            ),
-        )
-        .await
-    }
+        let spec = match range.spec() {
+            Some(spec) => spec,
+            None => return Ok(()),
+        };
 
-    async fn add_to_processed(&mut self, range: Range) -> io::Result<()> {
         kak(
             &session_id(self.session.clone()),
             format!(
-                r#"evaluate-commands -buffer '{}' %{{ coqide-add-to-processed '{}' }}"#,
+                r#"evaluate-commands -buffer '{}' %{{ coqide-remove-to-be-processed '{}' }}"#,
                 edited_file(self.session.clone()),
-                range
+                spec
             ),
         )
         .await
     }
 
-    async fn refresh_error_range(&mut self, range: Option<Range>) -> io::Result<()> {
-        let coq_file = edited_file(self.session.clone());
-
-        kak(
-            &session_id(self.session.clone()),
-            match range {
-                None => format!(
-                    r#"evaluate-commands -buffer '{0}' %{{ coqide-remove-error-range }}"#,
-                    coq_file
-                ),
-                Some(range) => format!(
-                    r#"evaluate-commands -buffer '{0}' %{{ coqide-set-error-range '{1}' }}"#,
-                    coq_file, range
-                ),
-            },
-        )
-        .await
-    }
-
     async fn refresh_result_buffer_with(
         &mut self,
         richpp: ProtocolRichPP,
@@ -200,6 +318,7 @@ impl KakouneUIUpdater {
 
             let (mut content, colors) =
                 extract_colors(richpp, self.current_buffer_line, 1usize, true);
+            let colors = color_specs(&content, self.current_buffer_line, colors);
 
             let mut added_lines = 0;
             if !content.is_empty() {
@@ -229,71 +348,33 @@ impl KakouneUIUpdater {
         Ok(())
     }
 
-    /// Output all received goals to the goal buffer.
-    async fn output_goals(
-        &self,
-        fg: Vec<ProtocolValue>,
-        bg: Vec<(Vec<ProtocolValue>, Vec<ProtocolValue>)>,
-        gg: Vec<ProtocolValue>,
-        sg: Vec<ProtocolValue>,
-    ) -> io::Result<()> {
-        let goal_buffer = goal_file(&temporary_folder(self.session.clone()));
+    /// Output all received goals to the goal buffer (see [`render_goals`]), showing in full the categories
+    /// expanded with [`DisplayCommand::ToggleGoals`].
+    async fn output_goals(&mut self, goals: Goals) -> io::Result<()> {
+        self.last_goals = Some(goals.clone());
 
-        let mut message: String;
-        let mut colors: Vec<String> = Vec::new();
+        let goal_buffer = goal_file(&temporary_folder(self.session.clone()));
 
-        if fg.is_empty() {
-            if bg.is_empty() || bg.iter().all(|(lg, rg)| lg.is_empty() && rg.is_empty()) {
-                if gg.is_empty() {
-                    if sg.is_empty() {
-                        message = "There are no more subgoals.\nProof is complete.".to_string();
-                    } else {
-                        message = "There are no more subgoals, but some goals remain shelved:\n"
-                            .to_string();
-                        let mut line = 3usize;
-                        for goal in sg.into_iter() {
-                            let (txt, mut cols, i) = goal_to_string(goal, line);
-                            message = format!("{}\n{}", message, txt);
-                            colors.append(&mut cols);
-                            line = i + 1;
-                        }
-                    }
-                } else {
-                    message = "There are no more subgoals, but there are some given up goals:\n"
-                        .to_string();
-                    let mut line = 3usize;
-                    for goal in gg.into_iter() {
-                        let (txt, mut cols, i) = goal_to_string(goal, line);
-                        message = format!("{}\n{}", message, txt);
-                        colors.append(&mut cols);
-                        line = i + 1;
-                    }
-                }
-            } else {
-                message = "The current subgoal is complete, but there are unfinished subgoals:\n"
-                    .to_string();
-                let mut line = 3usize;
-                for (first, last) in bg.into_iter() {
-                    for goal in first.into_iter().chain(last.into_iter()) {
-                        let (txt, mut cols, i) = goal_to_string(goal, line);
-
-                        message = format!("{}\n{}", message, txt);
-                        colors.append(&mut cols);
-                        line = i + 1;
-                    }
+        let goal_names = goals
+            .focused
+            .iter()
+            .chain(
+                goals
+                    .background
+                    .iter()
+                    .flat_map(|(first, last)| first.iter().chain(last.iter())),
+            )
+            .chain(goals.shelved.iter())
+            .chain(goals.given_up.iter())
+            .filter_map(|goal| goal.user_name.clone())
+            .fold(Vec::new(), |mut names, name| {
+                if !names.contains(&name) {
+                    names.push(name);
                 }
-            }
-        } else {
-            message = format!("{} subgoal(s) remaining:\n", fg.len());
-            let mut line = 3usize;
-            for goal in fg.into_iter() {
-                let (txt, mut cols, i) = goal_to_string(goal, line);
-
-                message = format!("{}\n{}", message, txt);
-                colors.append(&mut cols);
-                line = i + 1;
-            }
-        }
+                names
+            });
+
+        let (message, colors) = render_goals(goals, &self.expanded_goals);
 
         let mut file = File::create(&goal_buffer).await?;
         file.write_all(message.as_bytes()).await?;
@@ -307,17 +388,211 @@ impl KakouneUIUpdater {
                 colors.join("\" \"")
             ),
         )
+        .await?;
+
+        self.set_goal_names(goal_names).await
+    }
+
+    /// Expand (or collapse) a category of goals, and refresh the goal buffer with the last goals received.
+    async fn toggle_goals(&mut self, category: GoalCategory) -> io::Result<()> {
+        if !self.expanded_goals.remove(&category) {
+            self.expanded_goals.insert(category);
+        }
+
+        match self.last_goals.take() {
+            Some(goals) => self.output_goals(goals).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Record the user-given names of all current goals, so that goal selectors can be inserted.
+    async fn set_goal_names(&self, names: Vec<String>) -> io::Result<()> {
+        kak(
+            &session_id(self.session.clone()),
+            format!(
+                r#"evaluate-commands -buffer '{0}' %{{ coqide-set-goal-names {1} }}"#,
+                edited_file(self.session.clone()),
+                names.join(" ")
+            ),
+        )
         .await
     }
 }
 
+/// Creates the content of the goal buffer for the given goals, along with its colors.
+///
+/// Only focused goals are shown by default, and other categories are summarized in a footer
+/// (like `coqtop`'s `(shelved: 1)`) unless they are among the expanded categories.
+/// When there are no focused goals left, the first non-empty category is always shown.
+pub fn render_goals(goals: Goals, expanded: &HashSet<GoalCategory>) -> (String, Vec<String>) {
+    let Goals {
+        focused: fg,
+        background: bg,
+        shelved: sg,
+        given_up: gg,
+    } = goals;
+    let bg = bg
+        .into_iter()
+        .flat_map(|(first, last)| first.into_iter().chain(last.into_iter()))
+        .collect::<Vec<_>>();
+
+    let counts = [
+        (GoalCategory::Background, bg.len()),
+        (GoalCategory::Shelved, sg.len()),
+        (GoalCategory::GivenUp, gg.len()),
+    ];
+    let mut categories = vec![
+        (GoalCategory::Background, bg),
+        (GoalCategory::Shelved, sg),
+        (GoalCategory::GivenUp, gg),
+    ];
+
+    let mut buffer = GoalBuffer::new();
+
+    if !fg.is_empty() {
+        buffer.push_line(format!("{} subgoal(s) remaining:", fg.len()));
+        buffer.push_goals(fg);
+    } else if let Some(index) = categories.iter().position(|(_, goals)| !goals.is_empty()) {
+        let (category, goals) = categories.remove(index);
+
+        buffer.push_line(match category {
+            GoalCategory::Background => {
+                "The current subgoal is complete, but there are unfinished subgoals:"
+            }
+            GoalCategory::GivenUp => {
+                "There are no more subgoals, but there are some given up goals:"
+            }
+            GoalCategory::Shelved => "There are no more subgoals, but some goals remain shelved:",
+        });
+        buffer.push_goals(goals);
+    } else {
+        buffer.push_line("There are no more subgoals.\nProof is complete.");
+    }
+
+    let summary = counts
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(category, count)| format!("{}: {}", category_name(*category), count))
+        .collect::<Vec<_>>();
+    if !summary.is_empty() {
+        buffer.push_line("");
+        buffer.push_line(format!("({})", summary.join(", ")));
+    }
+
+    for (category, goals) in categories {
+        if goals.is_empty() || !expanded.contains(&category) {
+            continue;
+        }
+
+        buffer.push_line("");
+        buffer.push_line(match category {
+            GoalCategory::Background => "Unfocused goals:",
+            GoalCategory::Shelved => "Shelved goals:",
+            GoalCategory::GivenUp => "Given up goals:",
+        });
+        buffer.push_goals(goals);
+    }
+
+    let GoalBuffer {
+        message, colors, ..
+    } = buffer;
+    let colors = color_specs(&message, 1, colors);
+    (message, colors)
+}
+
+/// The maximum number of columns taken on screen by a result shown inline.
+/// The full result is still available in the result buffer.
+const MAX_INLINE_RESULT_LENGTH: u64 = 120;
+
+/// Creates the `replace-ranges` specification which inserts the given message right after a statement.
+///
+/// The message is truncated to [`MAX_INLINE_RESULT_LENGTH`] columns, and escaped so that it can be put inside a `'`-quoted string nested in a `%§…§` string.
+fn inline_result_spec(range: Range, message: &str) -> String {
+    let message = if ColumnUnit::Display.length(message) > MAX_INLINE_RESULT_LENGTH {
+        let mut length = 0;
+        message
+            .chars()
+            .take_while(|c| {
+                length += ColumnUnit::Display.width(*c);
+                length < MAX_INLINE_RESULT_LENGTH
+            })
+            .chain(std::iter::once('…'))
+            .collect()
+    } else {
+        message.to_string()
+    };
+    let message = message
+        .replace('\\', "\\\\")
+        .replace('{', "\\{")
+        .replace('\'', "''")
+        .replace('§', "§§");
+
+    format!(
+        "{}.{}+0| {{coqide_inline_result_face}}{}",
+        range.end.line,
+        range.end.column + 1,
+        message
+    )
+}
+
+/// The textual content of the goal buffer, along with its colors.
+struct GoalBuffer {
+    message: String,
+    colors: Vec<(Range, String)>,
+    /// The line where the next piece of text will be inserted.
+    line: usize,
+}
+
+impl GoalBuffer {
+    fn new() -> Self {
+        Self {
+            message: String::new(),
+            colors: Vec::new(),
+            line: 1,
+        }
+    }
+
+    /// Append some uncolored text, followed by a new line.
+    fn push_line<S: AsRef<str>>(&mut self, txt: S) {
+        let txt = txt.as_ref();
+
+        self.message += txt;
+        self.message += "\n";
+        self.line += txt.matches('\n').count() + 1;
+    }
+
+    /// Append all the given goals, each one preceded by an empty line.
+    fn push_goals(&mut self, goals: Vec<Goal>) {
+        for goal in goals {
+            self.push_line("");
+
+            let (txt, mut cols, line) = goal_to_string(goal, self.line);
+            self.message += txt.as_str();
+            self.colors.append(&mut cols);
+            self.line = line;
+        }
+    }
+}
+
+/// Retrieves the name of a category of goals, as displayed in the goal buffer.
+fn category_name(category: GoalCategory) -> &'static str {
+    match category {
+        GoalCategory::Background => "unfocused",
+        GoalCategory::Shelved => "shelved",
+        GoalCategory::GivenUp => "given up",
+    }
+}
+
 /// Extract the message and the colors from a [`ProtocolRichPP`] starting at the given line number.
+///
+/// The columns of the colors are counted in characters, until the whole content of their buffer is known
+/// (see [`color_specs`]).
 fn extract_colors(
     richpp: ProtocolRichPP,
     starting_line: usize,
     starting_column: usize,
     align: bool,
-) -> (String, Vec<String>) {
+) -> (String, Vec<(Range, String)>) {
     let ProtocolRichPP::RichPP(parts) = richpp;
     let mut message = String::new();
     let mut colors = Vec::new();
@@ -334,7 +609,7 @@ fn extract_colors(
                         current_line += 1;
                         current_column = if align { starting_column } else { 1 };
                     } else {
-                        current_column += 1;
+                        current_column += ColumnUnit::Char.width(c) as usize;
                     }
                 }
 
@@ -355,27 +630,32 @@ fn extract_colors(
             | ProtocolRichPPPart::Warning(txt) => {
                 let begin_column = current_column;
                 let begin_line = current_line;
+                // Range-specs end on the last character.
+                let mut end = (current_line, current_column - 1);
 
                 for c in txt.chars() {
+                    end = (current_line, current_column);
                     if c == '\n' {
                         current_line += 1;
                         current_column = if align { starting_column } else { 1 };
                     } else {
-                        current_column += 1;
+                        current_column += ColumnUnit::Char.width(c) as usize;
                     }
                 }
 
                 let tmp = format!("\n{}", " ".repeat(starting_column - 1));
                 message += txt.replace("\n", tmp.as_str()).as_str();
 
-                Some(format!(
-                    "{}|coqide_{}",
-                    Range::new(
-                        begin_line as u64,
-                        begin_column as u64,
-                        current_line as u64,
-                        (current_column - 1) as u64
-                    ),
+                Some((
+                    Range {
+                        unit: ColumnUnit::Char,
+                        ..Range::new(
+                            begin_line as u64,
+                            begin_column as u64,
+                            end.0 as u64,
+                            end.1 as u64,
+                        )
+                    },
                     color_name,
                 ))
             }
@@ -389,6 +669,30 @@ fn extract_colors(
     (message, colors)
 }
 
+/// Formats the colors of a buffer as range-specs, given its content starting at the given line number.
+///
+/// Kakoune counts the columns of range-specs in bytes, so they can only be converted once the lines
+/// they are on are known.
+fn color_specs(content: &str, starting_line: usize, colors: Vec<(Range, String)>) -> Vec<String> {
+    let lines = content.lines().collect::<Vec<_>>();
+
+    colors
+        .into_iter()
+        .filter_map(|(range, color_name)| {
+            range
+                .convert(ColumnUnit::Byte, |line| {
+                    (line as usize)
+                        .checked_sub(starting_line)
+                        .and_then(|index| lines.get(index))
+                        .copied()
+                        .unwrap_or_default()
+                })
+                .spec()
+                .map(|spec| format!("{}|coqide_{}", spec, color_name))
+        })
+        .collect()
+}
+
 /// Retrieves the name of the color corresponding to a RichPP node.
 fn color_name(part: &ProtocolRichPPPart) -> String {
     match part {
@@ -406,42 +710,61 @@ fn color_name(part: &ProtocolRichPPPart) -> String {
     .to_string()
 }
 
-/// Transforms a [`ProtocolValue::Goal`] into its colored textual representation.
-fn goal_to_string(goal: ProtocolValue, mut line: usize) -> (String, Vec<String>, usize) {
-    if let ProtocolValue::Goal(box ProtocolValue::Str(name), hyps, ccl) = goal {
-        let mut message = String::new();
-        let mut colors = Vec::new();
+/// Transforms a [`Goal`] into its colored textual representation.
+fn goal_to_string(goal: Goal, mut line: usize) -> (String, Vec<(Range, String)>, usize) {
+    let Goal {
+        name,
+        hypotheses: hyps,
+        conclusion: ccl,
+        user_name,
+    } = goal;
+    let title = match user_name {
+        Some(user_name) => format!("({}) [{}]", name, user_name),
+        None => format!("({})", name),
+    };
 
-        let mut max_size = 0usize;
+    let mut message = String::new();
+    let mut colors = Vec::new();
 
-        for hyp in hyps {
-            let (msg, mut cols) = extract_colors(hyp, line, 2usize, true);
-            line += 1;
+    let mut max_size = 0usize;
 
-            max_size = max_size.max(msg.lines().map(|l| l.len()).max().unwrap_or(0) + 2);
+    for hyp in hyps {
+        let (msg, mut cols) = extract_colors(hyp, line, 2usize, true);
+        line += 1;
 
-            message = if message.is_empty() {
-                format!(" {} ", msg)
-            } else {
-                format!("{}\n {} ", message, msg)
-            };
-            colors.append(&mut cols);
-        }
-        let (msg, mut cols) = extract_colors(ccl, line + 1, 2usize, true);
+        max_size = max_size.max(
+            msg.lines()
+                .map(|l| ColumnUnit::Display.length(l) as usize)
+                .max()
+                .unwrap_or(0)
+                + 2,
+        );
 
-        max_size = max_size.max(msg.lines().map(|l| l.len()).max().unwrap_or(0) + 2);
-        let middle_line = "─".repeat(max_size);
         message = if message.is_empty() {
-            line += 2;
-            format!("{} ({})\n {} \n", middle_line, name, msg)
+            format!(" {} ", msg)
         } else {
-            line += 2;
-            format!("{}\n{} ({})\n {} \n", message, middle_line, name, msg)
+            format!("{}\n {} ", message, msg)
         };
         colors.append(&mut cols);
-
-        (message, colors, line)
-    } else {
-        unreachable!()
     }
+    let (msg, mut cols) = extract_colors(ccl, line + 1, 2usize, true);
+
+    max_size = max_size.max(
+        msg.lines()
+            .map(|l| ColumnUnit::Display.length(l) as usize)
+            .max()
+            .unwrap_or(0)
+            + 2,
+    );
+    let middle_line = "─".repeat(max_size);
+    message = if message.is_empty() {
+        line += 2;
+        format!("{} {}\n {} \n", middle_line, title, msg)
+    } else {
+        line += 2;
+        format!("{}\n{} {}\n {} \n", message, middle_line, title, msg)
+    };
+    colors.append(&mut cols);
+
+    (message, colors, line)
 }
@@ -1,10 +1,16 @@
 #![feature(box_patterns)]
 
 pub mod client;
+pub mod config;
 pub mod coqtop;
+pub mod error;
+pub mod error_state;
 pub mod files;
 pub mod kakoune;
 pub mod logger;
+pub mod manager;
+pub mod options;
 pub mod range;
 pub mod session;
 pub mod state;
+pub mod worker;
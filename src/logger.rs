@@ -14,10 +14,11 @@ use log4rs::{
 ///
 /// This logger dumps all messages whose level are greater than `WARN` to both
 /// stderr and the logfile, and all other messages only to the log file.
+/// Messages less important than the given `level` are dropped.
 ///
 /// If this behavior is not expected (e.g. you might want all messages on stderr too),
 /// the [`Handle`] returned allows for later customisations.
-pub fn init<P: AsRef<Path>>(path: P) -> io::Result<Handle> {
+pub fn init<P: AsRef<Path>>(path: P, level: LevelFilter) -> io::Result<Handle> {
     let on_console = ConsoleAppender::builder()
         .encoder(Box::new(PatternEncoder::new(
             "{d(%Y-%m-%d %H:%M:%S)} {M} {h([{l}])} {m}{n}",
@@ -33,19 +34,19 @@ pub fn init<P: AsRef<Path>>(path: P) -> io::Result<Handle> {
     let config = Config::builder()
         .appender(
             Appender::builder()
-                .filter(Box::new(ThresholdFilter::new(LevelFilter::Debug)))
+                .filter(Box::new(ThresholdFilter::new(level)))
                 .build("stderr", Box::new(on_console)),
         )
         .appender(
             Appender::builder()
-                .filter(Box::new(ThresholdFilter::new(LevelFilter::Debug)))
+                .filter(Box::new(ThresholdFilter::new(level)))
                 .build("log", Box::new(in_log_file)),
         )
         .build(
             Root::builder()
                 .appender("log")
                 .appender("stderr")
-                .build(LevelFilter::Debug),
+                .build(level),
         )
         .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))?;
 
@@ -0,0 +1,364 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd, process::CommandExt},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_signals::Signals;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
+    sync::{mpsc, Notify},
+};
+use tokio_stream::StreamExt;
+
+use crate::{
+    config::Config,
+    coqtop::{
+        coqidetop::SHUTDOWN_SIGNALS,
+        pool::Pool,
+        project::{Location, ProjectCache},
+    },
+    files::{log_file, manager_lock, manager_socket},
+    options::Options,
+    session::edited_file,
+    worker::{self, Managed, Shared},
+};
+
+/// How many times a buffer tries to reach the daemon of its project, while it is starting.
+const ATTEMPTS: usize = 50;
+
+/// How long a buffer waits before trying again to reach the daemon of its project.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the daemon of a project waits for a first buffer before quitting.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The folder of the daemon serving all the buffers of a project in a Kakoune session, which contains its
+/// socket and its log file.
+///
+/// It is named after a hash of the project file which does not depend on the build, so that buffers started
+/// by different versions of the daemon still find each other.
+pub fn manager_dir(session: &str, location: &Location) -> String {
+    std::env::temp_dir()
+        .join(format!(
+            "coqide-{}-{:016x}",
+            session,
+            fnv1a(location.path().as_os_str().as_bytes())
+        ))
+        .display()
+        .to_string()
+}
+
+/// The 64-bit FNV-1a hash of some bytes.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A buffer served by the daemon of its project.
+///
+/// The current process stands for the buffer until the daemon stops serving it, so that Kakoune can still
+/// interrupt it with `SIGUSR1`, and stop it with any of the [`SHUTDOWN_SIGNALS`].
+pub struct Attached {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Attached {
+    /// Asks the daemon in the given folder to serve the buffer described by the command-line arguments,
+    /// starting this daemon if it is not running yet.
+    pub async fn connect(dir: &str, args: &[String]) -> io::Result<Self> {
+        let socket = manager_socket(dir);
+        let mut spawned = false;
+
+        for _ in 0..ATTEMPTS {
+            match UnixStream::connect(&socket).await {
+                Ok(stream) => {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+
+                    let request = serde_json::to_string(args)?;
+                    writer
+                        .write_all(format!("{}\n", request).as_bytes())
+                        .await?;
+                    match lines.next_line().await? {
+                        Some(line) if line == "ok" => return Ok(Self { lines, writer }),
+                        Some(line) => return Err(io::Error::other(line)),
+                        // NOTE: the daemon was quitting, and another one must be started
+                        None => spawned = false,
+                    }
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                    ) =>
+                {
+                    // NOTE: a refused connection may come from a socket left behind by a daemon which did not
+                    //       quit cleanly, but also from a daemon which is not listening yet, so only the daemon
+                    //       holding the lock of the folder may remove it (see `serve`)
+                    if !spawned {
+                        spawn(dir, args)?;
+                        spawned = true;
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Cannot reach the daemon at {}", socket),
+        ))
+    }
+
+    /// Forwards interruptions and shutdowns to the daemon, until it stops serving the buffer.
+    pub async fn wait(mut self) -> io::Result<()> {
+        let mut signals = Signals::new([&[libc::SIGUSR1][..], &SHUTDOWN_SIGNALS].concat())?;
+
+        loop {
+            tokio::select! {
+                Some(signal) = signals.next() => {
+                    let control = match signal {
+                        libc::SIGUSR1 => "interrupt",
+                        _ => "quit",
+                    };
+                    log::debug!("Received signal {}, sending `{}` to the daemon", signal, control);
+
+                    self.writer.write_all(format!("{}\n", control).as_bytes()).await?;
+                }
+                line = self.lines.next_line() => match line? {
+                    Some(line) => log::warn!("Unexpected message from the daemon: {:?}", line),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Starts a daemon serving all the buffers attaching to the socket in the given folder, with the settings of the
+/// first buffer.
+fn spawn(dir: &str, args: &[String]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    // NOTE: the daemon is not part of the process group of the buffer, so that it outlives it
+    let child = std::process::Command::new(std::env::current_exe()?)
+        .arg("--serve")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    log::info!("Started daemon {} in {}", child.id(), dir);
+    Ok(())
+}
+
+/// Serves all the buffers attaching to the socket in the given folder, until none is left.
+///
+/// At most [`Config::max_coqtops`] `coqidetop` processes run at the same time, and projects are only read once.
+pub async fn serve(dir: &str, config: &Config) -> io::Result<()> {
+    let socket = manager_socket(dir);
+    // NOTE: the daemon holding the lock may be quitting, in which case this one takes over
+    let mut held = lock(dir)?;
+    for _ in 1..ATTEMPTS {
+        if held.is_some() {
+            break;
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+        held = lock(dir)?;
+    }
+    let held = match held {
+        Some(held) => held,
+        None => {
+            log::info!("Another daemon is already serving buffers at {}", socket);
+            return Ok(());
+        }
+    };
+    // NOTE: no other daemon is running, so any socket is left behind by one which did not quit cleanly
+    match fs::remove_file(&socket) {
+        Ok(()) => log::info!("Removed the stale socket {}", socket),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = UnixListener::bind(&socket)?;
+    log::info!("Serving buffers at {}", socket);
+
+    let shared = Arc::new(Shared {
+        projects: ProjectCache::default(),
+        pool: Pool::new(config.max_coqtops.get()),
+    });
+    let mut signals = Signals::new(SHUTDOWN_SIGNALS)?;
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let mut next_id = 0;
+    let mut served = 0;
+    let mut stopping = false;
+
+    let res = loop {
+        tokio::select! {
+            accepted = listener.accept(), if !stopping => match accepted {
+                Ok((stream, _)) => {
+                    next_id += 1;
+                    served += 1;
+
+                    let (id, shared, done_tx) = (next_id, shared.clone(), done_tx.clone());
+                    tokio::spawn(async move {
+                        serve_buffer(id, stream, shared).await;
+                        let _ = done_tx.send(id);
+                    });
+                }
+                Err(err) => break Err(err),
+            },
+            Some(id) = done_rx.recv() => {
+                log::debug!("Buffer {} is not served anymore", id);
+
+                served -= 1;
+                if served == 0 {
+                    log::info!("No buffer left, quitting");
+                    break Ok(());
+                }
+            }
+            Some(signal) = signals.next() => {
+                // NOTE: all the buffers receive the signal too, and quit on their own
+                log::info!("Received signal {}, quitting once all buffers are stopped", signal);
+
+                stopping = true;
+                if served == 0 {
+                    break Ok(());
+                }
+            }
+            _ = tokio::time::sleep(IDLE_TIMEOUT), if served == 0 => {
+                log::info!("No buffer has attached, quitting");
+                break Ok(());
+            }
+        }
+    };
+
+    // NOTE: the socket must be removed before not listening anymore, so that buffers attaching in the meantime
+    //       start a new daemon instead of finding this one gone, and the lock file before releasing it, so that
+    //       this new daemon does not lock a file which is about to be removed
+    for path in [socket, log_file(dir), manager_lock(dir)] {
+        if let Err(err) = fs::remove_file(&path) {
+            log::warn!("Cannot remove {}: {}", path, err);
+        }
+    }
+    drop(listener);
+    drop(held);
+    if let Err(err) = fs::remove_dir(dir) {
+        log::debug!("Cannot remove {}: {}", dir, err);
+    }
+
+    res
+}
+
+/// Takes the lock of the given folder, which is held by the daemon serving buffers there for as long as it runs.
+///
+/// [`None`] is returned if another daemon holds it.
+fn lock(dir: &str) -> io::Result<Option<File>> {
+    let path = manager_lock(dir);
+
+    loop {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        // NOTE: the lock file may have been removed by the previous daemon right before being unlocked
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.ino() == file.metadata()?.ino() => return Ok(Some(file)),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Serves the buffer described by the first line received on a connection, and forwards the controls received
+/// on the following lines.
+async fn serve_buffer(id: usize, stream: UnixStream, shared: Arc<Shared>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let attached = match lines.next_line().await {
+        Ok(Some(line)) => attach(&line),
+        Ok(None) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        Err(err) => Err(err),
+    };
+    let (options, config) = match attached {
+        Ok(attached) => attached,
+        Err(err) => {
+            log::error!("Cannot serve buffer {}: {}", id, err);
+            let _ = writer
+                .write_all(format!("Invalid request: {}\n", err).as_bytes())
+                .await;
+            return;
+        }
+    };
+    if let Err(err) = writer.write_all(b"ok\n").await {
+        log::error!("Cannot serve buffer {}: {}", id, err);
+        return;
+    }
+
+    let session = options.session();
+    let managed = Managed {
+        id,
+        shared,
+        interrupt: Arc::new(Notify::new()),
+        quit: Arc::new(Notify::new()),
+        release: Arc::new(Notify::new()),
+    };
+    log::info!("Serving {} as buffer {}", edited_file(session.clone()), id);
+
+    let (interrupt, quit) = (managed.interrupt.clone(), managed.quit.clone());
+    let controls = tokio::spawn(async move {
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) if line == "interrupt" => interrupt.notify_waiters(),
+                Ok(Some(line)) if line == "quit" => break,
+                Ok(Some(line)) => log::warn!("Unknown control {:?} for buffer {}", line, id),
+                // NOTE: the buffer is not served anymore if its process has been killed
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        interrupt.notify_waiters();
+        quit.notify_one();
+    });
+
+    let res = worker::run(session.clone(), config, Some(managed)).await;
+    log::debug!("Result for buffer {}: {:?}", id, res);
+
+    controls.abort();
+    worker::cleanup(session).await;
+}
+
+/// Reads the command-line arguments of a buffer, sent as a JSON array of strings.
+fn attach(request: &str) -> io::Result<(Options, Arc<Config>)> {
+    let args = serde_json::from_str::<Vec<String>>(request)?;
+    let options = Options::try_parse_forwarded(args)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+    let config = Config::load(&options)?;
+
+    Ok((options, config))
+}
@@ -0,0 +1,168 @@
+use std::{net::SocketAddr, num::NonZeroUsize, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
+use log::LevelFilter;
+
+use crate::{coqtop::backend::BackendKind, session::Session};
+
+/// All the options `coqide-daemon` can be given on the command-line.
+///
+/// Some of them fall back to environment variables when they are not given explicitly.
+/// Settings which are not given at all are taken from the configuration file (see [`Config`]).
+///
+/// [`Config`]: crate::config::Config
+#[derive(Clone, Debug, Parser)]
+#[command(
+    name = "coqide-daemon",
+    version,
+    about = "A bridge between Kakoune and coqidetop",
+    long_about = None
+)]
+pub struct Options {
+    /// The name of the Kakoune client the daemon has been started in.
+    #[arg(long, value_name = "NAME")]
+    pub client: String,
+    /// The identifier of the Kakoune session.
+    #[arg(long, value_name = "ID")]
+    pub session: String,
+    /// The Coq file being edited.
+    #[arg(long, value_name = "PATH")]
+    pub file: String,
+    /// The temporary folder containing the goal, result and log files.
+    #[arg(long, value_name = "DIR")]
+    pub tmp_dir: String,
+    /// The UNIX socket to listen to for commands.
+    #[arg(long, value_name = "PATH")]
+    pub socket: String,
+
+    /// The configuration file to read settings from, instead of looking for a `.coqide.toml` in the
+    /// ancestors of the edited file.
+    #[arg(long, value_name = "PATH", env = "COQIDE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Do not read any configuration file.
+    #[arg(long, conflicts_with = "config")]
+    pub no_config: bool,
+
+    /// The prover to talk to (`coqidetop` or `sertop`) [default: coqidetop].
+    #[arg(long, value_name = "PROVER", env = "COQIDE_BACKEND")]
+    pub backend: Option<BackendKind>,
+    /// The executable of the prover to run [default: the name of the prover].
+    #[arg(long, value_name = "PATH", env = "COQTOP")]
+    pub coqtop: Option<String>,
+    /// Only log messages at least as important as this level (one of `off`, `error`, `warn`, `info`,
+    /// `debug` or `trace`) [default: debug].
+    #[arg(long, value_name = "LEVEL", env = "COQIDE_LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    /// The project file to take flags from, instead of looking for a `_CoqProject` (or a `dune-project`)
+    /// in the ancestors of the edited file.
+    #[arg(long, value_name = "PATH", env = "COQIDE_PROJECT_FILE")]
+    pub project_file: Option<PathBuf>,
+    /// Do not take flags from any project file.
+    #[arg(long, conflicts_with = "project_file")]
+    pub no_project_file: bool,
+    /// In dune projects, run `coqidetop` through `dune coq top` to get the exact flags dune would use.
+    #[arg(long, env = "COQIDE_DUNE_COQ_TOP")]
+    pub dune_coq_top: bool,
+    /// Do not restart `coqidetop` when the project files are modified.
+    #[arg(long)]
+    pub no_watch_project: bool,
+    /// Share a single daemon (and its `coqidetop` processes) between all the buffers of a project.
+    #[arg(long, env = "COQIDE_MANAGER")]
+    pub manager: bool,
+    /// When sharing a daemon, the maximum number of `coqidetop` processes running at the same time
+    /// [default: 4].
+    #[arg(long, value_name = "N", env = "COQIDE_MAX_COQTOPS")]
+    pub max_coqtops: Option<NonZeroUsize>,
+    /// Talk to `coqidetop` over TCP connections on the loopback interface, instead of its standard input and
+    /// output.
+    #[arg(long, env = "COQIDE_TCP")]
+    pub tcp: bool,
+    /// Do not start `coqidetop`, but wait for one started elsewhere (e.g. through an SSH tunnel) to connect to
+    /// this address and the three next ports.
+    #[arg(long, value_name = "ADDRESS", env = "COQIDE_ATTACH")]
+    pub attach: Option<SocketAddr>,
+    /// Serve all the buffers attaching to the socket in this folder (this is how a shared daemon is started).
+    #[arg(long, value_name = "DIR", hide = true)]
+    pub serve: Option<String>,
+    /// Interrupt any Coq statement which takes longer than this number of seconds to be processed.
+    #[arg(long, value_name = "SECONDS", env = "COQIDE_TIMEOUT", value_parser = parse_seconds)]
+    pub timeout: Option<Duration>,
+
+    /// Additional flags given as is to `coqidetop`, after those coming from the project and configuration
+    /// files.
+    #[arg(last = true, value_name = "COQ_FLAGS")]
+    pub coq_flags: Vec<String>,
+}
+
+impl Options {
+    /// Creates the session described by the options.
+    pub fn session(&self) -> Arc<Session> {
+        Session::new(
+            self.client.clone(),
+            self.session.clone(),
+            self.file.clone(),
+            self.tmp_dir.clone(),
+            self.socket.clone(),
+        )
+    }
+
+    /// The command-line arguments of a buffer (without the name of the program), with the options taken from
+    /// environment variables given explicitly.
+    ///
+    /// A daemon shared between buffers (see [`Options::manager`]) runs in the environment of the buffer which
+    /// started it, so it must not resolve the options of the other buffers in its own environment
+    /// (see [`Options::try_parse_forwarded`]).
+    pub fn forwarded_args(args: &[String]) -> Vec<String> {
+        let command = Self::command();
+        let matches = match command.clone().try_get_matches_from(
+            ["coqide-daemon".to_string()]
+                .into_iter()
+                .chain(args.to_vec()),
+        ) {
+            Ok(matches) => matches,
+            // NOTE: the daemon reports the error to the buffer anyway
+            Err(_) => return args.to_vec(),
+        };
+
+        let mut forwarded = vec![];
+        for arg in command.get_arguments() {
+            let id = arg.get_id().as_str();
+            let value = arg.get_env().and_then(|name| std::env::var(name).ok());
+
+            if let (Some(ValueSource::EnvVariable), Some(long), Some(value)) =
+                (matches.value_source(id), arg.get_long(), value)
+            {
+                if arg.get_action().takes_values() {
+                    forwarded.push(format!("--{}={}", long, value));
+                } else if matches.get_flag(id) {
+                    forwarded.push(format!("--{}", long));
+                }
+            }
+        }
+
+        forwarded.extend_from_slice(args);
+        forwarded
+    }
+
+    /// Reads the command-line arguments given by [`Options::forwarded_args`], without falling back to
+    /// environment variables.
+    pub fn try_parse_forwarded(args: Vec<String>) -> Result<Self, clap::Error> {
+        let matches = Self::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(["coqide-daemon".to_string()].into_iter().chain(args))?;
+
+        Self::from_arg_matches(&matches)
+    }
+}
+
+fn parse_seconds(text: &str) -> Result<Duration, String> {
+    text.parse::<f64>()
+        .ok()
+        .and_then(seconds)
+        .ok_or_else(|| format!("`{}` is not a positive number of seconds", text))
+}
+
+/// Converts a positive number of seconds to a [`Duration`].
+pub(crate) fn seconds(seconds: f64) -> Option<Duration> {
+    (seconds.is_finite() && seconds > 0.0).then(|| Duration::from_secs_f64(seconds))
+}
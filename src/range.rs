@@ -1,16 +1,298 @@
 use std::fmt;
 
-#[derive(Clone, Debug, Copy)]
+use unicode_width::UnicodeWidthChar;
+
+/// The unit in which the columns of a [`Range`] are counted.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum ColumnUnit {
+    /// Bytes of the UTF-8 encoding, as in Kakoune's range-specs and in Coq's locations.
+    #[default]
+    Byte,
+    /// Unicode scalar values, as yielded by [`str::chars`].
+    Char,
+    /// Cells of the screen, as displayed by Kakoune (where tabulations only take a single cell).
+    Display,
+}
+
+impl ColumnUnit {
+    /// The number of columns taken by a single character.
+    pub fn width(self, c: char) -> u64 {
+        match self {
+            Self::Byte => c.len_utf8() as u64,
+            Self::Char => 1,
+            Self::Display => UnicodeWidthChar::width(c).unwrap_or(1) as u64,
+        }
+    }
+
+    /// The number of columns taken by a whole piece of text.
+    pub fn length(self, text: &str) -> u64 {
+        text.chars().map(|c| self.width(c)).sum()
+    }
+
+    /// Converts a (1-based) column of the given line to another unit.
+    ///
+    /// Columns falling inside a character are moved to its beginning, and columns past the end of the line
+    /// are kept as far from its end.
+    /// Column 0, right before the line (e.g. the end of an empty range-spec at its beginning), is kept as is.
+    pub fn convert(self, line: &str, column: u64, to: ColumnUnit) -> u64 {
+        if self == to || column == 0 {
+            return column;
+        }
+
+        let (mut from_column, mut to_column) = (1, 1);
+        for c in line.chars() {
+            if from_column + self.width(c) > column {
+                return to_column;
+            }
+            from_column += self.width(c);
+            to_column += to.width(c);
+        }
+
+        to_column + (column - from_column)
+    }
+}
+
+/// A position in a buffer, where both lines and columns start at 1.
+///
+/// Positions are ordered as they appear in the buffer.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line: u64,
+    pub column: u64,
+}
+
+impl Position {
+    pub fn new(line: u64, column: u64) -> Self {
+        Self { line, column }
+    }
+
+    /// The position right after this one on the same line, when columns are counted in bytes
+    /// and this position is on an ASCII character (as the ends of Coq statements always are).
+    fn next_byte(self) -> Self {
+        Self::new(self.line, self.column + 1)
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self::new(1, 1)
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.line, self.column)
+    }
+}
+
+/// A modification of the buffer, which replaced the text from `begin` up to `removed_end` (excluded)
+/// with some text which now ends at `inserted_end` (excluded).
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub struct Edit {
+    pub begin: Position,
+    pub removed_end: Position,
+    pub inserted_end: Position,
+}
+
+impl Edit {
+    /// An edit starting at the given position, whose extent is unknown.
+    ///
+    /// Everything after its beginning is considered to have been modified.
+    pub fn unknown_from(begin: Position) -> Self {
+        let end = Position::new(u64::MAX, u64::MAX);
+
+        Self {
+            begin,
+            removed_end: end,
+            inserted_end: end,
+        }
+    }
+
+    /// Moves a position after the edit to where it is once the edit has been made.
+    ///
+    /// Positions before the edit are left untouched, and [`None`] is returned for those which have been removed.
+    pub fn shift(&self, position: Position) -> Option<Position> {
+        if position < self.begin {
+            Some(position)
+        } else if position < self.removed_end {
+            None
+        } else if position.line == self.removed_end.line {
+            Some(Position::new(
+                self.inserted_end.line,
+                self.inserted_end.column + (position.column - self.removed_end.column),
+            ))
+        } else {
+            Some(Position::new(
+                position.line - self.removed_end.line + self.inserted_end.line,
+                position.column,
+            ))
+        }
+    }
+}
+
+/// A range of a buffer, from its first character to its last character (both included).
+///
+/// Ranges are ordered by their beginnings, then by their ends.
+/// They are only equal when their columns are counted in the same unit and they were computed at the same
+/// timestamp, as the same positions may designate other text in another unit or once the buffer has been
+/// edited (see [`State::translate`]).
+///
+/// [`State::translate`]: crate::state::State::translate
+#[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Range {
-    pub begin: (u64, u64),
-    pub end: (u64, u64),
+    pub begin: Position,
+    pub end: Position,
+    /// The unit in which both columns are counted.
+    pub unit: ColumnUnit,
+    /// The timestamp of the Kakoune buffer this range was computed at (`0` if unknown).
+    pub timestamp: u64,
 }
 
 impl Range {
+    /// Creates a new range whose columns are counted in bytes.
     pub fn new(begin_line: u64, begin_column: u64, end_line: u64, end_column: u64) -> Self {
         Self {
-            begin: (begin_line, begin_column),
-            end: (end_line, end_column),
+            begin: Position::new(begin_line, begin_column),
+            end: Position::new(end_line, end_column),
+            unit: ColumnUnit::Byte,
+            timestamp: 0,
+        }
+    }
+
+    /// The range from the given position to the end of the buffer, however long it is.
+    pub fn rest_from(begin: Position) -> Self {
+        Self {
+            begin,
+            end: Position::new(u64::MAX, u64::MAX),
+            ..Self::default()
+        }
+    }
+
+    /// The same range, but computed at the given timestamp of the buffer.
+    pub fn at(self, timestamp: u64) -> Self {
+        Self { timestamp, ..self }
+    }
+
+    /// The same range, but with its beginning before its end (e.g. for reversed Kakoune selections).
+    pub fn normalized(self) -> Self {
+        Self {
+            begin: self.begin.min(self.end),
+            end: self.begin.max(self.end),
+            ..self
+        }
+    }
+
+    /// Is the given position inside this range?
+    pub fn contains(&self, position: Position) -> bool {
+        self.begin <= position && position <= self.end
+    }
+
+    /// Do both ranges have at least one position in common?
+    pub fn intersects(&self, other: &Range) -> bool {
+        self.begin <= other.end && other.begin <= self.end
+    }
+
+    /// The smallest range containing both ranges.
+    pub fn union(self, other: Range) -> Self {
+        Self {
+            begin: self.begin.min(other.begin),
+            end: self.end.max(other.end),
+            ..self
+        }
+    }
+
+    /// Splits the range right after the given position, returning the parts before and after it if they are not empty.
+    ///
+    /// Columns must be counted in bytes, and `position` must be on an ASCII character, like the ends of Coq statements.
+    pub fn split_at(self, position: Position) -> (Option<Self>, Option<Self>) {
+        let before = (position >= self.begin).then(|| Self {
+            end: position.min(self.end),
+            ..self
+        });
+        let after = (position < self.end).then(|| Self {
+            begin: position.next_byte().max(self.begin),
+            ..self
+        });
+
+        (before, after)
+    }
+
+    /// The same range, once the given edit has been made in the buffer.
+    ///
+    /// [`None`] is returned if the edit modified the range itself.
+    pub fn shift(self, edit: &Edit) -> Option<Self> {
+        if self.end < edit.begin {
+            return Some(self);
+        }
+        if self.begin < edit.removed_end && edit.begin <= self.end {
+            return None;
+        }
+
+        Some(Self {
+            begin: edit.shift(self.begin)?,
+            end: edit.shift(self.end)?,
+            ..self
+        })
+    }
+
+    /// The same range, with its columns counted in another unit.
+    ///
+    /// `line` must return the content of the given line of the buffer.
+    pub fn convert<'a, F>(self, to: ColumnUnit, line: F) -> Self
+    where
+        F: Fn(u64) -> &'a str,
+    {
+        let convert = |position: Position| {
+            Position::new(
+                position.line,
+                self.unit.convert(line(position.line), position.column, to),
+            )
+        };
+
+        Self {
+            begin: convert(self.begin),
+            end: convert(self.end),
+            unit: to,
+            ..self
+        }
+    }
+
+    /// The range as in Kakoune's range-specs, if its columns are counted in bytes.
+    ///
+    /// Ranges counted in other units must be converted first (see [`Range::convert`]), as their columns
+    /// would designate other text.
+    pub fn spec(self) -> Option<RangeSpec> {
+        (self.unit == ColumnUnit::Byte).then_some(RangeSpec(self))
+    }
+
+    /// The part of this range spanning the bytes `start..stop` of the given text, which starts
+    /// at the beginning of the range (e.g. the location of an error in a statement, as reported by Coq).
+    ///
+    /// The columns of this range must be counted in bytes.
+    pub fn sub_range(self, text: &str, start: usize, stop: usize) -> Self {
+        debug_assert_eq!(self.unit, ColumnUnit::Byte);
+
+        let mut position = self.begin;
+        let (mut begin, mut end) = (None, None);
+        for (i, c) in text.char_indices() {
+            if i >= stop {
+                break;
+            }
+            if i >= start {
+                begin.get_or_insert(position);
+                end = Some(position);
+            }
+
+            if c == '\n' {
+                position = Position::new(position.line + 1, 1);
+            } else {
+                position.column += ColumnUnit::Byte.width(c);
+            }
+        }
+
+        match (begin, end) {
+            (Some(begin), Some(end)) => Self { begin, end, ..self },
+            _ => self,
         }
     }
 }
@@ -18,18 +300,21 @@ impl Range {
 impl Default for Range {
     fn default() -> Self {
         Self {
-            begin: (1, 1),
-            end: (1, 1),
+            begin: Position::default(),
+            end: Position::default(),
+            unit: ColumnUnit::Byte,
+            timestamp: 0,
         }
     }
 }
 
-impl fmt::Display for Range {
+/// A range whose columns are counted in bytes, which can be formatted as in Kakoune's range-specs
+/// (see [`Range::spec`]).
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub struct RangeSpec(Range);
+
+impl fmt::Display for RangeSpec {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}.{},{}.{}",
-            self.begin.0, self.begin.1, self.end.0, self.end.1
-        )
+        write!(f, "{},{}", self.0.begin, self.0.end)
     }
 }
@@ -1,11 +1,21 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 
-use crate::range::Range;
+use crate::{
+    coqtop::xml_protocol::types::ProtocolRichPP,
+    error_state::ErrorStateMachine,
+    range::{Edit, Position, Range},
+};
 
 #[derive(Clone, Debug)]
 pub struct Operation {
     pub state_id: i64,
     pub range: Range,
+    /// Is this a query (e.g. `Check` or `Compute`) whose results should be shown inline?
+    pub is_query: bool,
+    /// Has this statement added an axiom (e.g. an admitted proof)?
+    pub has_axiom: bool,
+    /// The code of the statement, to process it again when `coqidetop` is restarted.
+    pub code: String,
 }
 
 impl Default for Operation {
@@ -13,29 +23,218 @@ impl Default for Operation {
         Self {
             state_id: 1,
             range: Range::default(),
+            is_query: false,
+            has_axiom: false,
+            code: String::new(),
         }
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub enum ErrorState {
-    Ok,
-    Error,
-    Interrupted,
+/// All the operations known by `coqidetop`, in the order they were processed.
+///
+/// Statements are processed one after the other, and `coqidetop` gives increasing state IDs to new states,
+/// so operations are sorted both by state ID and by position in the buffer.
+/// Lookups by state ID are therefore simple binary searches, while lookups by position have to go through
+/// the edits made since the operations were processed.
+#[derive(Clone, Debug, Default)]
+pub struct Operations {
+    operations: Vec<Operation>,
+}
+
+impl Operations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Iterates over all operations, from the first one processed to the tip.
+    pub fn iter(&self) -> std::slice::Iter<'_, Operation> {
+        self.operations.iter()
+    }
+
+    /// The last operation processed.
+    pub fn tip(&self) -> Option<&Operation> {
+        self.operations.last()
+    }
+
+    /// The operation processed right before the tip.
+    pub fn before_tip(&self) -> Option<&Operation> {
+        self.operations.iter().nth_back(1)
+    }
+
+    /// Add a new operation at the tip.
+    pub fn push(&mut self, op: Operation) {
+        if let Some(tip) = self.tip() {
+            if op.state_id <= tip.state_id || op.range.begin < tip.range.end {
+                log::warn!(
+                    "Operation on state ID {} at {} does not come after the tip (state ID {} at {})",
+                    op.state_id,
+                    op.range.begin,
+                    tip.state_id,
+                    tip.range.begin
+                );
+            }
+        }
+
+        self.operations.push(op);
+    }
+
+    /// Remove the operation at the tip.
+    pub fn pop(&mut self) -> Option<Operation> {
+        self.operations.pop()
+    }
+
+    fn index_of(&self, state_id: i64) -> Option<usize> {
+        self.operations
+            .binary_search_by_key(&state_id, |op| op.state_id)
+            .ok()
+    }
+
+    /// Retrieves the operation with the given state ID.
+    pub fn get(&self, state_id: i64) -> Option<&Operation> {
+        self.index_of(state_id).map(|i| &self.operations[i])
+    }
+
+    /// Retrieves the operation with the given state ID, to modify it.
+    pub fn get_mut(&mut self, state_id: i64) -> Option<&mut Operation> {
+        self.index_of(state_id)
+            .map(move |i| &mut self.operations[i])
+    }
+
+    /// Is there an operation with the given state ID?
+    pub fn contains(&self, state_id: i64) -> bool {
+        self.index_of(state_id).is_some()
+    }
+
+    /// Remove all the operations on states after the given state ID, and return them starting from the tip.
+    pub fn truncate_after(&mut self, state_id: i64) -> Vec<Operation> {
+        let index = self
+            .operations
+            .partition_point(|op| op.state_id <= state_id);
+
+        let mut removed = self.operations.split_off(index);
+        removed.reverse();
+        removed
+    }
+
+    /// Finds the operation whose statement contains the given position.
+    ///
+    /// `translate` moves the range of an operation to the timestamp of the position (see [`State::translate`]).
+    pub fn at<F>(&self, position: Position, translate: F) -> Option<&Operation>
+    where
+        F: Fn(Range) -> Option<Range>,
+    {
+        self.translated(translate)
+            .take_while(|(_, range)| range.begin <= position)
+            .last()
+            .filter(|(_, range)| range.contains(position))
+            .map(|(op, _)| op)
+    }
+
+    /// Finds the last operation ending strictly before the given position, i.e. the last one to keep when going
+    /// back to it.
+    ///
+    /// `translate` moves the range of an operation to the timestamp of the position (see [`State::translate`]).
+    pub fn last_before<F>(&self, position: Position, translate: F) -> Option<&Operation>
+    where
+        F: Fn(Range) -> Option<Range>,
+    {
+        let rewound = Range::rest_from(position);
+        self.translated(translate)
+            .take_while(|(_, range)| !range.intersects(&rewound))
+            .last()
+            .map(|(op, _)| op)
+    }
+
+    /// Iterates over the operations along with their translated ranges, up to the first one which has been
+    /// modified by an edit: it has to be processed again, as well as all the operations after it.
+    fn translated<F>(&self, translate: F) -> impl Iterator<Item = (&Operation, Range)>
+    where
+        F: Fn(Range) -> Option<Range>,
+    {
+        self.operations
+            .iter()
+            .map_while(move |op| translate(op.range).map(|range| (op, range)))
+    }
 }
 
 pub struct State {
-    pub operations: VecDeque<Operation>,
+    pub operations: Operations,
     pub last_error_range: Option<Range>,
-    pub error_state: ErrorState,
+    pub error_state: ErrorStateMachine,
+    /// All the messages received for each state ID, in the order they were shown.
+    pub messages: HashMap<i64, Vec<ProtocolRichPP>>,
+    /// The last timestamp of the Kakoune buffer the daemon has been told about.
+    pub buffer_timestamp: u64,
+    /// The last edits of the buffer, along with the timestamp they resulted in.
+    pub edits: VecDeque<(u64, Edit)>,
 }
 
+/// The number of edits remembered to translate ranges.
+///
+/// Ranges computed before older edits are either before them (and never need to move)
+/// or have been discarded when going back before the edits.
+const MAX_REMEMBERED_EDITS: usize = 256;
+
 impl State {
     pub fn new() -> Self {
         Self {
-            operations: VecDeque::new(),
+            operations: Operations::new(),
             last_error_range: None,
-            error_state: ErrorState::Ok,
+            error_state: ErrorStateMachine::new(),
+            messages: HashMap::new(),
+            buffer_timestamp: 0,
+            edits: VecDeque::new(),
         }
     }
+
+    /// Forget about everything `coqidetop` was told (e.g. because it has been restarted), but not about the
+    /// buffer, and return all the operations which were processed.
+    pub fn reset(&mut self) -> Operations {
+        self.last_error_range = None;
+        self.error_state = ErrorStateMachine::new();
+        self.messages.clear();
+
+        std::mem::take(&mut self.operations)
+    }
+
+    /// Remember that the buffer has been edited, resulting in the given timestamp.
+    pub fn record_edit(&mut self, timestamp: u64, edit: Edit) {
+        self.buffer_timestamp = self.buffer_timestamp.max(timestamp);
+
+        self.edits.push_back((timestamp, edit));
+        if self.edits.len() > MAX_REMEMBERED_EDITS {
+            self.edits.pop_front();
+        }
+    }
+
+    /// Translate a range to the last known timestamp of the buffer, through all the edits made since it was computed.
+    ///
+    /// The range is lost (and [`None`] is returned) if any of these edits modified it.
+    pub fn translate(&self, range: Range) -> Option<Range> {
+        self.edits
+            .iter()
+            .filter(|(timestamp, _)| *timestamp > range.timestamp)
+            .try_fold(range, |range, (_, edit)| range.shift(edit))
+            .map(|range| range.at(self.buffer_timestamp.max(range.timestamp)))
+    }
+
+    /// Remember a message received for the given state ID.
+    pub fn record_message(&mut self, state_id: i64, message: ProtocolRichPP) {
+        self.messages.entry(state_id).or_default().push(message);
+    }
+
+    /// Forget about the messages of all the states which are not processed anymore.
+    pub fn forget_messages(&mut self) {
+        let operations = &self.operations;
+        self.messages
+            .retain(|state_id, _| operations.contains(*state_id));
+    }
 }
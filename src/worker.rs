@@ -0,0 +1,674 @@
+use std::{
+    collections::VecDeque,
+    fs, future, io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_signals::Signals;
+use tokio::sync::{broadcast, oneshot, Notify};
+use tokio_stream::StreamExt;
+
+use crate::{
+    client::{
+        bridge::ClientBridge,
+        commands::types::{ClientCommand, DisplayCommand},
+    },
+    config::Config,
+    coqtop::{
+        backend::{Backend, Call, Interrupt, ProverBackend},
+        coqidetop::{COQTOP, SHUTDOWN_SIGNALS},
+        pool::{Lease, Pool},
+        processor::CoqIdeTopProcessor,
+        project::{Project, ProjectCache},
+        xml_protocol::types::{ProtocolRichPP, ProtocolRichPPPart},
+    },
+    error::DaemonError,
+    error_state::ErrorEvent,
+    files::{goal_file, log_file, result_file},
+    kakoune::{
+        command_line::{is_running, kak},
+        ui_updater::KakouneUIUpdater,
+    },
+    range::Range,
+    session::{edited_file, input_fifo, session_id, temporary_folder, Session},
+    state::{Operations, State},
+};
+
+/// How often the daemon checks that the Kakoune session is still running.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What all the buffers served by a single daemon share.
+pub struct Shared {
+    /// The projects of all the buffers, which are only read once.
+    pub projects: ProjectCache,
+    /// The [`COQTOP`] processes running for all the buffers.
+    pub pool: Arc<Pool>,
+}
+
+/// How a buffer is controlled by a daemon serving several buffers.
+pub struct Managed {
+    /// The buffer, among all those served by the daemon.
+    pub id: usize,
+    pub shared: Arc<Shared>,
+    /// Notified to interrupt the statement being processed.
+    pub interrupt: Arc<Notify>,
+    /// Notified when the buffer must not be served anymore.
+    pub quit: Arc<Notify>,
+    /// Notified when [`COQTOP`] must be stopped to make room for another buffer.
+    pub release: Arc<Notify>,
+}
+
+/// Serves a buffer, processing all the commands received until asked to quit (either with a command or a signal)
+/// or until Kakoune is not running anymore.
+///
+/// When the daemon serves several buffers, [`COQTOP`] is only started once it is needed, and may be stopped
+/// to make room for another buffer (see [`Pool`]).
+/// It is then started again as soon as it is needed, and processes again all the statements which were processed.
+pub async fn run(
+    session: Arc<Session>,
+    config: Arc<Config>,
+    managed: Option<Managed>,
+) -> io::Result<()> {
+    let mut signals = Signals::new(SHUTDOWN_SIGNALS)?;
+    let state = Arc::new(RwLock::new(State::new()));
+
+    let mut client_bridge =
+        ClientBridge::new::<10000>(session.clone(), state.clone(), config.clone()).await?;
+    let mut coq = match &managed {
+        Some(_) => None,
+        None => Some(Coq::start(session.clone(), config.clone(), None).await?),
+    };
+    let mut coqtop_processor = CoqIdeTopProcessor::new(
+        session.clone(),
+        state.clone(),
+        config.clone(),
+        client_bridge.command_tx.clone(),
+    )?;
+    let mut ui_updater = KakouneUIUpdater::new(session.clone(), state.clone());
+    let mut liveness = tokio::time::interval(LIVENESS_INTERVAL);
+
+    // NOTE: `coqidetop` must quit even if something goes wrong (e.g. Kakoune is not running anymore)
+    let res: io::Result<()> = async {
+        if let Some(report) = coq
+            .as_ref()
+            .and_then(|coq| coq.project.as_ref())
+            .and_then(Project::report)
+        {
+            ui_updater
+                .process(VecDeque::from([DisplayCommand::ColorResult(report, false)]))
+                .await?;
+        }
+
+        loop {
+            let received = tokio::select! {
+                received = client_bridge.recv() => received,
+                Some(signal) = signals.next() => {
+                    log::info!("Received signal {}, quitting", signal);
+                    break;
+                }
+                _ = notified(managed.as_ref().map(|managed| &managed.quit)) => {
+                    log::info!("Not serving {} anymore, quitting", edited_file(session.clone()));
+                    break;
+                }
+                _ = notified(managed.as_ref().map(|managed| &managed.release)), if coq.is_some() => {
+                    log::info!("Stopping {} to make room for another buffer", COQTOP);
+
+                    stop(&mut coq, &mut ui_updater).await?;
+                    continue;
+                }
+                _ = liveness.tick() => {
+                    let session_id = session_id(session.clone());
+                    match is_running(&session_id).await {
+                        Ok(true) => continue,
+                        Ok(false) => {
+                            log::warn!("Kakoune session {} is not running anymore, quitting", session_id);
+                            break;
+                        }
+                        Err(err) => {
+                            log::warn!("Cannot check whether Kakoune is still running: {}", err);
+                            continue;
+                        }
+                    }
+                }
+                path = changed(&mut coq) => {
+                    log::info!("{} has been modified, restarting {}", path.display(), COQTOP);
+
+                    if let Some(coq) = coq.as_mut() {
+                        let reason = format!("{} has been modified", path.display());
+                        restart(coq, managed.as_ref(), Some(reason), true, &state, &client_bridge.command_tx, &mut ui_updater).await?;
+                    }
+                    continue;
+                }
+            };
+            let cmd = match received {
+                Ok(cmd) => cmd,
+                Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => {
+                    log::warn!("{}, quitting", err);
+                    break;
+                }
+                Err(err) => {
+                    report(DaemonError::try_from(err)?, &mut ui_updater).await?;
+                    continue;
+                }
+            };
+            if coq.is_none() && needs_coq(&cmd) {
+                let started = resume(
+                    &mut coq,
+                    &session,
+                    &config,
+                    managed.as_ref(),
+                    &state,
+                    &mut ui_updater,
+                )
+                .await?;
+                // NOTE: the command is received again once all the statements have been processed again
+                if started {
+                    let display = replay_before(cmd, &state, &client_bridge.command_tx)?;
+                    ui_updater.process(display).await?;
+                    continue;
+                }
+            }
+            let (call, cmd, display3) = match client_bridge.process(cmd).await {
+                Ok(processed) => processed,
+                Err(err) => {
+                    report(DaemonError::try_from(err)?, &mut ui_updater).await?;
+                    continue;
+                }
+            };
+
+            ui_updater.process(display3.into_iter().collect()).await?;
+            if let ClientCommand::Quit = cmd {
+                break;
+            }
+            if let (ClientCommand::Restart(replay), Some(coq)) = (&cmd, coq.as_mut()) {
+                log::info!("Restarting {}", COQTOP);
+
+                restart(
+                    coq,
+                    managed.as_ref(),
+                    None,
+                    *replay,
+                    &state,
+                    &client_bridge.command_tx,
+                    &mut ui_updater,
+                )
+                .await?;
+                continue;
+            }
+            if let Some(call) = call {
+                match coq.as_mut() {
+                    Some(coq) => {
+                        ask(
+                            coq,
+                            call,
+                            cmd,
+                            &state,
+                            &mut coqtop_processor,
+                            &mut ui_updater,
+                        )
+                        .await?
+                    }
+                    None => {
+                        log::warn!("Dropping {:?} as {} is not running", cmd, COQTOP);
+
+                        if let ClientCommand::Next(_, range, _) = cmd {
+                            ui_updater
+                                .process(VecDeque::from([DisplayCommand::RemoveToBeProcessed(
+                                    range,
+                                )]))
+                                .await?;
+                        }
+                    }
+                }
+                forget_stopped(&mut coq, &mut ui_updater).await?;
+            }
+            client_bridge.send_deferred()?;
+        }
+
+        Ok(())
+    }
+    .await;
+
+    let quit = match coq {
+        Some(coq) => coq.bridge.quit().await,
+        None => Ok(()),
+    };
+    res.and(quit)
+}
+
+/// Asks Kakoune to forget about the daemon, and removes all the files created for it in the temporary folder.
+///
+/// Kakoune may not be running anymore, so failures are only logged.
+pub async fn cleanup(session: Arc<Session>) {
+    let purged = kak(
+        &session_id(session.clone()),
+        format!(
+            r#"evaluate-commands -buffer '{}' %{{ coqide-purge }}"#,
+            edited_file(session.clone())
+        ),
+    )
+    .await;
+    if let Err(err) = purged {
+        log::warn!("Cannot purge CoqIDE in Kakoune: {}", err);
+    }
+
+    let tmp_dir = temporary_folder(session.clone());
+    for path in [
+        input_fifo(session.clone()),
+        goal_file(&tmp_dir),
+        result_file(&tmp_dir),
+        log_file(&tmp_dir),
+    ] {
+        match fs::remove_file(&path) {
+            Ok(()) => log::debug!("Removed {}", path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => log::warn!("Cannot remove {}: {}", path, err),
+        }
+    }
+    // NOTE: the folder is kept if anything else has been put inside
+    if let Err(err) = fs::remove_dir(&tmp_dir) {
+        log::debug!("Cannot remove {}: {}", tmp_dir, err);
+    }
+}
+
+/// A [`COQTOP`] process, along with the project it has been started for.
+struct Coq {
+    session: Arc<Session>,
+    config: Arc<Config>,
+    project: Option<Project>,
+    bridge: Backend,
+    /// Where modifications of the project files are notified, if they are watched.
+    project_changes: Option<oneshot::Receiver<PathBuf>>,
+    /// The right to run the process, when the daemon serves several buffers.
+    lease: Option<Lease>,
+}
+
+impl Coq {
+    /// Reads the project of the edited file, and starts [`COQTOP`] for it once there is room for it.
+    async fn start(
+        session: Arc<Session>,
+        config: Arc<Config>,
+        managed: Option<&Managed>,
+    ) -> io::Result<Self> {
+        let lease = match managed {
+            Some(managed) => Some(
+                managed
+                    .shared
+                    .pool
+                    .acquire(managed.id, managed.release.clone())
+                    .await,
+            ),
+            None => None,
+        };
+
+        let mut coq = Self::spawn(session, config, managed).await?;
+        coq.lease = lease;
+        Ok(coq)
+    }
+
+    async fn spawn(
+        session: Arc<Session>,
+        config: Arc<Config>,
+        managed: Option<&Managed>,
+    ) -> io::Result<Self> {
+        let file = edited_file(session.clone());
+        let (project, interrupt) = match managed {
+            Some(managed) => (
+                managed.shared.projects.load(&file, &config).await,
+                Interrupt::Notified(managed.interrupt.clone()),
+            ),
+            None => (Project::load(&file, &config).await, Interrupt::Signal),
+        };
+        let bridge = Backend::spawn(session.clone(), &config, project.as_ref(), interrupt).await?;
+
+        let mut coq = Self {
+            session,
+            config,
+            project,
+            bridge,
+            project_changes: None,
+            lease: None,
+        };
+        coq.watch();
+        Ok(coq)
+    }
+
+    /// Reads the project again, and replaces the [`COQTOP`] process by a new one.
+    ///
+    /// The previous process is kept if the new one cannot be started, so that the daemon can still be used.
+    async fn restart(&mut self, managed: Option<&Managed>) -> io::Result<()> {
+        match Self::spawn(self.session.clone(), self.config.clone(), managed).await {
+            Ok(mut coq) => {
+                coq.lease = self.lease.take();
+                *self = coq;
+                Ok(())
+            }
+            Err(err) => {
+                self.watch();
+                Err(err)
+            }
+        }
+    }
+
+    /// Watches the files of the project (if any, and if the configuration allows it) for modifications.
+    fn watch(&mut self) {
+        self.project_changes = self
+            .project
+            .as_ref()
+            .filter(|_| self.config.watch_project)
+            .map(Project::watch);
+    }
+
+    /// Waits until a file of the project is modified, or forever if the project is not watched.
+    async fn changed(&mut self) -> PathBuf {
+        let changed = match self.project_changes.as_mut() {
+            Some(rx) => rx.await.ok(),
+            None => None,
+        };
+        // NOTE: modifications are only notified once
+        self.project_changes = None;
+
+        match changed {
+            Some(path) => path,
+            None => future::pending().await,
+        }
+    }
+}
+
+/// Waits until a file of the project is modified, or forever if [`COQTOP`] is not running.
+async fn changed(coq: &mut Option<Coq>) -> PathBuf {
+    match coq.as_mut() {
+        Some(coq) => coq.changed().await,
+        None => future::pending().await,
+    }
+}
+
+/// Waits until the daemon notifies the buffer, or forever if it only serves this buffer.
+async fn notified(notify: Option<&Arc<Notify>>) {
+    match notify {
+        Some(notify) => notify.notified().await,
+        None => future::pending().await,
+    }
+}
+
+/// Does processing this command need [`COQTOP`] to be running?
+fn needs_coq(cmd: &ClientCommand) -> bool {
+    !matches!(
+        cmd,
+        ClientCommand::Quit
+            | ClientCommand::IgnoreError
+            | ClientCommand::StopInterrupt
+            | ClientCommand::ToggleGoals(_)
+            | ClientCommand::MessagesAt(..)
+            | ClientCommand::ShowConfig
+    )
+}
+
+/// Sends a call to [`COQTOP`] on behalf of a command, and shows what comes out of it.
+async fn ask(
+    coq: &mut Coq,
+    call: Call,
+    cmd: ClientCommand,
+    state: &Arc<RwLock<State>>,
+    processor: &mut CoqIdeTopProcessor,
+    ui_updater: &mut KakouneUIUpdater,
+) -> io::Result<()> {
+    if let Some(lease) = &coq.lease {
+        lease.touch();
+    }
+
+    match coq.bridge.ask(call).await {
+        Ok(response) => {
+            let feedback = coq.bridge.feedback();
+            let mut display = processor.process_response(response, cmd).await?;
+            let mut display2 = processor.process_feedback(feedback).await?;
+
+            // When we receive some feedback, we want to process it last.
+            // In any case, this will not change the global application state, compared
+            // to processing a response.
+            display.append(&mut display2);
+
+            ui_updater.process(display).await
+        }
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+            log::warn!("Interrupting current processing");
+
+            state
+                .write()
+                .unwrap()
+                .error_state
+                .transition(ErrorEvent::Interrupted);
+            Ok(())
+        }
+        Err(err) => {
+            let display = processor
+                .process_error(DaemonError::try_from(err)?, cmd)
+                .await?;
+            ui_updater.process(display).await
+        }
+    }
+}
+
+/// Stops [`COQTOP`] to make room for another buffer, keeping everything known about the buffer so that all the
+/// statements processed can be processed again later.
+async fn stop(coq: &mut Option<Coq>, ui_updater: &mut KakouneUIUpdater) -> io::Result<()> {
+    use ProtocolRichPPPart::*;
+
+    if let Some(coq) = coq.take() {
+        if let Err(err) = coq.bridge.quit().await {
+            log::warn!("{} could not be stopped: {}", COQTOP, err);
+        }
+    }
+
+    ui_updater
+        .process(VecDeque::from([DisplayCommand::ColorResult(
+            ProtocolRichPP::RichPP(vec![Warning(format!(
+                "{} has been stopped to make room for another buffer, and will be started again when needed.",
+                COQTOP
+            ))]),
+            false,
+        )]))
+        .await
+}
+
+/// Forgets about [`COQTOP`] once it has been stopped because it could not be interrupted otherwise (see
+/// [`ProverBackend::is_stopped`]), so that it is started again as soon as it is needed.
+async fn forget_stopped(
+    coq: &mut Option<Coq>,
+    ui_updater: &mut KakouneUIUpdater,
+) -> io::Result<()> {
+    use ProtocolRichPPPart::*;
+
+    if !coq.as_ref().is_some_and(|coq| coq.bridge.is_stopped()) {
+        return Ok(());
+    }
+    *coq = None;
+
+    ui_updater
+        .process(VecDeque::from([DisplayCommand::ColorResult(
+            ProtocolRichPP::RichPP(vec![Warning(format!(
+                "{} cannot be interrupted remotely and has been stopped, and will be waited for again when needed.",
+                COQTOP
+            ))]),
+            false,
+        )]))
+        .await
+}
+
+/// Starts [`COQTOP`] for a buffer which has none (because it has not needed it yet, or because it has been
+/// stopped to make room for another buffer), so that all the statements which were processed can be processed
+/// again (see [`replay_before`]).
+///
+/// Failing to start it is only shown in the result buffer, as it is tried again for the next command.
+/// Returns whether it has been started.
+async fn resume(
+    coq: &mut Option<Coq>,
+    session: &Arc<Session>,
+    config: &Arc<Config>,
+    managed: Option<&Managed>,
+    state: &Arc<RwLock<State>>,
+    ui_updater: &mut KakouneUIUpdater,
+) -> io::Result<bool> {
+    use ProtocolRichPPPart::*;
+
+    let coq = match Coq::start(session.clone(), config.clone(), managed).await {
+        Ok(started) => coq.insert(started),
+        Err(err) => {
+            log::error!("Cannot start {}: {}", COQTOP, err);
+
+            ui_updater
+                .process(VecDeque::from([DisplayCommand::ColorResult(
+                    ProtocolRichPP::RichPP(vec![Error(format!(
+                        "Cannot start {}: {}",
+                        COQTOP, err
+                    ))]),
+                    false,
+                )]))
+                .await?;
+            return Ok(false);
+        }
+    };
+
+    let mut display = VecDeque::new();
+    if !state.read().unwrap().operations.is_empty() {
+        display.push_back(DisplayCommand::ColorResult(
+            ProtocolRichPP::RichPP(vec![Warning(format!("{} has been started again.", COQTOP))]),
+            false,
+        ));
+    }
+    if let Some(report) = coq.project.as_ref().and_then(Project::report) {
+        display.push_back(DisplayCommand::ColorResult(report, true));
+    }
+    ui_updater.process(display).await?;
+
+    Ok(true)
+}
+
+/// Forgets about all the statements processed by the previous [`COQTOP`] process once a new one has been started
+/// for the given command, and processes them again before this command (see [`reset`]).
+///
+/// Starting a new process is already restarting it, so a [`ClientCommand::Restart`] is not processed again,
+/// and the statements are only processed again if it asks to.
+pub fn replay_before(
+    cmd: ClientCommand,
+    state: &Arc<RwLock<State>>,
+    command_tx: &broadcast::Sender<ClientCommand>,
+) -> io::Result<VecDeque<DisplayCommand>> {
+    let display = match cmd {
+        ClientCommand::Restart(replay) => reset(state, command_tx, replay)?,
+        cmd => {
+            let display = reset(state, command_tx, true)?;
+            command_tx
+                .send(cmd)
+                .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+            display
+        }
+    };
+
+    Ok(display)
+}
+
+/// Restarts [`COQTOP`] (for the given reason), and shows that it has been restarted in the result buffer.
+///
+/// All the statements which were processed are processed again if asked to.
+async fn restart(
+    coq: &mut Coq,
+    managed: Option<&Managed>,
+    reason: Option<String>,
+    replay: bool,
+    state: &Arc<RwLock<State>>,
+    command_tx: &broadcast::Sender<ClientCommand>,
+    ui_updater: &mut KakouneUIUpdater,
+) -> io::Result<()> {
+    use ProtocolRichPPPart::*;
+
+    let display = match coq.restart(managed).await {
+        Ok(()) => {
+            let mut display = reset(state, command_tx, replay)?;
+            display.push_front(DisplayCommand::ColorResult(
+                ProtocolRichPP::RichPP(vec![Warning(match reason {
+                    Some(reason) => format!("{}, {} has been restarted.", reason, COQTOP),
+                    None => format!("{} has been restarted.", COQTOP),
+                })]),
+                false,
+            ));
+            if let Some(report) = coq.project.as_ref().and_then(Project::report) {
+                display.push_back(DisplayCommand::ColorResult(report, true));
+            }
+            display
+        }
+        Err(err) => {
+            log::error!("Cannot restart {}: {}", COQTOP, err);
+
+            VecDeque::from([DisplayCommand::ColorResult(
+                ProtocolRichPP::RichPP(vec![Error(format!("Cannot restart {}: {}", COQTOP, err))]),
+                false,
+            )])
+        }
+    };
+
+    ui_updater.process(display).await
+}
+
+/// Forgets about all the statements processed by the previous [`COQTOP`] process, and initializes the new one.
+///
+/// If asked to, the statements are processed again by the new process, and are shown as to be processed
+/// until then.
+fn reset(
+    state: &Arc<RwLock<State>>,
+    command_tx: &broadcast::Sender<ClientCommand>,
+    replay: bool,
+) -> io::Result<VecDeque<DisplayCommand>> {
+    let operations = state.write().unwrap().reset();
+    let display = forget(&operations, replay);
+
+    // NOTE: nothing was processed if the daemon has not been initialized yet, which Kakoune will do itself
+    if operations.is_empty() {
+        return Ok(display);
+    }
+
+    // NOTE: the first operation is the initial state, which has no statement
+    let statements = operations
+        .iter()
+        .skip(1)
+        .filter(|_| replay)
+        .map(|op| ClientCommand::Next(true, op.range, op.code.clone()));
+    for cmd in [ClientCommand::Init].into_iter().chain(statements).chain([
+        ClientCommand::ShowGoals(Range::default()),
+        ClientCommand::Status,
+    ]) {
+        command_tx
+            .send(cmd)
+            .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+    }
+
+    Ok(display)
+}
+
+/// Removes the given operations from the buffer, keeping them as to be processed if they are processed again.
+fn forget(operations: &Operations, replay: bool) -> VecDeque<DisplayCommand> {
+    let mut display = VecDeque::from([DisplayCommand::RefreshErrorRange(None, true)]);
+    for op in operations.iter() {
+        display.push_back(DisplayCommand::RemoveProcessed(op.range));
+        display.push_back(DisplayCommand::RemoveAxiom(op.range));
+        display.push_back(DisplayCommand::RemoveInlineResult(op.range));
+        if !replay {
+            display.push_back(DisplayCommand::RemoveToBeProcessed(op.range));
+        }
+    }
+    display
+}
+
+/// Logs an error which does not stop the daemon, and shows it in the result buffer.
+async fn report(err: DaemonError, ui_updater: &mut KakouneUIUpdater) -> io::Result<()> {
+    log::error!("{}", err);
+
+    ui_updater
+        .process(VecDeque::from([DisplayCommand::ColorResult(
+            err.message(),
+            false,
+        )]))
+        .await
+}
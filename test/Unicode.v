@@ -0,0 +1,18 @@
+Require Import Utf8.
+
+(* Les énoncés ci-dessous utilisent ∀, → et ∧ partout. *)
+Definition compose {A B C : Type} (g : B → C) (f : A → B) : A → C := λ x, g (f x).
+
+Theorem and_swap : ∀ P Q : Prop, P ∧ Q → Q ∧ P.
+Proof.
+  intros P Q [HP HQ]. split.
+  - (* ∧ à droite *) exact HQ.
+  - exact HP.
+Qed.
+
+Theorem compose_id : ∀ (A : Type) (f : A → A), compose (λ x, x) f = f.
+Proof. intros A f. reflexivity. Qed.
+
+Check (∀ n : nat, n = n → n ≤ n).
+Compute compose (λ n, n + 1) (λ n, 2 × n) 3.
+Fail Check (λ (α : nat), α ∧ α).
@@ -0,0 +1,126 @@
+//! Helpers shared by the integration tests.
+
+// NOTE: every test only uses some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use daemon::{
+    client::commands::{decode::CommandDecoder, types::ClientCommand},
+    coqtop::{
+        backend::{Goal, Goals},
+        xml_protocol::types::{ProtocolRichPP, ProtocolRichPPPart},
+    },
+    range::{Edit, Position, Range},
+    state::Operation,
+};
+
+/// Runs `coq-parser` on the whole content of a file, returning all the statements found as they would be
+/// decoded by the daemon from a `next` command.
+pub fn parse_statements(content: &str) -> Vec<(Range, String)> {
+    let last_line = content.lines().count() + 1;
+
+    let mut parser = Command::new(env!("CARGO_BIN_EXE_coq-parser"))
+        .args(["1", "1", "to", &last_line.to_string(), "1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    parser
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(content.as_bytes())
+        .unwrap();
+    let output = parser.wait_with_output().unwrap();
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let mut src = BytesMut::from(format!("next false 0 {}\n", line).as_str());
+            match CommandDecoder::default().decode(&mut src).unwrap() {
+                Some((_, Ok(ClientCommand::Next(_, range, code)))) => (range, code),
+                command => panic!("could not decode {:?}: {:?}", line, command),
+            }
+        })
+        .collect()
+}
+
+/// Retrieves the text spanned by a range (whose end is inclusive) counted in bytes.
+pub fn text_at(content: &str, range: Range) -> &str {
+    let offset = |position: Position| {
+        content
+            .split_inclusive('\n')
+            .take(position.line as usize - 1)
+            .map(str::len)
+            .sum::<usize>()
+            + position.column as usize
+            - 1
+    };
+
+    let begin = offset(range.begin);
+    let end = offset(range.end);
+    let last_char = content[end..].chars().next().unwrap();
+
+    &content[begin..end + last_char.len_utf8()]
+}
+
+pub fn at(line: u64, column: u64) -> Position {
+    Position::new(line, column)
+}
+
+pub fn edit(begin: Position, removed_end: Position, inserted_end: Position) -> Edit {
+    Edit {
+        begin,
+        removed_end,
+        inserted_end,
+    }
+}
+
+pub fn insertion(begin: Position, inserted_end: Position) -> Edit {
+    edit(begin, begin, inserted_end)
+}
+
+pub fn removal(begin: Position, removed_end: Position) -> Edit {
+    edit(begin, removed_end, begin)
+}
+
+/// Creates an operation on a statement spanning the given range.
+pub fn operation(state_id: i64, range: Range) -> Operation {
+    Operation {
+        state_id,
+        range,
+        ..Operation::default()
+    }
+}
+
+/// Creates a goal without hypotheses, whose conclusion is not colored.
+pub fn goal(name: &str, ccl: &str) -> Goal {
+    Goal {
+        name: name.to_string(),
+        hypotheses: vec![],
+        conclusion: ProtocolRichPP::RichPP(vec![ProtocolRichPPPart::Raw(ccl.to_string())]),
+        user_name: None,
+    }
+}
+
+pub fn goals(
+    focused: Vec<Goal>,
+    background: Vec<(Vec<Goal>, Vec<Goal>)>,
+    shelved: Vec<Goal>,
+    given_up: Vec<Goal>,
+) -> Goals {
+    Goals {
+        focused,
+        background,
+        shelved,
+        given_up,
+    }
+}
@@ -0,0 +1,177 @@
+//! The `.coqide.toml` configuration file, and how it is merged with the command-line.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use log::LevelFilter;
+
+use daemon::{
+    config::{Config, MessageKind, CONFIG_FILE},
+    coqtop::backend::BackendKind,
+    options::Options,
+};
+
+const CONFIG: &str = r#"
+coqtop = "coqidetop.opt"
+log-level = "info"
+project-file = "theories/_CoqProject"
+coq-flags = ["-w", "-notation-overridden"]
+timeout = 30
+manager = true
+max-coqtops = 2
+
+[async-proofs]
+mode = "lazy"
+jobs = 2
+
+[messages]
+hide = ["imports", "warning"]
+"#;
+
+/// Creates a project containing a configuration file at its root, and returns the path to a Coq file
+/// nested in it.
+fn project(name: &str, config: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("coqide-{}-{}", name, std::process::id()));
+    let theories = root.join("theories");
+    fs::create_dir_all(&theories).unwrap();
+    fs::write(root.join(CONFIG_FILE), config).unwrap();
+
+    let file = theories.join("A.v");
+    fs::write(&file, "Check nat.\n").unwrap();
+    file.canonicalize().unwrap()
+}
+
+fn options(file: &Path, flags: &[&str]) -> Options {
+    let file = file.to_str().unwrap();
+    let required = [
+        "coqide-daemon",
+        "--client",
+        "client0",
+        "--session",
+        "1234",
+        "--file",
+        file,
+        "--tmp-dir",
+        "/tmp",
+        "--socket",
+        "/tmp/input.sock",
+    ];
+    Options::try_parse_from(required.iter().chain(flags)).unwrap()
+}
+
+#[test]
+fn settings_are_read_from_the_closest_ancestor() {
+    let file = project("ancestor", CONFIG);
+    let root = file.parent().unwrap().parent().unwrap();
+
+    let config = Config::load(&options(&file, &[])).unwrap();
+
+    assert_eq!(config.file, Some(root.join(CONFIG_FILE)));
+    assert_eq!(config.coqtop, "coqidetop.opt");
+    assert_eq!(config.log_level, LevelFilter::Info);
+    assert_eq!(config.project_file, Some(root.join("theories/_CoqProject")));
+    assert_eq!(
+        config.coq_flags,
+        [
+            "-w",
+            "-notation-overridden",
+            "-async-proofs",
+            "lazy",
+            "-async-proofs-j",
+            "2"
+        ]
+    );
+    assert_eq!(config.timeout, Some(Duration::from_secs(30)));
+    assert!(config.manager);
+    assert_eq!(config.max_coqtops.get(), 2);
+    assert!(config.shows(MessageKind::Info));
+    assert!(!config.shows(MessageKind::Imports));
+    assert!(!config.shows(MessageKind::Warning));
+}
+
+#[test]
+fn flags_take_precedence() {
+    let file = project("precedence", CONFIG);
+
+    let config = Config::load(&options(
+        &file,
+        &[
+            "--coqtop",
+            "/opt/coq/bin/coqidetop",
+            "--log-level",
+            "error",
+            "--timeout",
+            "5",
+            "--project-file",
+            "Other",
+            "--max-coqtops",
+            "8",
+            "--",
+            "-noinit",
+        ],
+    ))
+    .unwrap();
+
+    assert_eq!(config.coqtop, "/opt/coq/bin/coqidetop");
+    assert_eq!(config.log_level, LevelFilter::Error);
+    assert_eq!(config.timeout, Some(Duration::from_secs(5)));
+    assert_eq!(config.project_file, Some(PathBuf::from("Other")));
+    assert_eq!(config.coq_flags.last().unwrap(), "-noinit");
+    assert_eq!(config.coq_flags.len(), 7);
+    assert_eq!(config.max_coqtops.get(), 8);
+
+    let config = Config::load(&options(&file, &["--no-config"])).unwrap();
+    assert_eq!(config.file, None);
+    assert_eq!(config.coqtop, "coqidetop");
+    assert_eq!(config.log_level, LevelFilter::Debug);
+    assert!(config.coq_flags.is_empty());
+    assert!(!config.manager);
+    assert_eq!(config.max_coqtops.get(), 4);
+
+    let config = Config::load(&options(&file, &["--no-config", "--backend", "sertop"])).unwrap();
+    assert_eq!(config.backend, BackendKind::SerTop);
+    assert_eq!(config.coqtop, "sertop");
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    let invalid = [
+        ("unknown", "colour = \"red\"\n"),
+        ("level", "log-level = \"loud\"\n"),
+        ("timeout", "timeout = -1\n"),
+        ("coqtops", "max-coqtops = 0\n"),
+        ("backend", "backend = \"coqtop\"\n"),
+        ("attach", "attach = \"build-box\"\n"),
+        ("mode", "[async-proofs]\nmode = \"sometimes\"\n"),
+        ("syntax", "coqtop = \n"),
+    ];
+
+    for (name, config) in invalid {
+        let file = project(name, config);
+        assert!(
+            Config::load(&options(&file, &[])).is_err(),
+            "{:?} was accepted",
+            config
+        );
+    }
+}
+
+#[test]
+fn resolved_configuration_is_shown_as_toml() {
+    let file = project("show", CONFIG);
+    let config = Config::load(&options(&file, &[])).unwrap();
+
+    let shown = config.to_string();
+    assert!(shown.contains("coqtop = \"coqidetop.opt\"\n"), "{}", shown);
+    assert!(shown.contains("log-level = \"info\"\n"), "{}", shown);
+    assert!(shown.contains("timeout = 30\n"), "{}", shown);
+    assert!(
+        shown.ends_with("hide = [\"imports\", \"warning\"]"),
+        "{}",
+        shown
+    );
+}
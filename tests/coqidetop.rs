@@ -0,0 +1,137 @@
+//! Translating the results of the XML protocol to the responses and feedback the daemon understands.
+
+use daemon::{
+    coqtop::{
+        backend::{Failure, Feedback, FeedbackContent},
+        coqidetop::{translate_feedback, translate_response},
+        xml_protocol::types::{
+            self, ProtocolResult, ProtocolRichPP, ProtocolRichPPPart, ProtocolValue,
+        },
+    },
+    error::ProtocolError,
+};
+
+fn message(text: &str) -> ProtocolRichPP {
+    ProtocolRichPP::RichPP(vec![ProtocolRichPPPart::Raw(text.to_string())])
+}
+
+fn feedback(state_id: ProtocolValue, content: types::FeedbackContent) -> ProtocolResult {
+    ProtocolResult::Feedback(String::new(), String::new(), state_id, content)
+}
+
+#[test]
+fn good_results_are_converted() {
+    let answer =
+        translate_response(
+            ProtocolResult::Good(ProtocolValue::StateId(2)),
+            |value| match value {
+                ProtocolValue::StateId(state_id) => Ok(state_id),
+                value => panic!("unexpected value {:?}", value),
+            },
+        );
+
+    assert!(matches!(answer, Ok(Ok(2))));
+}
+
+#[test]
+fn failures_keep_the_location_and_the_safe_state() {
+    let answer = translate_response(
+        ProtocolResult::Fail(
+            Some(6),
+            Some(9),
+            ProtocolValue::StateId(4),
+            message("The reference foo was not found."),
+        ),
+        |_| Ok(()),
+    );
+
+    match answer {
+        Ok(Err(Failure {
+            location: Some((6, 9)),
+            safe_state_id: Some(4),
+            message: ProtocolRichPP::RichPP(parts),
+        })) => assert_eq!(parts.len(), 1),
+        answer => panic!("unexpected answer {:?}", answer),
+    }
+
+    // NOTE: `coqidetop` gives the state 0 when there is no state to go back to
+    let answer = translate_response(
+        ProtocolResult::Fail(None, None, ProtocolValue::StateId(0), message("Oops.")),
+        |_| Ok(()),
+    );
+    assert!(matches!(
+        answer,
+        Ok(Err(Failure {
+            location: None,
+            safe_state_id: None,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn malformed_results_are_errors() {
+    let unexpected =
+        translate_response(
+            ProtocolResult::Good(ProtocolValue::Unit),
+            |value| match value {
+                ProtocolValue::StateId(state_id) => Ok(state_id),
+                value => Err(ProtocolError::UnexpectedValue("a state ID", value)),
+            },
+        );
+    assert!(unexpected.is_err());
+
+    let fail = translate_response(
+        ProtocolResult::Fail(None, None, ProtocolValue::Unit, message("Oops.")),
+        |_| Ok(()),
+    );
+    assert!(fail.is_err());
+
+    let feedback = translate_response(
+        feedback(ProtocolValue::StateId(1), types::FeedbackContent::Processed),
+        |_| Ok(()),
+    );
+    assert!(feedback.is_err());
+}
+
+#[test]
+fn feedback_is_translated() {
+    assert!(matches!(
+        translate_feedback(feedback(
+            ProtocolValue::StateId(3),
+            types::FeedbackContent::Processed
+        )),
+        Ok(Some(Feedback {
+            state_id: 3,
+            content: FeedbackContent::Processed
+        }))
+    ));
+
+    match translate_feedback(feedback(
+        ProtocolValue::StateId(4),
+        types::FeedbackContent::FileLoaded(
+            ProtocolValue::Str("Foo".to_string()),
+            ProtocolValue::Str("/tmp/Foo.vo".to_string()),
+        ),
+    )) {
+        Ok(Some(Feedback {
+            state_id: 4,
+            content: FeedbackContent::FileLoaded(name, path),
+        })) => assert_eq!((name.as_str(), path.as_str()), ("Foo", "/tmp/Foo.vo")),
+        feedback => panic!("unexpected feedback {:?}", feedback),
+    }
+
+    assert!(matches!(
+        translate_feedback(feedback(
+            ProtocolValue::StateId(4),
+            types::FeedbackContent::Complete
+        )),
+        Ok(None)
+    ));
+
+    assert!(translate_feedback(feedback(
+        ProtocolValue::Unit,
+        types::FeedbackContent::Processed
+    ))
+    .is_err());
+}
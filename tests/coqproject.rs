@@ -0,0 +1,284 @@
+//! Parsing `_CoqProject` files, and reporting what is wrong in them.
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use clap::Parser;
+
+use daemon::{
+    config::Config,
+    coqtop::{
+        coqproject::{
+            parser::{parse, tokenize, Diagnostic, NativeCompiler, ProjectOption},
+            CoqProject,
+        },
+        project::{synthetic_module_name, Project, ProjectCache},
+    },
+    options::Options,
+};
+
+const PROJECT: &str = r#"# The whole project
+-R theories Foo
+-Q "src with spaces" Foo.Bar # bound to Foo.Bar
+-I src
+-arg "-w -notation-overridden"
+-arg -noinit
+-native-compiler ondemand
+-docroot Foo
+-o CoqMakefile
+-generate-meta-for-package coq-foo
+COQDOCFLAGS = "-utf8 --toc"
+
+theories/A.v theories/B.v
+src/plugin.mlg src/foo_plugin.mlpack
+"#;
+
+#[test]
+fn every_documented_option() {
+    let (options, diagnostics) = parse(PROJECT);
+
+    assert_eq!(diagnostics, []);
+    assert_eq!(
+        options,
+        [
+            (2, ProjectOption::Recursive("theories".into(), "Foo".into())),
+            (
+                3,
+                ProjectOption::Qualified("src with spaces".into(), "Foo.Bar".into())
+            ),
+            (4, ProjectOption::Include("src".into())),
+            (
+                5,
+                ProjectOption::Arg(vec!["-w".into(), "-notation-overridden".into()])
+            ),
+            (6, ProjectOption::Arg(vec!["-noinit".into()])),
+            (7, ProjectOption::NativeCompiler(NativeCompiler::OnDemand)),
+            (8, ProjectOption::Docroot("Foo".into())),
+            (9, ProjectOption::Output("CoqMakefile".into())),
+            (10, ProjectOption::GenerateMetaForPackage("coq-foo".into())),
+            (
+                11,
+                ProjectOption::Variable("COQDOCFLAGS".into(), "-utf8 --toc".into())
+            ),
+            (13, ProjectOption::File("theories/A.v".into())),
+            (13, ProjectOption::File("theories/B.v".into())),
+            (14, ProjectOption::File("src/plugin.mlg".into())),
+            (14, ProjectOption::File("src/foo_plugin.mlpack".into())),
+        ]
+    );
+}
+
+#[test]
+fn words_strings_and_comments() {
+    let (tokens, diagnostics) = tokenize("a \"b c\"#d \"e\"\n  # f \"g\n\"h\ni\"");
+
+    assert_eq!(diagnostics, []);
+    assert_eq!(
+        tokens
+            .iter()
+            .map(|token| (token.line, token.text.as_str(), token.quoted))
+            .collect::<Vec<_>>(),
+        [(1, "a", false), (1, "b c", true), (3, "h\ni", true),]
+    );
+
+    let (tokens, diagnostics) = tokenize("a\n\"b c");
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(
+        diagnostics,
+        [Diagnostic {
+            line: 2,
+            message: "Unterminated string".to_string()
+        }]
+    );
+}
+
+#[test]
+fn malformed_entries_are_reported_with_their_line() {
+    let (options, diagnostics) = parse(concat!(
+        "-R theories\n",
+        "-Q src Src\n",
+        "-native-compiler maybe\n",
+        "-I\n",
+        "-custom foo\n",
+        "README.md\n",
+        "VAR =\n",
+    ));
+
+    assert_eq!(
+        options,
+        [(2, ProjectOption::Qualified("src".into(), "Src".into()))]
+    );
+    assert_eq!(
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.line)
+            .collect::<Vec<_>>(),
+        [1, 3, 4, 5, 5, 6, 7]
+    );
+    assert!(
+        diagnostics[0].message.contains("`-R` expects 2 arguments"),
+        "{:?}",
+        diagnostics
+    );
+}
+
+#[test]
+fn included_project_files_and_coqtop_flags() {
+    let root = std::env::temp_dir().join(format!("coqide-coqproject-{}", std::process::id()));
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(
+        root.join("_CoqProject"),
+        "-R theories Foo\n-f sub/_Common\n-f missing\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("sub/_Common"),
+        "-Q . Common\n-arg -noinit\n-bogus\n",
+    )
+    .unwrap();
+
+    let project = CoqProject::read(root.join("_CoqProject")).unwrap();
+
+    assert_eq!(
+        project.coqtop_flags(),
+        [
+            "-R".to_string(),
+            root.join("theories").display().to_string(),
+            "Foo".to_string(),
+            "-Q".to_string(),
+            root.join(".").display().to_string(),
+            "Common".to_string(),
+            "-noinit".to_string(),
+        ]
+    );
+    assert_eq!(
+        project
+            .diagnostics
+            .iter()
+            .map(|(path, diagnostic)| (path.clone(), diagnostic.line))
+            .collect::<Vec<_>>(),
+        [(root.join("sub/_Common"), 3), (root.join("_CoqProject"), 3)]
+    );
+    assert!(Project::CoqProject(project).report().is_some());
+}
+
+#[test]
+fn nothing_to_report_for_a_valid_project() {
+    let project = CoqProject::parse(PathBuf::from("/project/_CoqProject"), PROJECT);
+
+    assert_eq!(project.files().count(), 4);
+    assert_eq!(
+        project.coqtop_flags(),
+        [
+            "-R",
+            "/project/theories",
+            "Foo",
+            "-Q",
+            "/project/src with spaces",
+            "Foo.Bar",
+            "-I",
+            "/project/src",
+            "-w",
+            "-notation-overridden",
+            "-noinit",
+            "-native-compiler",
+            "ondemand",
+        ]
+    );
+    assert!(Project::CoqProject(project).report().is_none());
+}
+
+#[test]
+fn module_names_follow_the_deepest_mapping() {
+    let project = Project::CoqProject(CoqProject::parse(
+        PathBuf::from("/project/_CoqProject"),
+        "-R theories Foo\n-Q ./theories/sub Bar.Baz\n-Q vendor \"\"\n",
+    ));
+
+    assert_eq!(
+        project.module_name("/project/theories/A.v").as_deref(),
+        Some("Foo.A")
+    );
+    assert_eq!(
+        project
+            .module_name("/project/theories/sub/dir/B.v")
+            .as_deref(),
+        Some("Bar.Baz.dir.B")
+    );
+    assert_eq!(
+        project.module_name("/project/vendor/C.v").as_deref(),
+        Some("C")
+    );
+    assert_eq!(project.module_name("/project/theories/my-file.v"), None);
+    assert_eq!(project.module_name("/elsewhere/D.v"), None);
+}
+
+#[test]
+fn scratch_buffers_get_valid_module_names() {
+    assert_eq!(synthetic_module_name("*scratch*"), "scratch");
+    assert_eq!(synthetic_module_name("my notes.v"), "my_notes");
+    assert_eq!(synthetic_module_name("*1*"), "Scratch1");
+    assert_eq!(synthetic_module_name(""), "Scratch");
+}
+
+#[tokio::test]
+async fn modified_project_files_are_noticed() {
+    let root = std::env::temp_dir().join(format!("coqide-watch-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("_CoqProject"), "-R . Foo\n-f _Common\n").unwrap();
+    fs::write(root.join("_Common"), "-arg -noinit\n").unwrap();
+
+    let project = Project::CoqProject(CoqProject::read(root.join("_CoqProject")).unwrap());
+    assert_eq!(
+        project.sources(),
+        [root.join("_CoqProject"), root.join("_Common")]
+    );
+
+    let changes = project.watch();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    fs::write(root.join("_Common"), "-arg -noinit\n-Q lib Lib\n").unwrap();
+
+    let changed = tokio::time::timeout(Duration::from_secs(5), changes)
+        .await
+        .expect("the modification should be noticed")
+        .unwrap();
+    assert_eq!(changed, root.join("_Common"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cached_projects_are_read_again_once_modified() {
+    let root = std::env::temp_dir().join(format!("coqide-cache-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("_CoqProject"), "-R . Foo\n").unwrap();
+    let file = root.join("A.v").display().to_string();
+    fs::write(&file, "").unwrap();
+
+    let options = Options::try_parse_from([
+        "coqide-daemon",
+        "--client",
+        "client0",
+        "--session",
+        "1234",
+        "--file",
+        &file,
+        "--tmp-dir",
+        "/tmp",
+        "--socket",
+        "/tmp/input.sock",
+        "--no-config",
+    ])
+    .unwrap();
+    let config = Config::load(&options).unwrap();
+    let cache = ProjectCache::default();
+
+    let project = cache.load(&file, &config).await.unwrap();
+    assert_eq!(project.module_name(&file).as_deref(), Some("Foo.A"));
+    let project = cache.load(&file, &config).await.unwrap();
+    assert_eq!(project.coqtop_flags().len(), 3);
+
+    // NOTE: modification times may not be more precise than a second
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    fs::write(root.join("_CoqProject"), "-R . Bar\n").unwrap();
+
+    let project = cache.load(&file, &config).await.unwrap();
+    assert_eq!(project.module_name(&file).as_deref(), Some("Bar.A"));
+}
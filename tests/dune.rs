@@ -0,0 +1,170 @@
+//! Reading `coq.theory` stanzas of dune projects, and mapping theories to their build directories.
+
+use std::{fs, path::Path};
+
+use daemon::coqtop::{
+    coqproject::parser::Diagnostic,
+    dune::DuneProject,
+    project::Project,
+    sexp::{parse, Sexp},
+};
+
+fn atom(text: &str) -> Sexp {
+    Sexp::Atom(text.to_string())
+}
+
+#[test]
+fn atoms_strings_and_comments() {
+    let sexps = parse(concat!(
+        "; a comment (\n",
+        "(coq.theory #| (name Ignored) |#\n",
+        " (name Foo.Bar)\n",
+        " (flags \"-w\" \"-notation \\\"x\\\"\"))\n",
+        "(lang dune 3.8)",
+    ))
+    .unwrap();
+
+    assert_eq!(
+        sexps,
+        [
+            Sexp::List(vec![
+                atom("coq.theory"),
+                Sexp::List(vec![atom("name"), atom("Foo.Bar")]),
+                Sexp::List(vec![atom("flags"), atom("-w"), atom("-notation \"x\"")]),
+            ]),
+            Sexp::List(vec![atom("lang"), atom("dune"), atom("3.8")]),
+        ]
+    );
+    assert_eq!(sexps[0].field("coq.theory").map(<[_]>::len), Some(2));
+    assert_eq!(sexps[1].field("coq.theory"), None);
+}
+
+#[test]
+fn escaped_bytes_and_printing() {
+    let sexps = parse(r#"(Pp_string "\226\136\128 n, n = n\n")"#).unwrap();
+    assert_eq!(
+        sexps,
+        [Sexp::List(vec![atom("Pp_string"), atom("∀ n, n = n\n")])]
+    );
+
+    let printed = Sexp::List(vec![
+        atom("Add"),
+        Sexp::List(vec![]),
+        atom("Definition a := \"(\\)\"."),
+    ])
+    .to_string();
+    assert_eq!(printed, r#"(Add () "Definition a := \"(\\)\".")"#);
+    assert_eq!(
+        parse(&printed).unwrap()[0].field("Add").unwrap()[1],
+        atom("Definition a := \"(\\)\".")
+    );
+}
+
+#[test]
+fn escapes_in_dune_files() {
+    let sexps = parse(concat!(
+        "(coq.theory\n",
+        " (name Foo)\n",
+        r#" (flags "-w" "-notation \"∀\"" "\226\136\128\t\065" "\\\q\12"))"#,
+    ))
+    .unwrap();
+
+    assert_eq!(
+        sexps[0].field("coq.theory").unwrap()[1],
+        Sexp::List(vec![
+            atom("flags"),
+            atom("-w"),
+            // Characters which are not escaped are kept as they are.
+            atom("-notation \"∀\""),
+            // Bytes escaped as 3 decimal digits are decoded as UTF-8.
+            atom("∀\tA"),
+            // Other escaped characters stand for themselves.
+            atom("\\q12"),
+        ])
+    );
+}
+
+#[test]
+fn malformed_files_are_reported_with_their_line() {
+    assert_eq!(
+        parse("(library\n (name foo))\n\n(coq.theory\n (name Foo)"),
+        Err(Diagnostic {
+            line: 4,
+            message: "Cannot parse S-expression starting with `(`".to_string()
+        })
+    );
+
+    assert!(DuneProject::read(Path::new("/nonexistent/dune-project"), "/").is_err());
+}
+
+#[test]
+fn theories_are_mapped_to_their_build_directories() {
+    let root = std::env::temp_dir().join(format!("coqide-dune-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let root = root.canonicalize().unwrap();
+    for dir in ["theories/sub", "lib", "vendor", "_build/default/theories"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    fs::write(
+        root.join("dune-project"),
+        "(lang dune 3.8)\n(using coq 0.8)\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("theories/dune"),
+        "(coq.theory\n (name Foo)\n (theories Bar Stdlib))\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("lib/dune"),
+        "(coq.theory (name Bar) (theories Baz))",
+    )
+    .unwrap();
+    fs::write(root.join("vendor/dune"), "(coq.theory (name Baz))\n(rule").unwrap();
+    fs::write(
+        root.join("_build/default/theories/dune"),
+        "(coq.theory (name Foo))",
+    )
+    .unwrap();
+    fs::write(root.join("theories/sub/A.v"), "").unwrap();
+
+    let file = root.join("theories/sub/A.v").display().to_string();
+    let project = DuneProject::read(root.join("dune-project"), &file).unwrap();
+
+    assert_eq!(
+        project
+            .theories
+            .iter()
+            .map(|theory| theory.name.as_str())
+            .collect::<Vec<_>>(),
+        ["Bar", "Foo"]
+    );
+    assert_eq!(
+        project.theory().map(|theory| theory.name.as_str()),
+        Some("Foo")
+    );
+    assert_eq!(
+        project.coqtop_flags(),
+        [
+            "-R".to_string(),
+            root.join("_build/default/theories").display().to_string(),
+            "Foo".to_string(),
+            "-Q".to_string(),
+            root.join("_build/default/lib").display().to_string(),
+            "Bar".to_string(),
+        ]
+    );
+
+    let project = Project::Dune(project);
+    assert_eq!(project.path(), root.join("dune-project"));
+    assert_eq!(project.module_name(&file).as_deref(), Some("Foo.sub.A"));
+    assert_eq!(
+        project
+            .diagnostics()
+            .iter()
+            .map(|(path, diagnostic)| (path.clone(), diagnostic.line))
+            .collect::<Vec<_>>(),
+        [(root.join("vendor/dune"), 2)]
+    );
+    assert!(project.report().is_some());
+}
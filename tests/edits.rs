@@ -0,0 +1,106 @@
+//! Edits reported by Kakoune, and the translation of ranges computed before them.
+
+mod common;
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use common::{at, edit};
+use daemon::{
+    client::commands::{decode::CommandDecoder, protocol::decode_request, types::ClientCommand},
+    range::{Edit, Range},
+    state::State,
+};
+
+fn edits(command: Option<ClientCommand>) -> (u64, Vec<Edit>) {
+    match command {
+        Some(ClientCommand::Edited(timestamp, edits)) => (timestamp, edits),
+        command => panic!("unexpected {:?}", command),
+    }
+}
+
+#[test]
+fn edits_are_decoded_with_their_extents() {
+    let mut src = BytesMut::from("edited 7 3.5,3.5,3.9 4.1,5.2,4.1 2.3,2.1 6.4\n");
+    let (_, command) = CommandDecoder::default().decode(&mut src).unwrap().unwrap();
+
+    assert_eq!(
+        edits(command.ok()),
+        (
+            7,
+            vec![
+                edit(at(3, 5), at(3, 5), at(3, 9)),
+                edit(at(4, 1), at(5, 2), at(4, 1)),
+                // A (reversed) selection, around an edit whose extent is unknown.
+                Edit::unknown_from(at(2, 1)),
+                // The position where an edit whose extent is unknown begins.
+                Edit::unknown_from(at(6, 4)),
+            ]
+        )
+    );
+
+    let (_, command) = decode_request(
+        concat!(
+            r#"{"version": 1, "id": 1, "command": "edited", "timestamp": 8, "edits": [{"begin": {"line": 1, "column": 2}, "#,
+            r#""removed-end": {"line": 1, "column": 2}, "inserted-end": {"line": 2, "column": 1}}]}"#
+        )
+        .as_bytes(),
+    );
+    assert_eq!(
+        edits(command.ok()),
+        (8, vec![edit(at(1, 2), at(1, 2), at(2, 1))])
+    );
+}
+
+#[test]
+fn ranges_after_an_insertion_are_shifted() {
+    let mut state = State::new();
+    let statement = Range::new(3, 5, 3, 14).at(1);
+
+    // `ab` typed before the statement, on the same line.
+    state.record_edit(2, edit(at(3, 1), at(3, 1), at(3, 3)));
+    assert_eq!(
+        state.translate(statement),
+        Some(Range::new(3, 7, 3, 16).at(2))
+    );
+
+    // A new line inserted before the statement.
+    state.record_edit(3, edit(at(1, 4), at(1, 4), at(2, 1)));
+    assert_eq!(
+        state.translate(statement),
+        Some(Range::new(4, 7, 4, 16).at(3))
+    );
+
+    // Ranges computed after the edits are not moved again.
+    let later = Range::new(4, 7, 4, 16).at(3);
+    assert_eq!(state.translate(later), Some(later));
+}
+
+#[test]
+fn ranges_after_a_removal_are_shifted() {
+    let mut state = State::new();
+    let statement = Range::new(5, 3, 6, 2).at(1);
+
+    // The end of line 2 up to the beginning of line 5 removed.
+    state.record_edit(2, edit(at(2, 8), at(5, 1), at(2, 8)));
+    assert_eq!(
+        state.translate(statement),
+        Some(Range::new(2, 10, 3, 2).at(2))
+    );
+}
+
+#[test]
+fn ranges_before_an_edit_are_kept_and_modified_ranges_are_lost() {
+    let mut state = State::new();
+    let before = Range::new(1, 1, 1, 10).at(1);
+    let modified = Range::new(2, 1, 2, 10).at(1);
+
+    state.record_edit(2, edit(at(2, 4), at(2, 6), at(2, 5)));
+    assert_eq!(state.translate(before), Some(before.at(2)));
+    assert_eq!(state.translate(modified), None);
+
+    // When only the beginning is known, everything after it is lost.
+    state.record_edit(3, Edit::unknown_from(at(1, 20)));
+    assert_eq!(state.translate(before), Some(before.at(3)));
+    assert_eq!(state.translate(Range::new(7, 1, 7, 2).at(1)), None);
+}
@@ -0,0 +1,153 @@
+//! Every client command and every event must be handled explicitly in each error state.
+
+use daemon::{
+    client::commands::types::{ClientCommand, GoalCategory},
+    error_state::{ErrorEvent, ErrorState, ErrorStateMachine},
+    range::Range,
+    state::Operation,
+};
+
+const STATES: [ErrorState; 3] = [ErrorState::Ok, ErrorState::Error, ErrorState::Interrupted];
+
+/// One sample of each client command, along with whether it is accepted in
+/// the states `Ok`, `Error` and `Interrupted` (in this order).
+fn commands() -> Vec<(ClientCommand, [bool; 3])> {
+    vec![
+        (ClientCommand::Init, [true, true, true]),
+        (ClientCommand::Quit, [true, true, true]),
+        (ClientCommand::Previous, [true, true, false]),
+        (ClientCommand::RewindTo(1, 1), [true, true, false]),
+        (
+            ClientCommand::Query("Check nat.".to_string()),
+            [true, true, true],
+        ),
+        (ClientCommand::MoveTo(vec![]), [true, false, false]),
+        (
+            ClientCommand::Next(false, Range::default(), "Check nat.".to_string()),
+            [true, false, false],
+        ),
+        (ClientCommand::IgnoreError, [false, true, false]),
+        (ClientCommand::Hints, [true, true, true]),
+        (
+            ClientCommand::ShowGoals(Range::default()),
+            [true, true, false],
+        ),
+        (
+            ClientCommand::BackTo(Operation::default()),
+            [true, true, true],
+        ),
+        (ClientCommand::Status, [true, true, true]),
+        (ClientCommand::StopInterrupt, [true, true, true]),
+        (
+            ClientCommand::ToggleGoals(GoalCategory::Shelved),
+            [true, true, true],
+        ),
+        (ClientCommand::MessagesAt(1, 1), [true, true, true]),
+        (ClientCommand::Edited(1, vec![]), [true, true, true]),
+        (ClientCommand::ShowConfig, [true, true, true]),
+        (ClientCommand::Restart(true), [true, true, true]),
+    ]
+}
+
+#[test]
+fn commands_accepted_in_each_state() {
+    for (command, expected) in commands() {
+        for (state, expected) in STATES.into_iter().zip(expected) {
+            let accepted = ErrorStateMachine::from(state).accepts(&command);
+            assert_eq!(
+                accepted.is_ok(),
+                expected,
+                "{:?} in state {:?}: {:?}",
+                command,
+                state,
+                accepted
+            );
+        }
+    }
+}
+
+#[test]
+fn responses_accepted_in_each_state() {
+    for (command, _) in commands() {
+        let always = matches!(
+            command,
+            ClientCommand::Init
+                | ClientCommand::Quit
+                | ClientCommand::Previous
+                | ClientCommand::BackTo(_)
+        );
+
+        for state in STATES {
+            let accepted = ErrorStateMachine::from(state).accepts_response(&command);
+            assert_eq!(
+                accepted.is_ok(),
+                always || state == ErrorState::Ok,
+                "response to {:?} in state {:?}: {:?}",
+                command,
+                state,
+                accepted
+            );
+        }
+    }
+}
+
+#[test]
+fn feedback_accepted_only_without_error() {
+    for state in STATES {
+        assert_eq!(
+            ErrorStateMachine::from(state).accepts_feedback().is_ok(),
+            state == ErrorState::Ok
+        );
+    }
+}
+
+#[test]
+fn transitions() {
+    use ErrorEvent::{Cleared, Failed, Resumed};
+    use ErrorState::{Error, Ok};
+
+    // (from, event, to), where `None` means that the event is rejected.
+    let table = [
+        (Ok, Failed, Some(Error)),
+        (Error, Failed, Some(Error)),
+        (ErrorState::Interrupted, Failed, None),
+        (Ok, ErrorEvent::Interrupted, Some(ErrorState::Interrupted)),
+        (
+            Error,
+            ErrorEvent::Interrupted,
+            Some(ErrorState::Interrupted),
+        ),
+        (
+            ErrorState::Interrupted,
+            ErrorEvent::Interrupted,
+            Some(ErrorState::Interrupted),
+        ),
+        (Ok, Resumed, Some(Ok)),
+        (Error, Resumed, None),
+        (ErrorState::Interrupted, Resumed, Some(Ok)),
+        (Ok, Cleared, Some(Ok)),
+        (Error, Cleared, Some(Ok)),
+        (ErrorState::Interrupted, Cleared, None),
+    ];
+
+    for (from, event, to) in table {
+        assert_eq!(
+            Result::ok(ErrorStateMachine::next(from, event)),
+            to,
+            "{:?} on {:?}",
+            from,
+            event
+        );
+
+        // A rejected event leaves the state unchanged.
+        let mut machine = ErrorStateMachine::from(from);
+        assert_eq!(machine.transition(event), to.unwrap_or(from));
+        assert_eq!(machine.state(), to.unwrap_or(from));
+    }
+}
+
+#[test]
+fn starts_without_error() {
+    assert_eq!(ErrorStateMachine::new().state(), ErrorState::Ok);
+    assert_eq!(ErrorStateMachine::default().state(), ErrorState::Ok);
+}
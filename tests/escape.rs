@@ -0,0 +1,117 @@
+//! Strings sent by `coq-parser` must reach the daemon untouched, whatever bytes they contain and however
+//! they are split between reads.
+
+mod common;
+
+use std::fs;
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use common::{parse_statements, text_at};
+use daemon::{
+    client::commands::{decode::CommandDecoder, escape::escape, types::ClientCommand},
+    error::ClientError,
+};
+
+/// Feeds a `next` command carrying the given escaped code to the decoder one byte at a time,
+/// returning the decoded code.
+fn decode_byte_by_byte(escaped: &str) -> Result<String, ClientError> {
+    let line = format!("next false 1 1.1,1.1 \"{}\"\n", escaped);
+    let mut decoder = CommandDecoder::default();
+    let mut src = BytesMut::new();
+
+    for byte in line.bytes() {
+        src.extend_from_slice(&[byte]);
+        if let Some((_, command)) = decoder.decode(&mut src).unwrap() {
+            assert!(src.is_empty(), "{:?} was not decoded", src);
+            return command.map(|command| match command {
+                ClientCommand::Next(_, _, code) => code,
+                command => panic!("unexpected {:?}", command),
+            });
+        }
+    }
+    panic!("{:?} was never decoded", line)
+}
+
+#[test]
+fn every_statement_of_the_test_files_round_trips() {
+    let mut files = 0;
+    for entry in fs::read_dir("test").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "v") {
+            continue;
+        }
+        let content = fs::read_to_string(&path).unwrap();
+        files += 1;
+
+        let statements = parse_statements(&content);
+        assert!(!statements.is_empty(), "no statement in {}", path.display());
+        for (range, code) in statements {
+            assert_eq!(
+                text_at(&content, range),
+                code,
+                "at {:?} in {}",
+                range,
+                path.display()
+            );
+        }
+
+        assert_eq!(decode_byte_by_byte(&escape(&content)).unwrap(), content);
+    }
+    assert!(files > 0);
+}
+
+#[test]
+fn tricky_strings_round_trip() {
+    let strings = [
+        "",
+        "\\",
+        "\\\\n",
+        "\"\"",
+        "Check \"a\\\"b\".",
+        "line\nline\r\nline",
+        "\ttab",
+        "∀ α, α → β.",
+        "§ and %{…}",
+        "Notation \"x ⊕ y\" := (x + y).",
+    ];
+
+    for string in strings {
+        assert_eq!(decode_byte_by_byte(&escape(string)).unwrap(), string);
+        assert!(!escape(string).contains('\n'));
+    }
+}
+
+#[test]
+fn invalid_strings_are_rejected_without_losing_the_next_command() {
+    let lines: [&[u8]; 3] = [
+        b"next false 1 1.1,1.2 \"\\q\"\n",
+        b"next false 1 1.1,1.2 \"\xff\"\n",
+        b"next false 1 1.1,1.2 \"a\nb\"\n",
+    ];
+
+    for line in lines {
+        let mut decoder = CommandDecoder::default();
+        let mut src = BytesMut::from(line);
+        src.extend_from_slice(b"status\n");
+
+        let mut commands = Vec::new();
+        while let Some((_, command)) = decoder.decode(&mut src).unwrap() {
+            commands.push(command);
+        }
+
+        assert!(
+            matches!(commands.first(), Some(Err(ClientError::InvalidCommand(_)))),
+            "{:?} gave {:?}",
+            String::from_utf8_lossy(line),
+            commands
+        );
+        assert!(
+            matches!(commands.last(), Some(Ok(ClientCommand::Status))),
+            "{:?} gave {:?}",
+            String::from_utf8_lossy(line),
+            commands
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Rendering the goals of `test/Shelf.v` in the goal buffer, at every step of the proof.
+
+mod common;
+
+use std::collections::HashSet;
+
+use common::{goal, goals};
+use daemon::{
+    client::commands::types::GoalCategory, coqtop::backend::Goals,
+    kakoune::ui_updater::render_goals,
+};
+
+/// A step of a proof: the statement processed, the goals `coqidetop` answers with, and the whole content of the
+/// goal buffer, first with no category expanded, then with all of them expanded.
+type Step = (&'static str, Goals, &'static str, &'static str);
+
+fn replay(steps: Vec<Step>) {
+    let all = [
+        GoalCategory::Background,
+        GoalCategory::Shelved,
+        GoalCategory::GivenUp,
+    ]
+    .into_iter()
+    .collect::<HashSet<_>>();
+
+    for (statement, goals, collapsed, expanded) in steps {
+        assert_eq!(
+            render_goals(goals.clone(), &HashSet::new()).0,
+            collapsed,
+            "after `{}`",
+            statement
+        );
+        assert_eq!(
+            render_goals(goals, &all).0,
+            expanded,
+            "after `{}` with all goals expanded",
+            statement
+        );
+    }
+}
+
+/// The steps of `test/Shelf.v` up to `eexists _, 0.`, which shelves the goal of `?x`.
+fn shelf_steps() -> Vec<Step> {
+    let theorem = || {
+        goals(
+            vec![goal("1", "exists x n : nat, n * x = 0")],
+            vec![],
+            vec![],
+            vec![],
+        )
+    };
+    let shelved = goals(
+        vec![goal("4", "0 * ?x = 0")],
+        vec![],
+        vec![goal("3", "nat")],
+        vec![],
+    );
+
+    vec![
+        (
+            "Theorem useless : exists (x n : nat), n*x = 0.",
+            theorem(),
+            "1 subgoal(s) remaining:\n\n───────────────────────────── (1)\n exists x n : nat, n * x = 0 \n",
+            "1 subgoal(s) remaining:\n\n───────────────────────────── (1)\n exists x n : nat, n * x = 0 \n",
+        ),
+        (
+            "Proof.",
+            theorem(),
+            "1 subgoal(s) remaining:\n\n───────────────────────────── (1)\n exists x n : nat, n * x = 0 \n",
+            "1 subgoal(s) remaining:\n\n───────────────────────────── (1)\n exists x n : nat, n * x = 0 \n",
+        ),
+        (
+            "eexists _, 0.",
+            shelved,
+            "1 subgoal(s) remaining:\n\n──────────── (4)\n 0 * ?x = 0 \n\n(shelved: 1)\n",
+            "1 subgoal(s) remaining:\n\n──────────── (4)\n 0 * ?x = 0 \n\n(shelved: 1)\n\nShelved goals:\n\n───── (3)\n nat \n",
+        ),
+    ]
+}
+
+#[test]
+fn shelf_renders_at_every_step() {
+    let mut steps = shelf_steps();
+    steps.extend([
+        (
+            "lia.",
+            goals(vec![], vec![], vec![goal("3", "nat")], vec![]),
+            "There are no more subgoals, but some goals remain shelved:\n\n───── (3)\n nat \n\n(shelved: 1)\n",
+            "There are no more subgoals, but some goals remain shelved:\n\n───── (3)\n nat \n\n(shelved: 1)\n",
+        ),
+        (
+            "Unshelve.",
+            goals(vec![goal("3", "nat")], vec![], vec![], vec![]),
+            "1 subgoal(s) remaining:\n\n───── (3)\n nat \n",
+            "1 subgoal(s) remaining:\n\n───── (3)\n nat \n",
+        ),
+        (
+            "exact 5.",
+            goals(vec![], vec![], vec![], vec![]),
+            "There are no more subgoals.\nProof is complete.\n",
+            "There are no more subgoals.\nProof is complete.\n",
+        ),
+    ]);
+
+    replay(steps);
+}
+
+#[test]
+fn shelf_renders_at_every_step_when_giving_up() {
+    // `test/Shelf.v`, with `give_up.` instead of `lia.`
+    let mut steps = shelf_steps();
+    steps.extend([
+        (
+            "give_up.",
+            goals(
+                vec![],
+                vec![],
+                vec![goal("3", "nat")],
+                vec![goal("4", "0 * ?x = 0")],
+            ),
+            "There are no more subgoals, but some goals remain shelved:\n\n───── (3)\n nat \n\n(shelved: 1, given up: 1)\n",
+            "There are no more subgoals, but some goals remain shelved:\n\n───── (3)\n nat \n\n(shelved: 1, given up: 1)\n\nGiven up goals:\n\n──────────── (4)\n 0 * ?x = 0 \n",
+        ),
+        (
+            "Unshelve.",
+            goals(
+                vec![goal("3", "nat")],
+                vec![],
+                vec![],
+                vec![goal("4", "0 * ?x = 0")],
+            ),
+            "1 subgoal(s) remaining:\n\n───── (3)\n nat \n\n(given up: 1)\n",
+            "1 subgoal(s) remaining:\n\n───── (3)\n nat \n\n(given up: 1)\n\nGiven up goals:\n\n──────────── (4)\n 0 * ?x = 0 \n",
+        ),
+        (
+            "exact 5.",
+            goals(vec![], vec![], vec![], vec![goal("4", "0 * ?x = 0")]),
+            "There are no more subgoals, but there are some given up goals:\n\n──────────── (4)\n 0 * ?x = 0 \n\n(given up: 1)\n",
+            "There are no more subgoals, but there are some given up goals:\n\n──────────── (4)\n 0 * ?x = 0 \n\n(given up: 1)\n",
+        ),
+    ]);
+
+    replay(steps);
+}
+
+#[test]
+fn unfocused_goals_are_counted() {
+    replay(vec![(
+        "exact I.",
+        goals(
+            vec![],
+            vec![(vec![goal("1", "True")], vec![goal("2", "True")])],
+            vec![goal("3", "nat")],
+            vec![goal("4", "False")],
+        ),
+        "The current subgoal is complete, but there are unfinished subgoals:\n\n────── (1)\n True \n\n────── (2)\n True \n\n(unfocused: 2, shelved: 1, given up: 1)\n",
+        "The current subgoal is complete, but there are unfinished subgoals:\n\n────── (1)\n True \n\n────── (2)\n True \n\n(unfocused: 2, shelved: 1, given up: 1)\n\nShelved goals:\n\n───── (3)\n nat \n\nGiven up goals:\n\n─────── (4)\n False \n",
+    )]);
+}
@@ -0,0 +1,27 @@
+//! Where the daemon serving all the buffers of a project can be reached.
+
+use std::path::PathBuf;
+
+use daemon::{coqtop::project::Location, manager::manager_dir};
+
+#[test]
+fn folder_does_not_depend_on_the_build() {
+    let location = Location::CoqProject(PathBuf::from("/home/me/proof/_CoqProject"));
+
+    assert_eq!(
+        manager_dir("42", &location),
+        std::env::temp_dir()
+            .join("coqide-42-135ae4dbc6e25925")
+            .display()
+            .to_string()
+    );
+}
+
+#[test]
+fn folder_depends_on_the_session_and_the_project() {
+    let location = Location::CoqProject(PathBuf::from("/home/me/proof/_CoqProject"));
+    let other = Location::Dune(PathBuf::from("/home/me/other/dune-project"));
+
+    assert_ne!(manager_dir("42", &location), manager_dir("43", &location));
+    assert_ne!(manager_dir("42", &location), manager_dir("42", &other));
+}
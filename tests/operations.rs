@@ -0,0 +1,153 @@
+//! The operation store must answer lookups by state ID and by position without any `coqidetop` around.
+
+mod common;
+
+use common::{at, insertion, operation, removal};
+use daemon::{
+    coqtop::xml_protocol::types::ProtocolRichPP,
+    error_state::{ErrorEvent, ErrorState},
+    range::{Edit, Range},
+    state::{Operation, Operations, State},
+};
+
+/// A store with the initial state, followed by 3 statements on lines 1, 2-3 and 5.
+fn store() -> Operations {
+    let mut operations = Operations::new();
+    operations.push(Operation::default());
+    operations.push(operation(2, Range::new(1, 1, 1, 10)));
+    operations.push(operation(3, Range::new(2, 1, 3, 10)));
+    operations.push(operation(5, Range::new(5, 1, 5, 10)));
+    operations
+}
+
+fn state_ids<'a, I: IntoIterator<Item = &'a Operation>>(operations: I) -> Vec<i64> {
+    operations.into_iter().map(|op| op.state_id).collect()
+}
+
+#[test]
+fn tip_is_the_last_operation_pushed() {
+    let mut operations = store();
+
+    assert_eq!(operations.len(), 4);
+    assert_eq!(operations.tip().map(|op| op.state_id), Some(5));
+    assert_eq!(operations.before_tip().map(|op| op.state_id), Some(3));
+
+    assert_eq!(operations.pop().map(|op| op.state_id), Some(5));
+    assert_eq!(operations.tip().map(|op| op.state_id), Some(3));
+    assert_eq!(state_ids(operations.iter()), vec![1, 2, 3]);
+}
+
+#[test]
+fn lookup_by_state_id() {
+    let mut operations = store();
+
+    assert_eq!(operations.get(3).map(|op| op.range.begin.line), Some(2));
+    assert!(operations.get(4).is_none());
+    assert!(operations.contains(1));
+    assert!(!operations.contains(6));
+
+    operations.get_mut(2).unwrap().has_axiom = true;
+    assert!(operations.get(2).unwrap().has_axiom);
+}
+
+#[test]
+fn lookup_by_cursor() {
+    let operations = store();
+    let lookup = |line, column| operations.at(at(line, column), Some).map(|op| op.state_id);
+
+    // The initial state is never preferred over the first statement.
+    assert_eq!(lookup(1, 1), Some(2));
+    assert_eq!(lookup(1, 10), Some(2));
+    assert_eq!(lookup(3, 4), Some(3));
+    assert_eq!(lookup(4, 1), None);
+    assert_eq!(lookup(5, 11), None);
+    assert_eq!(lookup(9, 1), None);
+}
+
+#[test]
+fn last_operation_before_cursor() {
+    let operations = store();
+    let before = |line, column| {
+        operations
+            .last_before(at(line, column), Some)
+            .map(|op| op.state_id)
+    };
+
+    assert_eq!(before(1, 1), None);
+    assert_eq!(before(1, 5), Some(1));
+    assert_eq!(before(1, 11), Some(2));
+    assert_eq!(before(3, 10), Some(2));
+    assert_eq!(before(4, 1), Some(3));
+    assert_eq!(before(100, 1), Some(5));
+}
+
+#[test]
+fn lookups_follow_edits() {
+    let mut state = State::new();
+    state.operations = store();
+    let lookup = |state: &State, line, column| {
+        state
+            .operations
+            .at(at(line, column), |range| state.translate(range))
+            .map(|op| op.state_id)
+    };
+    let before = |state: &State, line, column| {
+        state
+            .operations
+            .last_before(at(line, column), |range| state.translate(range))
+            .map(|op| op.state_id)
+    };
+
+    // Two lines inserted at the beginning of line 2.
+    state.record_edit(3, insertion(at(2, 1), at(4, 1)));
+    assert_eq!(lookup(&state, 2, 4), None);
+    assert_eq!(lookup(&state, 4, 4), Some(3));
+    assert_eq!(lookup(&state, 7, 1), Some(5));
+    assert_eq!(before(&state, 4, 1), Some(2));
+    assert_eq!(before(&state, 6, 1), Some(3));
+
+    // Part of the second statement removed: it and everything after it have to be processed again.
+    state.record_edit(4, removal(at(4, 2), at(4, 5)));
+    assert_eq!(lookup(&state, 1, 4), Some(2));
+    assert_eq!(lookup(&state, 4, 1), None);
+    assert_eq!(lookup(&state, 7, 1), None);
+    assert_eq!(before(&state, 100, 1), Some(2));
+}
+
+#[test]
+fn truncate_after_state_id() {
+    let mut operations = store();
+
+    let removed = operations.truncate_after(2);
+    assert_eq!(state_ids(&removed), vec![5, 3]);
+    assert_eq!(state_ids(operations.iter()), vec![1, 2]);
+
+    // States which are not known anymore still remove everything after them.
+    operations.push(operation(7, Range::new(2, 1, 2, 10)));
+    operations.push(operation(8, Range::new(3, 1, 3, 10)));
+    let removed = operations.truncate_after(4);
+    assert_eq!(state_ids(&removed), vec![8, 7]);
+
+    assert!(operations.truncate_after(10).is_empty());
+    assert_eq!(state_ids(operations.iter()), vec![1, 2]);
+}
+
+#[test]
+fn reset_keeps_what_is_known_about_the_buffer() {
+    let mut state = State::new();
+    state.operations = store();
+    state.record_edit(4, Edit::unknown_from(at(7, 1)));
+    state.record_message(3, ProtocolRichPP::RichPP(vec![]));
+    state.last_error_range = Some(Range::new(6, 1, 6, 5));
+    state.error_state.transition(ErrorEvent::Failed);
+
+    let operations = state.reset();
+
+    assert_eq!(state_ids(operations.iter()), vec![1, 2, 3, 5]);
+    assert!(state.operations.is_empty());
+    assert!(state.messages.is_empty());
+    assert_eq!(state.last_error_range, None);
+    assert_eq!(state.error_state.state(), ErrorState::Ok);
+    assert_eq!(state.buffer_timestamp, 4);
+    assert_eq!(state.edits.len(), 1);
+}
@@ -0,0 +1,102 @@
+//! The command-line interface of `coqide-daemon`.
+
+use std::time::Duration;
+
+use clap::Parser;
+use log::LevelFilter;
+
+use daemon::options::Options;
+
+const REQUIRED: [&str; 11] = [
+    "coqide-daemon",
+    "--client",
+    "client0",
+    "--session",
+    "1234",
+    "--file",
+    "A.v",
+    "--tmp-dir",
+    "/tmp/coqide",
+    "--socket",
+    "/tmp/coqide/input.sock",
+];
+
+#[test]
+fn flags_and_coq_flags() {
+    let options = Options::try_parse_from(REQUIRED.into_iter().chain([
+        "--log-level",
+        "warn",
+        "--timeout",
+        "2.5",
+        "--",
+        "-noinit",
+        "-R",
+        ".",
+        "Foo",
+    ]))
+    .unwrap();
+
+    assert_eq!(options.client, "client0");
+    assert_eq!(options.file, "A.v");
+    assert_eq!(options.log_level, Some(LevelFilter::Warn));
+    assert_eq!(options.timeout, Some(Duration::from_millis(2500)));
+    assert_eq!(options.coq_flags, ["-noinit", "-R", ".", "Foo"]);
+    assert!(!options.no_project_file);
+}
+
+#[test]
+fn invalid_flags_are_rejected() {
+    let missing_socket = REQUIRED[..9].to_vec();
+    let extra: [&[&str]; 5] = [
+        &["--timeout", "0"],
+        &["--max-coqtops", "0"],
+        &["--attach", "9000"],
+        &["--log-level", "loud"],
+        &["--project-file", "_CoqProject", "--no-project-file"],
+    ];
+
+    for args in extra
+        .into_iter()
+        .map(|flags| [&REQUIRED[..], flags].concat())
+        .chain([missing_socket])
+    {
+        assert!(Options::try_parse_from(&args).is_err(), "{:?}", args);
+    }
+}
+
+#[test]
+fn environment_is_resolved_before_forwarding() {
+    // NOTE: no other test relies on these variables
+    std::env::set_var("COQTOP", "/opt/coq/bin/coqidetop");
+    std::env::set_var("COQIDE_TCP", "true");
+    std::env::set_var("COQIDE_DUNE_COQ_TOP", "false");
+
+    let args = REQUIRED[1..]
+        .iter()
+        .map(ToString::to_string)
+        .chain(["--".to_string(), "-noinit".to_string()])
+        .collect::<Vec<_>>();
+    let forwarded = Options::forwarded_args(&args);
+
+    std::env::remove_var("COQTOP");
+    std::env::remove_var("COQIDE_TCP");
+    std::env::remove_var("COQIDE_DUNE_COQ_TOP");
+
+    assert_eq!(forwarded[..2], ["--coqtop=/opt/coq/bin/coqidetop", "--tcp"]);
+    let options = Options::try_parse_forwarded(forwarded).unwrap();
+    assert_eq!(options.coqtop.as_deref(), Some("/opt/coq/bin/coqidetop"));
+    assert!(options.tcp);
+    assert!(!options.dune_coq_top);
+    assert_eq!(options.coq_flags, ["-noinit"]);
+}
+
+#[test]
+fn forwarded_arguments_ignore_the_environment() {
+    let args = REQUIRED[1..].iter().map(ToString::to_string).collect();
+
+    std::env::set_var("COQIDE_MAX_COQTOPS", "2");
+    let options = Options::try_parse_forwarded(args);
+    std::env::remove_var("COQIDE_MAX_COQTOPS");
+
+    assert_eq!(options.unwrap().max_coqtops, None);
+}
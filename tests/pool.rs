@@ -0,0 +1,40 @@
+//! Sharing a limited number of `coqidetop` processes between the buffers of a project.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::Notify;
+
+use daemon::coqtop::pool::Pool;
+
+#[tokio::test]
+async fn least_recently_used_buffer_makes_room() {
+    let pool = Pool::new(2);
+    let releases = [(); 3].map(|_| Arc::new(Notify::new()));
+
+    let first = pool.acquire(1, releases[0].clone()).await;
+    let second = pool.acquire(2, releases[1].clone()).await;
+    first.touch();
+    assert_eq!(pool.holders(), [2, 1]);
+
+    let third = {
+        let pool = pool.clone();
+        let release = releases[2].clone();
+        tokio::spawn(async move { pool.acquire(3, release).await })
+    };
+
+    tokio::time::timeout(Duration::from_secs(5), releases[1].notified())
+        .await
+        .expect("the second buffer should be asked to make room");
+    assert_eq!(pool.holders(), [1]);
+
+    drop(second);
+    let third = tokio::time::timeout(Duration::from_secs(5), third)
+        .await
+        .expect("the third buffer should get a process")
+        .unwrap();
+    assert_eq!(pool.holders(), [1, 3]);
+
+    drop(first);
+    drop(third);
+    assert!(pool.holders().is_empty());
+}
@@ -0,0 +1,148 @@
+//! Commands sent by clients, both as JSON requests and in the old line format, and the responses sent back.
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use daemon::{
+    client::commands::{
+        decode::CommandDecoder,
+        protocol::{decode_request, ErrorKind, Origin, Response},
+        types::ClientCommand,
+    },
+    error::ClientError,
+    range::Range,
+};
+
+/// A malformed request, the id which must be kept in the answer, and what the error must be.
+type ErrorCase = (&'static [u8], Option<u64>, fn(&ClientError) -> bool);
+
+/// Decodes all the commands in the given input, which must end with a newline.
+fn decode_all(input: &str) -> Vec<(Origin, Result<ClientCommand, ClientError>)> {
+    let mut decoder = CommandDecoder::default();
+    let mut src = BytesMut::from(input);
+
+    let mut requests = Vec::new();
+    while let Some(request) = decoder.decode(&mut src).unwrap() {
+        requests.push(request);
+    }
+    assert!(src.is_empty(), "{:?} was not decoded", src);
+    requests
+}
+
+#[test]
+fn json_next() {
+    let (origin, command) = decode_request(
+        br#"{"version": 1, "id": 3, "command": "next", "timestamp": 7, "range": {"begin": {"line": 1, "column": 1}, "end": {"line": 2, "column": 4}}, "code": "Check \"nat\"."}"#,
+    );
+
+    assert_eq!(origin, Origin::Json(Some(3)));
+    match command {
+        Ok(ClientCommand::Next(false, range, code)) => {
+            assert_eq!(range, Range::new(1, 1, 2, 4).at(7));
+            assert_eq!(code, "Check \"nat\".");
+        }
+        command => panic!("unexpected {:?}", command),
+    }
+}
+
+#[test]
+fn json_errors_keep_the_id() {
+    let cases: [ErrorCase; 5] = [
+        (b"{not json", None, |err| {
+            matches!(err, ClientError::MalformedRequest(_))
+        }),
+        (
+            br#"{"version": 1, "id": 4, "command": "jump"}"#,
+            Some(4),
+            |err| matches!(err, ClientError::MalformedRequest(_)),
+        ),
+        (
+            br#"{"version": 1, "id": 5, "command": "rewind-to", "line": 1}"#,
+            Some(5),
+            |err| matches!(err, ClientError::MalformedRequest(_)),
+        ),
+        (
+            br#"{"version": 2, "id": 6, "command": "init"}"#,
+            Some(6),
+            |err| matches!(err, ClientError::UnsupportedVersion(2)),
+        ),
+        (br#"{"version": 1, "command": "init"}"#, None, |err| {
+            matches!(err, ClientError::MalformedRequest(_))
+        }),
+    ];
+
+    for (line, id, is_expected) in cases {
+        let (origin, command) = decode_request(line);
+
+        assert_eq!(origin, Origin::Json(id));
+        match command {
+            Err(err) => assert!(is_expected(&err), "unexpected {:?}", err),
+            Ok(command) => panic!("unexpected {:?}", command),
+        }
+    }
+}
+
+#[test]
+fn both_formats_in_the_same_stream() {
+    let requests = decode_all(concat!(
+        "init\n",
+        "{\"version\": 1, \"id\": 1, \"command\": \"toggle-goals\", \"category\": \"given-up\"}\n",
+        "\n",
+        "nonsense 42\n",
+        "{\"version\": 1, \"id\": 2, \"command\": \"edited\", \"timestamp\": 3, \"ranges\": []}\n",
+        "messages-at 3 4\n",
+        "restart false\n",
+        "{\"version\": 1, \"id\": 3, \"command\": \"restart\", \"replay\": true}\n",
+    ));
+
+    let summary = requests
+        .iter()
+        .map(|(origin, command)| {
+            (
+                *origin,
+                command.as_ref().ok().map(|cmd| format!("{:?}", cmd)),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            (Origin::Legacy, Some("Init".to_string())),
+            (
+                Origin::Json(Some(1)),
+                Some("ToggleGoals(GivenUp)".to_string())
+            ),
+            (Origin::Legacy, None),
+            (Origin::Json(Some(2)), Some("Edited(3, [])".to_string())),
+            (Origin::Legacy, Some("MessagesAt(3, 4)".to_string())),
+            (Origin::Legacy, Some("Restart(false)".to_string())),
+            (Origin::Json(Some(3)), Some("Restart(true)".to_string())),
+        ]
+    );
+}
+
+#[test]
+fn incomplete_json_request_waits_for_more_input() {
+    let mut decoder = CommandDecoder::default();
+    let mut src = BytesMut::from(r#"{"version": 1, "id": 1, "comm"#);
+
+    assert!(decoder.decode(&mut src).unwrap().is_none());
+
+    src.extend_from_slice(b"and\": \"status\"}\n");
+    match decoder.decode(&mut src).unwrap() {
+        Some((Origin::Json(Some(1)), Ok(ClientCommand::Status))) => {}
+        request => panic!("unexpected {:?}", request),
+    }
+}
+
+#[test]
+fn responses() {
+    assert_eq!(
+        Response::ack(Some(3)).encode(),
+        "{\"version\":1,\"id\":3,\"status\":\"ack\"}\n"
+    );
+    assert_eq!(
+        Response::error(None, ErrorKind::MalformedRequest, "oops".to_string()).encode(),
+        "{\"version\":1,\"id\":null,\"status\":\"error\",\"kind\":\"malformed-request\",\"message\":\"oops\"}\n"
+    );
+}
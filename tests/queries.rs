@@ -0,0 +1,35 @@
+//! Only `Check`, `Compute`, `Search` and `Print` statements have their results shown inline.
+
+use daemon::coqtop::processor::is_query;
+
+#[test]
+fn queries_are_detected() {
+    for code in [
+        "Check nat.",
+        "Compute 1 + 1.",
+        "Search (_ + _ = _ + _).",
+        "Print nat.",
+        "  Check(nat).",
+        "(* comment *) Print nat.",
+        "(* one *) (* two *)\nCheck nat.",
+    ] {
+        assert!(is_query(code), "{:?} is a query", code);
+    }
+}
+
+#[test]
+fn other_commands_are_not_queries() {
+    for code in [
+        "Checker.",
+        "Searching.",
+        "Printing Coercions.",
+        "Print_nat.",
+        "Computed.",
+        "Definition Check := 0.",
+        "(* Check nat. *) Definition x := 0.",
+        "(* unterminated Check nat.",
+        "",
+    ] {
+        assert!(!is_query(code), "{:?} is not a query", code);
+    }
+}
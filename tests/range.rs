@@ -0,0 +1,180 @@
+//! Positions and ranges, and how they are moved by edits of the buffer.
+
+mod common;
+
+use common::{at, insertion, removal};
+use daemon::range::{ColumnUnit, Edit, Range};
+
+#[test]
+fn ranges_are_ordered_by_position() {
+    let mut ranges = vec![
+        Range::new(2, 1, 2, 5),
+        Range::new(1, 3, 1, 9),
+        Range::new(1, 3, 1, 4),
+    ];
+    ranges.sort();
+
+    assert_eq!(
+        ranges,
+        vec![
+            Range::new(1, 3, 1, 4),
+            Range::new(1, 3, 1, 9),
+            Range::new(2, 1, 2, 5),
+        ]
+    );
+    assert!(at(1, 10) < at(2, 1));
+}
+
+#[test]
+fn ranges_are_only_equal_at_the_same_timestamp_and_in_the_same_unit() {
+    let range = Range::new(1, 1, 1, 5);
+
+    assert_eq!(range.at(3), range.at(3));
+    assert_ne!(range.at(3), range.at(4));
+    assert_ne!(
+        range,
+        Range {
+            unit: ColumnUnit::Char,
+            ..range
+        }
+    );
+}
+
+#[test]
+fn contains_includes_both_ends() {
+    let range = Range::new(1, 5, 2, 3);
+
+    assert!(range.contains(at(1, 5)));
+    assert!(range.contains(at(1, 80)));
+    assert!(range.contains(at(2, 3)));
+    assert!(!range.contains(at(1, 4)));
+    assert!(!range.contains(at(2, 4)));
+
+    // A single character.
+    let single = Range::new(3, 2, 3, 2);
+    assert!(single.contains(at(3, 2)));
+    assert!(!single.contains(at(3, 3)));
+
+    // Reversed ranges contain nothing until normalized.
+    let reversed = Range::new(2, 3, 1, 5);
+    assert!(!reversed.contains(at(1, 8)));
+    assert_eq!(reversed.normalized(), range);
+    assert!(reversed.normalized().contains(at(1, 8)));
+}
+
+#[test]
+fn intersects_when_sharing_a_position() {
+    let range = Range::new(1, 5, 2, 3);
+
+    assert!(range.intersects(&Range::new(2, 3, 2, 8)));
+    assert!(range.intersects(&Range::new(1, 1, 1, 5)));
+    assert!(range.intersects(&Range::new(1, 7, 1, 8)));
+    assert!(Range::new(1, 7, 1, 8).intersects(&range));
+    assert!(!range.intersects(&Range::new(2, 4, 2, 8)));
+    assert!(!range.intersects(&Range::new(1, 1, 1, 4)));
+
+    // The rest of the buffer intersects everything not strictly before its beginning.
+    assert!(range.intersects(&Range::rest_from(at(2, 3))));
+    assert!(range.intersects(&Range::rest_from(at(1, 1))));
+    assert!(!range.intersects(&Range::rest_from(at(2, 4))));
+    assert!(Range::rest_from(at(100, 1)).contains(at(u64::MAX, 1)));
+}
+
+#[test]
+fn union_spans_both_ranges() {
+    let first = Range::new(1, 5, 2, 3).at(2);
+    let second = Range::new(2, 1, 4, 1).at(2);
+
+    assert_eq!(first.union(second), Range::new(1, 5, 4, 1).at(2));
+    assert_eq!(second.union(first), first.union(second));
+
+    // Ranges do not need to intersect.
+    let far = Range::new(7, 2, 7, 9).at(2);
+    assert_eq!(first.union(far), Range::new(1, 5, 7, 9).at(2));
+    assert_eq!(first.union(first), first);
+}
+
+#[test]
+fn split_at_keeps_the_position_before() {
+    let range = Range::new(1, 5, 2, 3);
+
+    // Right after the end of a statement in the middle of the range.
+    assert_eq!(
+        range.split_at(at(1, 9)),
+        (Some(Range::new(1, 5, 1, 9)), Some(Range::new(1, 10, 2, 3)))
+    );
+    assert_eq!(
+        range.split_at(at(1, 5)),
+        (Some(Range::new(1, 5, 1, 5)), Some(Range::new(1, 6, 2, 3)))
+    );
+
+    // Positions at or past the end leave nothing after, positions before the beginning nothing before.
+    assert_eq!(range.split_at(at(2, 3)), (Some(range), None));
+    assert_eq!(range.split_at(at(5, 1)), (Some(range), None));
+    assert_eq!(range.split_at(at(1, 1)), (None, Some(range)));
+}
+
+#[test]
+fn positions_are_shifted_by_edits() {
+    // `abc` inserted at 2.4.
+    let edit = insertion(at(2, 4), at(2, 7));
+    assert_eq!(edit.shift(at(2, 3)), Some(at(2, 3)));
+    assert_eq!(edit.shift(at(2, 4)), Some(at(2, 7)));
+    assert_eq!(edit.shift(at(3, 4)), Some(at(3, 4)));
+
+    // Two lines inserted at 2.4, the second one ending after 6 bytes.
+    let edit = insertion(at(2, 4), at(4, 7));
+    assert_eq!(edit.shift(at(2, 10)), Some(at(4, 13)));
+    assert_eq!(edit.shift(at(3, 1)), Some(at(5, 1)));
+
+    // Everything from 2.4 to 5.2 (excluded) removed.
+    let edit = removal(at(2, 4), at(5, 2));
+    assert_eq!(edit.shift(at(2, 3)), Some(at(2, 3)));
+    assert_eq!(edit.shift(at(2, 4)), None);
+    assert_eq!(edit.shift(at(5, 1)), None);
+    assert_eq!(edit.shift(at(5, 2)), Some(at(2, 4)));
+    assert_eq!(edit.shift(at(5, 9)), Some(at(2, 11)));
+    assert_eq!(edit.shift(at(7, 9)), Some(at(4, 9)));
+}
+
+#[test]
+fn ranges_are_shifted_unless_modified() {
+    let range = Range::new(2, 4, 3, 6).at(1);
+
+    // Right after the range.
+    assert_eq!(range.shift(&insertion(at(3, 7), at(3, 9))), Some(range));
+    // Right before the range.
+    assert_eq!(
+        range.shift(&insertion(at(2, 4), at(2, 6))),
+        Some(Range::new(2, 6, 3, 6).at(1))
+    );
+    assert_eq!(
+        range.shift(&insertion(at(1, 1), at(3, 1))),
+        Some(Range::new(4, 4, 5, 6).at(1))
+    );
+    // Inside the range, or touching its last character.
+    assert_eq!(range.shift(&insertion(at(2, 9), at(2, 10))), None);
+    assert_eq!(range.shift(&insertion(at(3, 6), at(3, 7))), None);
+    assert_eq!(range.shift(&removal(at(1, 1), at(2, 5))), None);
+    // Removed text ending right before the range.
+    assert_eq!(
+        range.shift(&removal(at(1, 2), at(2, 4))),
+        Some(Range::new(1, 2, 2, 6).at(1))
+    );
+    // An edit whose extent is unknown.
+    assert_eq!(range.shift(&Edit::unknown_from(at(1, 1))), None);
+    assert_eq!(range.shift(&Edit::unknown_from(at(3, 7))), Some(range));
+}
+
+#[test]
+fn sub_range_follows_lines() {
+    let code = "Lemma foo :\n  True.";
+    let range = Range::new(3, 5, 4, 7);
+
+    // `foo`
+    assert_eq!(range.sub_range(code, 6, 9), Range::new(3, 11, 3, 13));
+    // `True`
+    assert_eq!(range.sub_range(code, 14, 18), Range::new(4, 3, 4, 6));
+    // Locations outside the statement are ignored.
+    assert_eq!(range.sub_range(code, 40, 42), range);
+}
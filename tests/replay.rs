@@ -0,0 +1,119 @@
+//! Processing again all the statements once `coqidetop` has been started again for a buffer.
+
+mod common;
+
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::broadcast::{self, error::TryRecvError, Receiver};
+
+use common::operation;
+use daemon::{
+    client::commands::types::ClientCommand,
+    range::Range,
+    state::{Operation, State},
+    worker::replay_before,
+};
+
+/// A state with the initial state, followed by statements on lines 1 and 2.
+fn processed() -> Arc<RwLock<State>> {
+    let mut state = State::new();
+    state.operations.push(Operation::default());
+    for (state_id, line, code) in [(2, 1, "Check nat."), (3, 2, "Check bool.")] {
+        state.operations.push(Operation {
+            code: code.to_string(),
+            ..operation(state_id, Range::new(line, 1, line, 11))
+        });
+    }
+    Arc::new(RwLock::new(state))
+}
+
+fn received(rx: &mut Receiver<ClientCommand>) -> Vec<String> {
+    let mut commands = vec![];
+    loop {
+        match rx.try_recv() {
+            Ok(cmd) => commands.push(format!("{:?}", cmd)),
+            Err(TryRecvError::Empty) => return commands,
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+}
+
+fn next(line: u64, code: &str) -> String {
+    format!(
+        "{:?}",
+        ClientCommand::Next(true, Range::new(line, 1, line, 11), code.to_string())
+    )
+}
+
+fn refresh() -> [String; 2] {
+    [
+        format!("{:?}", ClientCommand::ShowGoals(Range::default())),
+        format!("{:?}", ClientCommand::Status),
+    ]
+}
+
+#[test]
+fn restarting_processes_each_statement_once() {
+    let state = processed();
+    let (tx, mut rx) = broadcast::channel(16);
+
+    replay_before(ClientCommand::Restart(true), &state, &tx).unwrap();
+
+    assert_eq!(
+        received(&mut rx),
+        [
+            vec![
+                format!("{:?}", ClientCommand::Init),
+                next(1, "Check nat."),
+                next(2, "Check bool."),
+            ],
+            refresh().to_vec(),
+        ]
+        .concat()
+    );
+    assert!(state.read().unwrap().operations.is_empty());
+}
+
+#[test]
+fn restarting_without_replaying_only_initializes() {
+    let state = processed();
+    let (tx, mut rx) = broadcast::channel(16);
+
+    replay_before(ClientCommand::Restart(false), &state, &tx).unwrap();
+
+    assert_eq!(
+        received(&mut rx),
+        [
+            vec![format!("{:?}", ClientCommand::Init)],
+            refresh().to_vec()
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn command_is_processed_after_the_statements() {
+    let state = processed();
+    let (tx, mut rx) = broadcast::channel(16);
+    let query = ClientCommand::Query("Check unit.".to_string());
+
+    replay_before(query.clone(), &state, &tx).unwrap();
+
+    let received = received(&mut rx);
+    assert_eq!(received.len(), 6);
+    assert_eq!(
+        received[1..3],
+        [next(1, "Check nat."), next(2, "Check bool.")]
+    );
+    assert_eq!(received[5], format!("{:?}", query));
+}
+
+#[test]
+fn nothing_is_processed_before_initialization() {
+    let state = Arc::new(RwLock::new(State::new()));
+    let (tx, mut rx) = broadcast::channel(16);
+
+    replay_before(ClientCommand::Init, &state, &tx).unwrap();
+
+    assert_eq!(received(&mut rx), [format!("{:?}", ClientCommand::Init)]);
+}
@@ -0,0 +1,124 @@
+//! Translating the S-expressions of SerAPI to the responses and feedback the daemon understands.
+
+use daemon::coqtop::{
+    backend::{Failure, Feedback, FeedbackContent},
+    sertop::{failure, serapi_flags, translate_feedback},
+    sexp::{parse, Sexp},
+    xml_protocol::types::{MessageType, ProtocolRichPP, ProtocolRichPPPart},
+};
+
+fn sexp(text: &str) -> Sexp {
+    parse(text).unwrap().remove(0)
+}
+
+fn raw(richpp: &ProtocolRichPP) -> String {
+    let ProtocolRichPP::RichPP(parts) = richpp;
+    parts
+        .iter()
+        .map(|part| match part {
+            ProtocolRichPPPart::Raw(text) => text.as_str(),
+            part => panic!("unexpected part {:?}", part),
+        })
+        .collect()
+}
+
+#[test]
+fn load_paths_are_given_as_single_arguments() {
+    let flags = [
+        "-R",
+        "theories",
+        "Foo",
+        "-Q",
+        "lib",
+        "Bar",
+        "-I",
+        "src",
+        "-native-compiler",
+        "ondemand",
+        "-noinit",
+    ];
+
+    assert_eq!(
+        serapi_flags(flags.iter().map(ToString::to_string).collect()),
+        [
+            "-R",
+            "theories,Foo",
+            "-Q",
+            "lib,Bar",
+            "-I",
+            "src",
+            "-noinit"
+        ]
+    );
+}
+
+#[test]
+fn feedback_is_translated() {
+    let processed = sexp("(Feedback ((doc_id 0) (span_id 3) (route 0) (contents Processed)))");
+    assert!(matches!(
+        translate_feedback(processed.field("Feedback").unwrap()),
+        Some(Feedback {
+            state_id: 3,
+            content: FeedbackContent::Processed
+        })
+    ));
+
+    let message = sexp(concat!(
+        "(Feedback ((doc_id 0) (span_id 4) (route 0) (contents (Message (level Notice) (loc ()) ",
+        "(pp (Pp_glue ((Pp_string nat) (Pp_print_break 1 0) (Pp_string \": Set\")))) ",
+        "(str \"nat : Set\")))))",
+    ));
+    match translate_feedback(message.field("Feedback").unwrap()) {
+        Some(Feedback {
+            state_id: 4,
+            content: FeedbackContent::Message(MessageType::Notice, text),
+        }) => assert_eq!(raw(&text), "nat : Set"),
+        feedback => panic!("unexpected feedback {:?}", feedback),
+    }
+
+    // NOTE: older versions of SerAPI only give the pretty-printing document
+    let message = sexp(concat!(
+        "(Feedback ((doc_id 0) (span_id 4) (route 0) (contents (Message (level Warning) (loc ()) ",
+        "(pp (Pp_glue ((Pp_string nat) (Pp_print_break 1 0) (Pp_string \": Set\"))))))))",
+    ));
+    match translate_feedback(message.field("Feedback").unwrap()) {
+        Some(Feedback {
+            content: FeedbackContent::Message(MessageType::Warning, text),
+            ..
+        }) => assert_eq!(raw(&text), "nat : Set"),
+        feedback => panic!("unexpected feedback {:?}", feedback),
+    }
+
+    let unknown =
+        sexp("(Feedback ((doc_id 0) (span_id 4) (route 0) (contents (ProcessingIn master))))");
+    assert!(translate_feedback(unknown.field("Feedback").unwrap()).is_none());
+}
+
+#[test]
+fn exceptions_are_failures() {
+    let answers = [
+        sexp("(Added 5 ((fname ToplevelInput) (bp 0) (ep 10)) NewTip)"),
+        sexp(concat!(
+            "(CoqExn ((loc (((fname ToplevelInput) (line_nb 1) (bol_pos 0) (line_nb_last 1) ",
+            "(bol_pos_last 0) (bp 6) (ep 9)))) (stm_ids ((5 5))) (backtrace (Backtrace ())) ",
+            "(exn (\"Nametab.GlobalizationError(_)\")) (pp (Pp_string \"unused\")) ",
+            "(str \"The reference foo was not found in the current environment.\")))",
+        )),
+    ];
+
+    match failure(&answers, 4) {
+        Some(Failure {
+            location: Some((6, 9)),
+            safe_state_id: Some(4),
+            message,
+        }) => {
+            assert_eq!(
+                raw(&message),
+                "The reference foo was not found in the current environment."
+            )
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+
+    assert!(failure(&answers[..1], 4).is_none());
+}
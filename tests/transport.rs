@@ -0,0 +1,60 @@
+//! Talking to a `coqidetop` started elsewhere, over TCP main and control channels.
+
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use daemon::coqtop::transport::Transport;
+
+/// Connects to one of the channels, as `coqidetop` does, once the daemon is listening.
+async fn connect(address: SocketAddr) -> TcpStream {
+    loop {
+        match TcpStream::connect(address).await {
+            Ok(stream) => return stream,
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+#[tokio::test]
+async fn attached_coqidetop_is_stopped_on_its_control_channel() {
+    let port = 40000 + (std::process::id() % 5000) as u16 * 4;
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    let attached = tokio::spawn(Transport::attach(address, &[]));
+
+    let channel = |offset: u16| connect(SocketAddr::from(([127, 0, 0, 1], port + offset)));
+    let mut main_r = channel(0).await;
+    let mut main_w = channel(1).await;
+    let mut control_r = channel(2).await;
+    let _control_w = channel(3).await;
+
+    let (mut transport, mut reader, mut writer) =
+        tokio::time::timeout(Duration::from_secs(5), attached)
+            .await
+            .expect("the daemon should accept all the channels")
+            .unwrap()
+            .unwrap();
+    assert_eq!(transport.id(), None);
+
+    let mut call = [0; 13];
+    writer.write_all(b"<call val=\"\">").await.unwrap();
+    main_r.read_exact(&mut call).await.unwrap();
+    assert_eq!(&call, b"<call val=\"\">");
+
+    let mut response = [0; 12];
+    main_w.write_all(b"<value val=\"").await.unwrap();
+    reader.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"<value val=\"");
+
+    assert!(!transport.is_stopped());
+    transport.interrupt().await;
+    assert!(transport.is_stopped());
+
+    let mut request = [0; 21];
+    control_r.read_exact(&mut request).await.unwrap();
+    assert_eq!(request[..4], [0x84, 0x95, 0xA6, 0xBE]);
+    assert_eq!(request[20], 0x40);
+}
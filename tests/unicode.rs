@@ -0,0 +1,130 @@
+//! Columns must be counted in bytes everywhere Kakoune or Coq is involved,
+//! which only makes a difference on files full of `∀` and `→`.
+
+mod common;
+
+use std::{collections::HashSet, fs};
+
+use common::{goal, goals, parse_statements, text_at};
+use daemon::{
+    coqtop::{
+        backend::Goal,
+        xml_protocol::types::{ProtocolRichPP, ProtocolRichPPPart},
+    },
+    kakoune::ui_updater::render_goals,
+    range::{ColumnUnit, Position, Range},
+};
+
+const UNICODE_FILE: &str = "test/Unicode.v";
+
+#[test]
+fn parser_ranges_are_counted_in_bytes() {
+    let content = fs::read_to_string(UNICODE_FILE).unwrap();
+    let statements = parse_statements(&content);
+
+    assert_eq!(statements.len(), 19);
+    for (range, code) in statements {
+        assert_eq!(text_at(&content, range), code, "at {:?}", range);
+    }
+}
+
+#[test]
+fn columns_convert_between_units() {
+    let content = fs::read_to_string(UNICODE_FILE).unwrap();
+    let line = content.lines().nth(3).unwrap();
+
+    // The final `.`, after three `→` (3 bytes each) and one `λ` (2 bytes).
+    assert_eq!(ColumnUnit::Byte.convert(line, 89, ColumnUnit::Char), 82);
+    assert_eq!(ColumnUnit::Char.convert(line, 82, ColumnUnit::Byte), 89);
+    assert_eq!(ColumnUnit::Byte.convert(line, 89, ColumnUnit::Display), 82);
+
+    // Columns inside a character are moved to its beginning.
+    let arrow = line.find('→').unwrap() as u64 + 1;
+    assert_eq!(
+        ColumnUnit::Byte.convert(line, arrow + 1, ColumnUnit::Char),
+        ColumnUnit::Byte.convert(line, arrow, ColumnUnit::Char)
+    );
+
+    let range = Range::new(4, 1, 4, 89).convert(ColumnUnit::Char, |_| line);
+    assert_eq!(range.begin, Position::new(4, 1));
+    assert_eq!(range.end, Position::new(4, 82));
+    assert_eq!(
+        range.convert(ColumnUnit::Byte, |_| line).end,
+        Position::new(4, 89)
+    );
+
+    // Column 0 is right before the line, whatever the unit.
+    assert_eq!(ColumnUnit::Char.convert(line, 0, ColumnUnit::Byte), 0);
+    assert_eq!(ColumnUnit::Byte.convert("", 0, ColumnUnit::Char), 0);
+}
+
+#[test]
+fn only_byte_ranges_are_formatted() {
+    let content = fs::read_to_string(UNICODE_FILE).unwrap();
+    let line = content.lines().nth(3).unwrap();
+
+    let range = Range::new(4, 1, 4, 89);
+    assert_eq!(range.spec().unwrap().to_string(), "4.1,4.89");
+
+    let range = range.convert(ColumnUnit::Char, |_| line);
+    assert_eq!(range.spec(), None);
+    assert_eq!(
+        range
+            .convert(ColumnUnit::Byte, |_| line)
+            .spec()
+            .unwrap()
+            .to_string(),
+        "4.1,4.89"
+    );
+}
+
+#[test]
+fn goal_colors_are_counted_in_bytes() {
+    let goal = Goal {
+        hypotheses: vec![ProtocolRichPP::RichPP(vec![
+            ProtocolRichPPPart::Variable("α".to_string()),
+            ProtocolRichPPPart::Raw(" : ".to_string()),
+            ProtocolRichPPPart::Type("Prop".to_string()),
+        ])],
+        conclusion: ProtocolRichPP::RichPP(vec![
+            ProtocolRichPPPart::Raw("∀ x, ".to_string()),
+            ProtocolRichPPPart::Variable("α".to_string()),
+        ]),
+        ..goal("1", "")
+    };
+    let goals = goals(vec![goal], vec![], vec![], vec![]);
+
+    let (content, colors) = render_goals(goals, &HashSet::new());
+    assert_eq!(
+        content,
+        "1 subgoal(s) remaining:\n\n α : Prop \n────────── (1)\n ∀ x, α \n"
+    );
+    assert_eq!(
+        colors,
+        [
+            "3.2,3.2|coqide_variable",
+            "3.7,3.10|coqide_type",
+            "5.9,5.9|coqide_variable",
+        ]
+    );
+}
+
+#[test]
+fn display_width_counts_wide_characters() {
+    assert_eq!(ColumnUnit::Display.length("∀ x, x → x"), 10);
+    assert_eq!(ColumnUnit::Display.length("証明"), 4);
+    assert_eq!(ColumnUnit::Byte.length("証明"), 6);
+}
+
+#[test]
+fn error_locations_are_byte_offsets() {
+    let content = fs::read_to_string(UNICODE_FILE).unwrap();
+    let (range, code) = parse_statements(&content).pop().unwrap();
+
+    // Coq would report the location of the ill-typed `α` in the statement, in bytes.
+    let start = code.rfind("α ∧").unwrap();
+    let location = range.sub_range(&code, start, start + "α".len());
+
+    assert_eq!(text_at(&content, location), "α");
+    assert_eq!(location.begin, location.end);
+}